@@ -3,20 +3,33 @@ use structopt::StructOpt;
 use std::net::{Ipv4Addr, SocketAddr};
 
 use mio::net::UdpSocket;
+use ou2::hash::{self, KeyHasher, HASHERS};
 use ou2::pdu::*;
 use ou2::socket_wrapper::{Message, UdpWrapper};
 
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{thread, time};
 
 use std::io;
 use std::io::prelude::*;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
 
-use mio::{Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Token};
 use ou2::pdu::{ValInsertPdu, ValLookupPdu, ValRemovePdu, PDU};
 
+use tracing::{info, warn};
+
+/// How long to wait for an error after an insert or remove, which have no response
+const ERROR_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to wait for every node to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many times a scan that got no answer within `QUERY_TIMEOUT` is sent again
+const SCAN_RETRIES: u32 = 3;
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "Debug client")]
 struct Opt {
@@ -36,9 +49,103 @@ struct Opt {
     /// Delay between inserts for CSV inserts (seconds)
     #[structopt(long, default_value = "0")]
     delay: u64,
+    /// Records per datagram for CSV inserts, at most 40. With 1 every record
+    /// is sent as a plain insert
+    #[structopt(long, default_value = "40")]
+    batch_size: u8,
+
+    /// Write log output as JSON, one object per line
+    #[structopt(long)]
+    log_json: bool,
+
+    /// Trace lookups and print the path they take through the network
+    #[structopt(long)]
+    trace: bool,
+
+    /// Seconds until inserted entries expire, they never expire if not set
+    #[structopt(long)]
+    ttl: Option<u32>,
+
+    /// Refuse ssns whose last digit is not a valid Luhn check digit
+    #[structopt(long)]
+    luhn: bool,
+
+    /// Hash function that places keys on the ring, the same as the nodes use
+    #[structopt(long, default_value = "djb2", possible_values = HASHERS)]
+    hasher: String,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Write all entries in the network to a CSV file that can be imported with --csv
+    Dump {
+        /// File to write the entries to
+        #[structopt(long)]
+        out: String,
+    },
+    /// Print the entries with the given email or name, ignoring case
+    Find {
+        /// Email to search for
+        #[structopt(long, required_unless = "name", conflicts_with = "name")]
+        email: Option<String>,
+        /// Name to search for
+        #[structopt(long)]
+        name: Option<String>,
+    },
+    /// Print changes to an ssn, or to all entries in a hash range, until interrupted
+    Watch {
+        /// Only watch this ssn
+        #[structopt(long)]
+        ssn: Option<Ssn>,
+        /// First hash of the range to watch
+        #[structopt(long, default_value = "0")]
+        start: u64,
+        /// Last hash of the range to watch, the end of the ring if not set
+        #[structopt(long)]
+        end: Option<u64>,
+        /// Seconds the subscription lasts, it is renewed halfway through
+        #[structopt(long, default_value = "60")]
+        lease: u32,
+    },
+    /// Store a value under a key, replacing any value already there
+    Put {
+        /// Key to store the value under
+        #[structopt(long)]
+        key: String,
+        /// Value to store
+        #[structopt(long, required_unless = "file", conflicts_with = "file")]
+        value: Option<String>,
+        /// File whose contents to store as the value
+        #[structopt(long)]
+        file: Option<String>,
+    },
+    /// Print the value stored under a key
+    Get {
+        /// Key to look up
+        #[structopt(long)]
+        key: String,
+        /// File to write the value to instead of printing it
+        #[structopt(long)]
+        out: Option<String>,
+    },
+    /// Delete the value stored under a key
+    Delete {
+        /// Key to delete
+        #[structopt(long)]
+        key: String,
+    },
+    /// Make the node offer its load to its neighbours, so that of the node and
+    /// a neighbour, one carrying more than the skew times the other's load
+    /// moves part of its range to the other
+    Rebalance,
+    /// Print the ranges every node owns and its share of the ring
+    Ring,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Person {
     ssn: String,
     name: String,
@@ -47,26 +154,116 @@ struct Person {
 
 fn main() -> std::io::Result<()> {
     let opt = Opt::from_args();
+    ou2::logging::init(opt.log_json);
     let mut udp_socket: UdpSocket = UdpSocket::bind("0.0.0.0:0".parse().unwrap()).unwrap();
     let mut udp_wrapper: UdpWrapper = UdpWrapper::new();
 
     /* Get my own IP from tracker */
     let my_address = get_my_address(&mut udp_wrapper, &mut udp_socket, opt.tracker);
     let my_port = udp_socket.local_addr().unwrap().port();
+    let hasher = hash::by_name(&opt.hasher).expect("structopt only accepts known hashers");
+    let max_hash = hasher.max_hash();
 
     let node = if let Some(n) = opt.node {
         n
     } else {
-        println!("Sending NET_GET_NODE to tracker to get a node");
+        info!("Sending NET_GET_NODE to tracker to get a node");
         if let Some(n) = get_node(&mut udp_wrapper, &mut udp_socket, opt.tracker) {
-            println!("Got NET_GET_NODE_RESPONSE from tracker with node {:?}", n);
+            info!("Got NET_GET_NODE_RESPONSE from tracker with node {:?}", n);
             n
         } else {
-            println!("NET_GET_NODE_RESPONSE was empty..");
+            warn!("NET_GET_NODE_RESPONSE was empty..");
             return Ok(());
         }
     };
 
+    match &opt.command {
+        Some(Command::Dump { out }) => {
+            return dump(
+                out,
+                hasher.as_ref(),
+                &mut udp_wrapper,
+                &mut udp_socket,
+                node,
+                my_address,
+                my_port,
+            );
+        }
+        Some(Command::Find { email, name }) => {
+            let query = match (email, name) {
+                (Some(email), _) => (QUERY_FIELD_EMAIL, email.clone()),
+                (_, Some(name)) => (QUERY_FIELD_NAME, name.clone()),
+                _ => unreachable!("structopt requires one of them"),
+            };
+            if let Err(message) = check_text("value", &query.1) {
+                println!("{}", message);
+                process::exit(1);
+            }
+            return find(
+                query,
+                max_hash,
+                &mut udp_wrapper,
+                &mut udp_socket,
+                node,
+                my_address,
+                my_port,
+            );
+        }
+        Some(Command::Watch {
+            ssn,
+            start,
+            end,
+            lease,
+        }) => {
+            // A lease of 0 would end the subscription
+            let lease = (*lease).clamp(1, MAX_LEASE);
+            let end = end.unwrap_or(max_hash);
+            let subscribe = || {
+                let ssn = ssn.as_ref().map(Ssn::to_string);
+                ValSubscribePdu::new(*start, end, ssn, lease, my_address.into(), my_port)
+            };
+            return watch(subscribe, lease, &mut udp_wrapper, &mut udp_socket, node);
+        }
+        Some(Command::Put { key, value, file }) => {
+            let value = match (value, file) {
+                (Some(value), _) => value.clone().into_bytes(),
+                (_, Some(file)) => std::fs::read(file)?,
+                _ => unreachable!("structopt requires one of them"),
+            };
+            let put = ValKvPutPdu::new(key.clone().into_bytes(), value);
+            udp_wrapper.send(&mut udp_socket, put.into(), node);
+            print_errors(&mut udp_wrapper, &mut udp_socket);
+            return Ok(());
+        }
+        Some(Command::Get { key, out }) => {
+            let get = ValKvGetPdu::new(key.clone().into_bytes(), my_address.into(), my_port);
+            udp_wrapper.send(&mut udp_socket, get.into(), node);
+            return get_value(out.as_deref(), &mut udp_wrapper, &mut udp_socket);
+        }
+        Some(Command::Delete { key }) => {
+            let delete = ValKvDeletePdu::new(key.clone().into_bytes());
+            udp_wrapper.send(&mut udp_socket, delete.into(), node);
+            print_errors(&mut udp_wrapper, &mut udp_socket);
+            return Ok(());
+        }
+        Some(Command::Ring) => {
+            return ring(
+                hasher.as_ref(),
+                &mut udp_wrapper,
+                &mut udp_socket,
+                node,
+                my_address,
+                my_port,
+            );
+        }
+        Some(Command::Rebalance) => {
+            udp_wrapper.send(&mut udp_socket, NetRebalancePdu::new(0).into(), node);
+            print_errors(&mut udp_wrapper, &mut udp_socket);
+            return Ok(());
+        }
+        None => {}
+    }
+
     /* Send CSV file if it exists */
     if let Some(csv) = &opt.csv {
         send_csv(csv.into(), &opt, &mut udp_wrapper, &mut udp_socket, node)?;
@@ -77,32 +274,82 @@ fn main() -> std::io::Result<()> {
     let mut buf = String::new();
     loop {
         println!(
-            "Write insert, remove or lookup in order to perform that operation, or exit to exit"
+            "Write insert, remove, lookup, insert-if or remove-if in order to perform that operation, or exit to exit"
         );
         buf.clear();
         stdin.read_line(&mut buf)?;
 
         match buf.trim() {
             "insert" => {
-                let insert_pdu =
-                    ValInsertPdu::new(ask_for("ssn"), ask_for("name"), ask_for("email"));
-                udp_wrapper.send(&mut udp_socket, insert_pdu.into(), node);
+                let insert_pdu = ValInsertPdu::new(
+                    ask_for_ssn(&opt),
+                    ask_for_text("name"),
+                    ask_for_text("email"),
+                );
+                udp_wrapper.send(&mut udp_socket, with_ttl(insert_pdu.into(), &opt), node);
+                print_errors(&mut udp_wrapper, &mut udp_socket);
             }
             "remove" => {
-                let remove_pdu = ValRemovePdu::new(ask_for("ssn"));
+                let remove_pdu = ValRemovePdu::new(ask_for_ssn(&opt));
                 udp_wrapper.send(&mut udp_socket, remove_pdu.into(), node);
+                print_errors(&mut udp_wrapper, &mut udp_socket);
+            }
+            "insert-if" => {
+                let insert_pdu = ValCondInsertPdu::new(
+                    ask_for_ssn(&opt),
+                    ask_for_text("name"),
+                    ask_for_text("email"),
+                    ask_for_version("expected version (0 if it must not exist)"),
+                    my_address.into(),
+                    my_port,
+                );
+                udp_wrapper.send(&mut udp_socket, with_ttl(insert_pdu.into(), &opt), node);
+                await_cond_response(&mut udp_wrapper, &mut udp_socket, VAL_COND_INSERT_ID);
+            }
+            "remove-if" => {
+                let remove_pdu = ValCondRemovePdu::new(
+                    ask_for_ssn(&opt),
+                    ask_for_version("expected version"),
+                    my_address.into(),
+                    my_port,
+                );
+                udp_wrapper.send(&mut udp_socket, remove_pdu.into(), node);
+                await_cond_response(&mut udp_wrapper, &mut udp_socket, VAL_COND_REMOVE_ID);
             }
             "lookup" => {
-                let lookup_pdu = ValLookupPdu::new(ask_for("ssn"), my_address.into(), my_port);
+                let lookup_pdu =
+                    ValLookupPdu::new_versioned(ask_for_ssn(&opt), my_address.into(), my_port);
+
+                if opt.trace {
+                    traced_lookup(
+                        &mut udp_wrapper,
+                        &mut udp_socket,
+                        node,
+                        lookup_pdu,
+                        my_address,
+                        my_port,
+                    );
+                    continue;
+                }
+
                 udp_wrapper.send(&mut udp_socket, lookup_pdu.into(), node);
 
-                if let (PDU::ValLookupResponse(pdu), _) =
-                    poll_response(&mut udp_wrapper, &mut udp_socket)
-                {
-                    println!("Got VAL_LOOKUP_RESPONSE");
-                    println!("ssn: {}, name: {}, email: {}", pdu.ssn, pdu.name, pdu.email);
-                } else {
-                    panic!("Expected ValLookUpResponse, got something else ");
+                loop {
+                    match poll_response(&mut udp_wrapper, &mut udp_socket) {
+                        (PDU::ValLookupResponse(pdu), _) => {
+                            println!("Got VAL_LOOKUP_RESPONSE");
+                            print_entry(&pdu);
+                            break;
+                        }
+                        (PDU::Error(pdu), sender) => {
+                            print_error(&pdu, sender);
+                            // Late errors for inserts and removes may arrive first
+                            if pdu.request_type == VAL_LOOKUP_VERSIONED_ID {
+                                break;
+                            }
+                        }
+                        _ => panic!("Expected ValLookUpResponse, got something else "),
+                    }
                 }
             }
             "exit" => return Ok(()),
@@ -113,56 +360,248 @@ fn main() -> std::io::Result<()> {
     }
 }
 
-fn poll_response(udp_wrapper: &mut UdpWrapper, mut udp_socket: &mut UdpSocket) -> Message {
-    let poll = Poll::new().unwrap();
+fn poll_response(udp_wrapper: &mut UdpWrapper, udp_socket: &mut UdpSocket) -> Message {
+    poll_response_within(udp_wrapper, udp_socket, None).expect("Waited without a timeout")
+}
+
+/// The next PDU that arrives within `timeout`, or None. Waits for as long as
+/// it takes without a timeout.
+fn poll_response_within(
+    udp_wrapper: &mut UdpWrapper,
+    udp_socket: &mut UdpSocket,
+    timeout: Option<Duration>,
+) -> Option<Message> {
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(16);
     const RESPONSE: Token = Token(1);
 
     poll.registry()
         .register(udp_socket, RESPONSE, Interest::READABLE)
         .unwrap();
 
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let message = loop {
+        udp_wrapper.try_read(udp_socket);
+        if let Some(x) = udp_wrapper.next_pdu() {
+            break Some(x);
+        }
+
+        let left = match deadline.map(|d| d.checked_duration_since(Instant::now())) {
+            Some(None) => break None,
+            left => left.flatten(),
+        };
+        poll.poll(&mut events, left).unwrap();
+    };
+
+    poll.registry().deregister(udp_socket).unwrap();
+    message
+}
+
+fn await_cond_response(udp_wrapper: &mut UdpWrapper, udp_socket: &mut UdpSocket, request_type: u8) {
     loop {
-        udp_wrapper.try_read(&mut udp_socket);
+        match poll_response(udp_wrapper, udp_socket) {
+            (PDU::ValCondResponse(pdu), _) if pdu.success => {
+                println!("Got VAL_COND_RESPONSE, succeeded");
+                println!("ssn: {}, version: {}", pdu.ssn, pdu.version);
+                return;
+            }
+            (PDU::ValCondResponse(pdu), _) => {
+                println!("Got VAL_COND_RESPONSE, the version did not match");
+                println!("ssn: {}, current version: {}", pdu.ssn, pdu.version);
+                return;
+            }
+            (PDU::Error(pdu), sender) => {
+                print_error(&pdu, sender);
+                if pdu.request_type == request_type {
+                    return;
+                }
+            }
+            (pdu, sender) => warn!("Ignoring unexpected {:?} from {:?}", pdu, sender),
+        }
+    }
+}
 
-        if let Some(x) = udp_wrapper.next_pdu() {
-            poll.registry().deregister(udp_socket).unwrap();
-            return x;
+/// Prints the errors that arrive within `ERROR_TIMEOUT`.
+fn print_errors(udp_wrapper: &mut UdpWrapper, udp_socket: &mut UdpSocket) {
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(16);
+    const RESPONSE: Token = Token(1);
+
+    poll.registry()
+        .register(udp_socket, RESPONSE, Interest::READABLE)
+        .unwrap();
+
+    let deadline = Instant::now() + ERROR_TIMEOUT;
+    loop {
+        udp_wrapper.try_read(udp_socket);
+
+        while let Some(message) = udp_wrapper.next_pdu() {
+            match message {
+                (PDU::Error(pdu), sender) => print_error(&pdu, sender),
+                (pdu, sender) => warn!("Ignoring unexpected {:?} from {:?}", pdu, sender),
+            }
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        poll.poll(&mut events, Some(deadline - now)).unwrap();
+    }
+
+    poll.registry().deregister(udp_socket).unwrap();
+}
+
+fn traced_lookup(
+    udp_wrapper: &mut UdpWrapper,
+    udp_socket: &mut UdpSocket,
+    node: SocketAddr,
+    lookup: ValLookupPdu,
+    my_address: Ipv4Addr,
+    my_port: u16,
+) {
+    let trace_id = new_trace_id();
+    let trace = ValTracePdu::new(trace_id, 0, my_address.into(), my_port, lookup.into());
+    let start = Instant::now();
+    udp_wrapper.send(udp_socket, trace.into(), node);
+
+    let mut previous = start;
+    println!("Trace {:016x}", trace_id);
+    loop {
+        match poll_response(udp_wrapper, udp_socket) {
+            (PDU::ValTraceHop(hop), _) if hop.trace_id == trace_id => {
+                let now = Instant::now();
+                println!(
+                    "  hop {:>3}  {:<21}  {:>8.3} ms  (+{:.3} ms)",
+                    hop.hop,
+                    hop.get_node_addr(),
+                    (now - start).as_secs_f64() * 1000.0,
+                    (now - previous).as_secs_f64() * 1000.0,
+                );
+                previous = now;
+            }
+            (PDU::ValLookupResponse(pdu), _) => {
+                println!(
+                    "Got VAL_LOOKUP_RESPONSE after {:.3} ms",
+                    start.elapsed().as_secs_f64() * 1000.0
+                );
+                print_entry(&pdu);
+                return;
+            }
+            (PDU::Error(pdu), sender) => {
+                print_error(&pdu, sender);
+                return;
+            }
+            (pdu, sender) => {
+                warn!("Ignoring unexpected {:?} from {:?}", pdu, sender);
+            }
         }
     }
 }
 
+/// Waits for the answer to a get and prints the value, or writes it to `out`.
+fn get_value(
+    out: Option<&str>,
+    udp_wrapper: &mut UdpWrapper,
+    udp_socket: &mut UdpSocket,
+) -> std::io::Result<()> {
+    loop {
+        match poll_response(udp_wrapper, udp_socket) {
+            (PDU::ValKvGetResponse(pdu), _) if !pdu.found => {
+                println!("Key {:?} not found", String::from_utf8_lossy(&pdu.key));
+                return Ok(());
+            }
+            (PDU::ValKvGetResponse(pdu), _) => {
+                match out {
+                    Some(out) => {
+                        File::create(out)?.write_all(&pdu.value)?;
+                        println!("Wrote {} bytes to {}", pdu.value.len(), out);
+                    }
+                    None => println!("{}", String::from_utf8_lossy(&pdu.value)),
+                }
+                println!("version: {}", pdu.version);
+                return Ok(());
+            }
+            (PDU::Error(pdu), sender) => {
+                print_error(&pdu, sender);
+                if pdu.request_type == VAL_KV_GET_ID {
+                    return Ok(());
+                }
+            }
+            (pdu, sender) => warn!("Ignoring unexpected {:?} from {:?}", pdu, sender),
+        }
+    }
+}
+
+fn print_entry(pdu: &ValLookupResponsePdu) {
+    println!(
+        "ssn: {}, name: {}, email: {}, version: {}",
+        pdu.ssn, pdu.name, pdu.email, pdu.version
+    );
+}
+
+fn print_notification(pdu: &ValNotifyPdu) {
+    if pdu.removed {
+        println!("removed ssn: {}, version: {}", pdu.ssn, pdu.version);
+    } else {
+        println!(
+            "changed ssn: {}, name: {}, email: {}, version: {}",
+            pdu.ssn, pdu.name, pdu.email, pdu.version
+        );
+    }
+}
+
+fn print_error(pdu: &ErrorPdu, sender: SocketAddr) {
+    println!(
+        "Got ERROR ({}, code {}) from {} for request type {}: {}",
+        pdu.code_name(),
+        pdu.code,
+        sender,
+        pdu.request_type,
+        pdu.message
+    );
+}
+
+fn new_trace_id() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    nanos ^ ((process::id() as u64) << 32)
+}
+
 fn get_my_address(
-    mut udp_wrapper: &mut UdpWrapper,
-    mut udp_socket: &mut UdpSocket,
+    udp_wrapper: &mut UdpWrapper,
+    udp_socket: &mut UdpSocket,
     tracker_addr: SocketAddr,
 ) -> Ipv4Addr {
     let lookup = StunLookupPdu::new();
-    udp_wrapper.send(&mut udp_socket, lookup.into(), tracker_addr);
+    udp_wrapper.send(udp_socket, lookup.into(), tracker_addr);
 
-    if let (PDU::StunResponse(pdu), _) = poll_response(&mut udp_wrapper, &mut udp_socket) {
+    if let (PDU::StunResponse(pdu), _) = poll_response(udp_wrapper, udp_socket) {
         let own_address: Ipv4Addr = pdu.address.into();
-        println!("Got STUN_RESPONSE, my address is: {:?}", own_address);
-        return own_address;
+        info!("Got STUN_RESPONSE, my address is: {:?}", own_address);
+        own_address
     } else {
         panic!("Expected stun response, got something else");
     }
 }
 
 fn get_node(
-    mut udp_wrapper: &mut UdpWrapper,
-    mut udp_socket: &mut UdpSocket,
+    udp_wrapper: &mut UdpWrapper,
+    udp_socket: &mut UdpSocket,
     tracker_addr: SocketAddr,
 ) -> Option<SocketAddr> {
     let lookup = NetGetNodePdu::new();
-    udp_wrapper.send(&mut udp_socket, lookup.into(), tracker_addr);
+    udp_wrapper.send(udp_socket, lookup.into(), tracker_addr);
 
-    if let (PDU::NetGetNodeResponse(pdu), _) = poll_response(&mut udp_wrapper, &mut udp_socket) {
-        return if pdu.address == 0 && pdu.port == 0 {
+    if let (PDU::NetGetNodeResponse(pdu), _) = poll_response(udp_wrapper, udp_socket) {
+        if pdu.address == 0 && pdu.port == 0 {
             None
         } else {
             let ip: Ipv4Addr = pdu.address.into();
             Some((ip, pdu.port).into())
-        };
+        }
     } else {
         panic!("Expected stun response, got something else");
     }
@@ -170,29 +609,418 @@ fn get_node(
 
 fn ask_for(s: &str) -> String {
     print!("{}: ", s);
-    io::stdout().flush().ok().expect("Could not flush stdout");
+    io::stdout().flush().expect("Could not flush stdout");
     let mut buf = String::new();
     io::stdin().read_line(&mut buf).unwrap();
     buf.trim().to_owned()
 }
 
+/// Wraps an insert in a `ValTtlPdu` if `--ttl` is given.
+fn with_ttl(insert: PDU, opt: &Opt) -> PDU {
+    match opt.ttl {
+        Some(ttl) => ValTtlPdu::new(ttl, insert).into(),
+        None => insert,
+    }
+}
+
+/// Asks for an ssn until it is valid, and returns it as 12 digits.
+fn ask_for_ssn(opt: &Opt) -> String {
+    loop {
+        match Ssn::parse_checked(&ask_for("ssn"), opt.luhn) {
+            Ok(ssn) => return ssn.into(),
+            Err(e) => println!("The ssn {}", e),
+        }
+    }
+}
+
+/// Asks for a name or email until it is short enough to store.
+fn ask_for_text(s: &str) -> String {
+    loop {
+        let text = ask_for(s);
+        match check_text(s, &text) {
+            Ok(()) => return text,
+            Err(message) => println!("{}", message),
+        }
+    }
+}
+
+/// Checks that a name or email is at most `MAX_TEXT_LENGTH` bytes, which
+/// nodes reject otherwise.
+fn check_text(field: &str, text: &str) -> Result<(), String> {
+    if text.len() > MAX_TEXT_LENGTH {
+        return Err(format!(
+            "The {} is {} bytes, at most {} are allowed",
+            field,
+            text.len(),
+            MAX_TEXT_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn ask_for_version(s: &str) -> u64 {
+    loop {
+        match ask_for(s).parse() {
+            Ok(version) => return version,
+            Err(_) => println!("Not a version"),
+        }
+    }
+}
+
+/// Queries every node and prints the matches once the whole ring has answered,
+/// or what has arrived when `QUERY_TIMEOUT` runs out.
+fn find(
+    (field, value): (u8, String),
+    max_hash: u64,
+    udp_wrapper: &mut UdpWrapper,
+    udp_socket: &mut UdpSocket,
+    node: SocketAddr,
+    my_address: Ipv4Addr,
+    my_port: u16,
+) -> std::io::Result<()> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(16);
+    const RESPONSE: Token = Token(1);
+
+    poll.registry()
+        .register(udp_socket, RESPONSE, Interest::READABLE)?;
+
+    let query = ValQueryPdu::new(field, value, 0, my_address.into(), my_port);
+    udp_wrapper.send(udp_socket, query.into(), node);
+
+    // Parts received for each answered range, by the start of the range
+    let mut ranges: HashMap<u64, (u64, u8, u8)> = HashMap::new();
+    let mut entries = Vec::new();
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    let complete = loop {
+        udp_wrapper.try_read(udp_socket);
+        while let Some(message) = udp_wrapper.next_pdu() {
+            match message {
+                (PDU::ValQueryResponse(pdu), _) => {
+                    let received = ranges.get(&pdu.range_start).map_or(0, |r| r.2);
+                    ranges.insert(pdu.range_start, (pdu.range_end, pdu.parts, received + 1));
+                    entries.extend(pdu.entries);
+                }
+                (PDU::Error(pdu), sender) => print_error(&pdu, sender),
+                (pdu, sender) => warn!("Ignoring unexpected {:?} from {:?}", pdu, sender),
+            }
+        }
+
+        if covers_ring(&ranges, max_hash) {
+            break true;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            break false;
+        }
+        poll.poll(&mut events, Some(deadline - now))?;
+    };
+
+    entries.sort_by(|a, b| a.ssn.cmp(&b.ssn));
+    for e in &entries {
+        println!("ssn: {}, name: {}, email: {}", e.ssn, e.name, e.email);
+    }
+    println!("Found {} entries", entries.len());
+    if !complete {
+        println!("Not every node answered in time, there may be more");
+    }
+    Ok(())
+}
+
+/// Whether the answered ranges cover every hash and have sent all their parts.
+fn covers_ring(ranges: &HashMap<u64, (u64, u8, u8)>, max_hash: u64) -> bool {
+    let mut start = 0;
+    loop {
+        match ranges.get(&start) {
+            Some(&(end, parts, received)) if received >= parts => {
+                if end == max_hash {
+                    return true;
+                }
+                start = end + 1;
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Subscribes and prints notifications until interrupted, renewing the
+/// subscription halfway through every lease.
+fn watch(
+    subscribe: impl Fn() -> ValSubscribePdu,
+    lease: u32,
+    udp_wrapper: &mut UdpWrapper,
+    udp_socket: &mut UdpSocket,
+    node: SocketAddr,
+) -> std::io::Result<()> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(16);
+    const NOTIFY: Token = Token(1);
+
+    poll.registry()
+        .register(udp_socket, NOTIFY, Interest::READABLE)?;
+
+    let renew_every = Duration::from_secs(lease.into()) / 2;
+    let mut renew_at = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= renew_at {
+            info!("Subscribing for {} seconds", lease);
+            udp_wrapper.send(udp_socket, subscribe().into(), node);
+            renew_at = now + renew_every;
+        }
+
+        udp_wrapper.try_read(udp_socket);
+        while let Some(message) = udp_wrapper.next_pdu() {
+            match message {
+                (PDU::ValNotify(pdu), _) => print_notification(&pdu),
+                (PDU::Error(pdu), sender) => print_error(&pdu, sender),
+                (pdu, sender) => warn!("Ignoring unexpected {:?} from {:?}", pdu, sender),
+            }
+        }
+
+        let timeout = renew_at.saturating_duration_since(Instant::now());
+        poll.poll(&mut events, Some(timeout))?;
+    }
+}
+
+/// Whether `page` answers a scan from `start` after `cursor`. Nodes page in
+/// the order of hash and ssn, and the cursor is the last entry of the page
+/// before, so a late page from an earlier scan of the range holds the cursor
+/// or entries before it.
+fn answers_scan(
+    page: &ValScanResponsePdu,
+    start: u64,
+    cursor: Option<&str>,
+    hasher: &dyn KeyHasher,
+) -> bool {
+    let position = |ssn: &str| (hasher.hash(ssn.as_bytes()), ssn.to_owned());
+    let after = |c| {
+        page.entries
+            .first()
+            .is_none_or(|e| position(&e.ssn) > position(c))
+    };
+    page.range_start == start && cursor.is_none_or(after)
+}
+
+/// Scans the ring from hash 0 and prints the ranges of every node that
+/// answers and its share of the ring. Adjacent ranges of a node are answered
+/// as one.
+fn ring(
+    hasher: &dyn KeyHasher,
+    udp_wrapper: &mut UdpWrapper,
+    udp_socket: &mut UdpSocket,
+    node: SocketAddr,
+    my_address: Ipv4Addr,
+    my_port: u16,
+) -> std::io::Result<()> {
+    let max_hash = hasher.max_hash();
+    let mut owners: Vec<(SocketAddr, Vec<(u64, u64)>)> = Vec::new();
+    let mut start = 0;
+    loop {
+        let scan = || ValScanPdu::new(start, max_hash, 1, None, my_address.into(), my_port);
+        let (page, owner) = scan_page(scan, hasher, udp_wrapper, udp_socket, node)?;
+        let range = (start, page.range_end);
+        match owners.iter_mut().find(|(n, _)| *n == owner) {
+            Some((_, ranges)) => ranges.push(range),
+            None => owners.push((owner, vec![range])),
+        }
+        if page.range_end >= max_hash {
+            break;
+        }
+        start = page.range_end + 1;
+    }
+
+    let ring = u128::from(max_hash) + 1;
+    for (owner, ranges) in &owners {
+        let hashes: u128 = ranges
+            .iter()
+            .map(|&(min, max)| u128::from(max - min) + 1)
+            .sum();
+        println!(
+            "Node {} owns {} hashes, {:.1}% of the ring, in {} range{}",
+            owner,
+            hashes,
+            hashes as f64 * 100.0 / ring as f64,
+            ranges.len(),
+            if ranges.len() == 1 { "" } else { "s" }
+        );
+        for (min, max) in ranges {
+            println!("  ({}, {})", min, max);
+        }
+    }
+    Ok(())
+}
+
+/// Sends the scan `scan` builds through `node`, and returns the page that
+/// answers it and the node that sent it. A page that does not arrive within
+/// `QUERY_TIMEOUT` is asked for again, up to `SCAN_RETRIES` times.
+fn scan_page(
+    scan: impl Fn() -> ValScanPdu,
+    hasher: &dyn KeyHasher,
+    udp_wrapper: &mut UdpWrapper,
+    udp_socket: &mut UdpSocket,
+    node: SocketAddr,
+) -> std::io::Result<(ValScanResponsePdu, SocketAddr)> {
+    let request = scan();
+    let (start, cursor) = (request.range_start, request.cursor.clone());
+    udp_wrapper.send(udp_socket, request.into(), node);
+
+    let mut retries = 0;
+    loop {
+        match poll_response_within(udp_wrapper, udp_socket, Some(QUERY_TIMEOUT)) {
+            Some((PDU::ValScanResponse(pdu), sender))
+                if answers_scan(&pdu, start, cursor.as_deref(), hasher) =>
+            {
+                return Ok((pdu, sender))
+            }
+            Some((PDU::Error(pdu), sender)) => {
+                print_error(&pdu, sender);
+                return Err(io::Error::other("scan failed"));
+            }
+            Some((pdu, sender)) => warn!("Ignoring unexpected {:?} from {:?}", pdu, sender),
+            None if retries < SCAN_RETRIES => {
+                retries += 1;
+                warn!("No answer to the scan from {}, sending it again", start);
+                udp_wrapper.send(udp_socket, scan().into(), node);
+            }
+            None => {
+                let message = format!("no answer to the scan from {}", start);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, message));
+            }
+        }
+    }
+}
+
+/// Scans the ring page by page, from hash 0 to the end of the ring of
+/// `hasher`, and writes every entry to `out`.
+fn dump(
+    out: &str,
+    hasher: &dyn KeyHasher,
+    udp_wrapper: &mut UdpWrapper,
+    udp_socket: &mut UdpSocket,
+    node: SocketAddr,
+    my_address: Ipv4Addr,
+    my_port: u16,
+) -> std::io::Result<()> {
+    let max_hash = hasher.max_hash();
+    let mut writer = csv::Writer::from_path(out)?;
+    let mut start = 0;
+    let mut cursor = None;
+    let mut count = 0;
+
+    loop {
+        let scan = || {
+            let (address, cursor) = (my_address.into(), cursor.clone());
+            ValScanPdu::new(start, max_hash, MAX_SCAN_ENTRIES, cursor, address, my_port)
+        };
+        let (page, _) = scan_page(scan, hasher, udp_wrapper, udp_socket, node)?;
+        info!(
+            "Got {} entries in ({}, {})",
+            page.entries.len(),
+            page.range_start,
+            page.range_end
+        );
+
+        for e in page.entries {
+            writer.serialize(Person {
+                ssn: e.ssn,
+                name: e.name,
+                email: e.email,
+            })?;
+            count += 1;
+        }
+
+        if page.more {
+            cursor = page.cursor;
+        } else if page.range_end >= max_hash {
+            break;
+        } else {
+            start = page.range_end + 1;
+            cursor = None;
+        }
+    }
+
+    writer.flush()?;
+    println!("Wrote {} entries to {}", count, out);
+    Ok(())
+}
+
+/// Whether `entry` can join `batch` with the batch still within
+/// `MAX_ENTRIES_SIZE`.
+fn fits_batch(batch: &[PDU], entry: &PDU) -> bool {
+    ValBatchPdu::size_of(batch) + ValBatchPdu::entry_size(entry) <= MAX_ENTRIES_SIZE
+}
+
 fn send_csv(
     csv: String,
     opt: &Opt,
     udp_wrapper: &mut UdpWrapper,
-    mut udp_socket: &mut UdpSocket,
+    udp_socket: &mut UdpSocket,
     node: SocketAddr,
 ) -> std::io::Result<()> {
     let file = File::open(csv);
     let mut rdr = csv::Reader::from_reader(file.unwrap());
     let seconds = time::Duration::from_secs(opt.delay);
+    let batch_size = opt.batch_size.clamp(1, MAX_BATCH_ENTRIES) as usize;
 
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut send_batch = |batch: &mut Vec<PDU>| {
+        let pdu = match batch.len() {
+            1 => batch.pop().unwrap(),
+            _ => ValBatchPdu::new(std::mem::take(batch)).into(),
+        };
+        udp_wrapper.send(udp_socket, pdu, node);
+        thread::sleep(seconds);
+    };
     for result in rdr.deserialize() {
         let person: Person = result?;
-        let insert_pdu = ValInsertPdu::new(person.ssn, person.name, person.email);
-        udp_wrapper.send(&mut udp_socket, insert_pdu.into(), node);
-        thread::sleep(seconds);
+        let ssn = match Ssn::parse_checked(&person.ssn, opt.luhn) {
+            Ok(ssn) => ssn,
+            Err(e) => {
+                println!("Skipping ssn {:?}: the ssn {}", person.ssn, e);
+                continue;
+            }
+        };
+        let checked = check_text("name", &person.name).and(check_text("email", &person.email));
+        if let Err(message) = checked {
+            println!("Skipping ssn {}: {}", ssn, message);
+            continue;
+        }
+
+        let insert_pdu = ValInsertPdu::new(ssn.into(), person.name, person.email);
+        let pdu = with_ttl(insert_pdu.into(), opt);
+        // Long names and emails fill a datagram before the batch is full
+        if !batch.is_empty() && !fits_batch(&batch, &pdu) {
+            send_batch(&mut batch);
+        }
+        batch.push(pdu);
+        if batch.len() == batch_size {
+            send_batch(&mut batch);
+        }
     }
+    if !batch.is_empty() {
+        send_batch(&mut batch);
+    }
+    print_errors(udp_wrapper, udp_socket);
 
     Ok(())
 }
+
+#[test]
+fn test_fits_batch() {
+    // Four of the inserts fit in a batch, but not with their TTLs
+    let insert = |i| {
+        let ssn = format!("1980010100{:02}", i);
+        let name = "n".repeat(4071);
+        ValTtlPdu::new(60, ValInsertPdu::new(ssn, name, "a@b.c".into()).into()).into()
+    };
+    let mut batch = Vec::new();
+    for i in 0..3 {
+        let entry = insert(i);
+        assert!(fits_batch(&batch, &entry));
+        batch.push(entry);
+    }
+    assert!(!fits_batch(&batch, &insert(3)));
+    let bytes = PDU::from(ValBatchPdu::new(batch)).to_bytes();
+    assert!(bytes.len() <= MAX_ENTRIES_SIZE);
+}
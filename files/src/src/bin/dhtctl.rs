@@ -0,0 +1,118 @@
+use structopt::StructOpt;
+
+use std::net::SocketAddrV4;
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mio::net::UdpSocket;
+use mio::{Events, Interest, Poll, Token};
+use ou2::pdu::*;
+use ou2::socket_wrapper::UdpWrapper;
+
+use tracing::warn;
+
+/// How long to wait for a node to refuse a control PDU, which has no response
+/// when it is taken
+const ERROR_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "dhtctl")]
+/// Controls nodes started with --control-key-file
+struct Opt {
+    /// File holding the control key of the node
+    #[structopt(long)]
+    control_key_file: String,
+
+    /// Write log output as JSON, one object per line
+    #[structopt(long)]
+    log_json: bool,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Make a node leave the network, handing its ranges to a neighbour as on
+    /// Ctrl-C
+    Leave {
+        /// Node ip and port, the address the node sees itself at
+        node: SocketAddrV4,
+    },
+    /// Make a node hand all its ranges to a neighbour and stay in the network
+    /// without any
+    Handoff {
+        /// Node ip and port, the address the node sees itself at
+        node: SocketAddrV4,
+        /// Neighbour to hand the ranges to
+        #[structopt(possible_values = &["successor", "predecessor"])]
+        to: String,
+    },
+}
+
+fn main() -> std::io::Result<()> {
+    let opt = Opt::from_args();
+    ou2::logging::init(opt.log_json);
+    let key = std::fs::read_to_string(&opt.control_key_file)?;
+    let key = key.trim().as_bytes();
+
+    let (command, node) = match &opt.command {
+        Command::Leave { node } => (CONTROL_LEAVE, *node),
+        Command::Handoff { node, to } if to == "successor" => (CONTROL_HANDOFF_SUCCESSOR, *node),
+        Command::Handoff { node, .. } => (CONTROL_HANDOFF_PREDECESSOR, *node),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let control = NetControlPdu::new(
+        command,
+        (*node.ip()).into(),
+        node.port(),
+        now.as_millis() as u64,
+        key,
+    );
+
+    let mut udp_socket = UdpSocket::bind("0.0.0.0:0".parse().unwrap())?;
+    let mut udp_wrapper = UdpWrapper::new();
+    udp_wrapper.send(&mut udp_socket, control.into(), node.into());
+    if refused(&mut udp_wrapper, &mut udp_socket)? {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Prints the errors that arrive within `ERROR_TIMEOUT` and returns whether
+/// there were any.
+fn refused(udp_wrapper: &mut UdpWrapper, udp_socket: &mut UdpSocket) -> std::io::Result<bool> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(16);
+    const RESPONSE: Token = Token(1);
+
+    poll.registry()
+        .register(udp_socket, RESPONSE, Interest::READABLE)?;
+
+    let mut refused = false;
+    let deadline = Instant::now() + ERROR_TIMEOUT;
+    loop {
+        udp_wrapper.try_read(udp_socket);
+        while let Some(message) = udp_wrapper.next_pdu() {
+            match message {
+                (PDU::Error(pdu), sender) => {
+                    println!(
+                        "Got ERROR ({}, code {}) from {}: {}",
+                        pdu.code_name(),
+                        pdu.code,
+                        sender,
+                        pdu.message
+                    );
+                    refused = true;
+                }
+                (pdu, sender) => warn!("Ignoring unexpected {:?} from {:?}", pdu, sender),
+            }
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(refused);
+        }
+        poll.poll(&mut events, Some(deadline - now))?;
+    }
+}
@@ -1,4 +1,6 @@
-use std::net::Ipv4Addr;
+use ou2::hash::{self, HASHERS};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -8,39 +10,229 @@ struct Opt {
     tracker_address: Ipv4Addr,
     /// Tracker port
     tracker_port: u16,
+    /// Write log output as JSON, one object per line
+    #[structopt(long)]
+    log_json: bool,
+    /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9100
+    #[structopt(long)]
+    metrics: Option<SocketAddr>,
+    /// Number of times a value PDU may be forwarded before it is dropped
+    #[structopt(long, default_value = "255")]
+    hop_limit: u8,
+    /// Maximum number of entries the node stores, unlimited if not set
+    #[structopt(long)]
+    max_entries: Option<usize>,
+    /// Index entries by email and name, which speeds up queries on them
+    #[structopt(long)]
+    index: bool,
+    /// Reject ssns whose last digit is not a valid Luhn check digit
+    #[structopt(long)]
+    luhn: bool,
+    /// Hash function that places keys on the ring, every node must use the same
+    #[structopt(long, default_value = "djb2", possible_values = HASHERS)]
+    hasher: String,
+    /// Number of virtual ranges the first node splits the ring into. Joining
+    /// nodes take half of every range of the node they join at
+    #[structopt(long, default_value = "1")]
+    vnodes: u32,
+    /// What joining nodes are placed by: the node with the largest span,
+    /// entries, requests per minute or entries plus requests is joined at.
+    /// Every node must use the same
+    #[structopt(
+        long,
+        default_value = "span",
+        possible_values = &["span", "entries", "requests", "combined"]
+    )]
+    join_weight: node::JoinWeight,
+    /// Offer load to both neighbours this often, in seconds, so the heavier of
+    /// two neighbours moves part of its range to the lighter. Off if not set
+    #[structopt(long)]
+    rebalance_every: Option<u64>,
+    /// How many times the load of a neighbour a node must carry before it
+    /// moves part of its range there, at least 1
+    #[structopt(long, default_value = "1.5")]
+    rebalance_skew: f64,
+    /// Seconds to wait for a new predecessor to connect after a join, before
+    /// the node goes on without it
+    #[structopt(long, default_value = "10")]
+    accept_timeout: u64,
+    /// Threads that store entries and answer value requests, each for the
+    /// hashes of one bucket, while the main thread does the I/O. With 0 the
+    /// main thread does everything
+    #[structopt(long, default_value = "0")]
+    workers: usize,
+    /// Seconds a node asked to leave goes on handling and forwarding the PDUs
+    /// that still arrive, before it hands over its range. It leaves earlier
+    /// once no more arrive
+    #[structopt(long, default_value = "2")]
+    drain_timeout: u64,
+    /// File holding the key that `client leave` and `client handoff` sign
+    /// their control PDUs with. Control PDUs are refused if not set
+    #[structopt(long)]
+    control_key_file: Option<String>,
 }
 
 fn main() {
     let opt = Opt::from_args();
-    let mut node = node::Node::new((opt.tracker_address, opt.tracker_port).into());
+    ou2::logging::init(opt.log_json);
+    if let Some(addr) = opt.metrics {
+        ou2::metrics::serve(addr).expect("Failed to start metrics endpoint");
+    }
+    assert!(
+        opt.rebalance_skew >= 1.0,
+        "--rebalance-skew must be at least 1"
+    );
+    let control_key = opt.control_key_file.map(|file| {
+        let key = std::fs::read_to_string(file).expect("Failed to read the control key");
+        assert!(!key.trim().is_empty(), "The control key file is empty");
+        key.trim().as_bytes().to_vec()
+    });
+    let config = node::Config {
+        hop_limit: opt.hop_limit,
+        max_entries: opt.max_entries,
+        index: opt.index,
+        luhn: opt.luhn,
+        hasher: hash::by_name(&opt.hasher).expect("structopt only accepts known hashers"),
+        vnodes: opt.vnodes,
+        join_weight: opt.join_weight,
+        rebalance_every: opt.rebalance_every.map(Duration::from_secs),
+        rebalance_skew: opt.rebalance_skew,
+        accept_timeout: Duration::from_secs(opt.accept_timeout),
+        workers: opt.workers,
+        drain_timeout: Duration::from_secs(opt.drain_timeout),
+        control_key,
+    };
+    let mut node = node::Node::new((opt.tracker_address, opt.tracker_port).into(), config);
     node.run();
 }
 
 #[allow(dead_code)]
 mod node {
     use ou2::socket_wrapper::*;
-    use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener};
-    use std::time::{Duration, Instant};
+    use std::collections::{BTreeSet, HashMap, VecDeque};
+    use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-    use mio::net::{TcpStream, UdpSocket};
+    use mio::net::{TcpListener, TcpStream, UdpSocket};
     use mio::{Events, Interest, Poll, Token};
     use std::io::prelude::*;
+    use std::io::ErrorKind;
 
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::{Arc, RwLock};
+    use std::thread::{self, JoinHandle};
 
-    use ctrlc;
+    use tracing::{debug, info, info_span, trace, warn, Span};
 
+    use ou2::hash::KeyHasher;
     use ou2::pdu::*;
     use State::*;
 
     const UDP: Token = Token(0);
     const SUCCESSOR: Token = Token(1);
     const PREDECESSOR: Token = Token(2);
+    const LISTEN: Token = Token(3);
+
+    /// How long a node waits after moving part of a range before it moves
+    /// more, so offers sent before the move do not make it move twice.
+    const REBALANCE_COOLDOWN: Duration = Duration::from_secs(10);
+
+    /// How long a joining node first waits for its predecessor to connect
+    /// before it sends NET_JOIN again. The wait doubles with every attempt, up
+    /// to `JOIN_TIMEOUT_MAX`.
+    const JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+    const JOIN_TIMEOUT_MAX: Duration = Duration::from_secs(32);
+
+    /// How long no PDU may arrive before a draining node considers itself
+    /// drained.
+    const DRAIN_QUIET: Duration = Duration::from_millis(100);
+
+    /// How far the timestamp of a control PDU may be from the clock of the
+    /// node.
+    const CONTROL_WINDOW: Duration = Duration::from_secs(30);
+
+    mod metrics {
+        use ou2::metrics::Metric;
+
+        pub static ENTRIES: Metric = Metric::gauge("dht_entries", "Entries stored", &[]);
+        pub static KV_ENTRIES: Metric =
+            Metric::gauge("dht_kv_entries", "Key/value entries stored", &[]);
+        pub static RANGE_SIZE: Metric = Metric::gauge(
+            "dht_hash_range_size",
+            "Number of hash values the node is responsible for",
+            &[],
+        );
+        pub static VIRTUAL_RANGES: Metric = Metric::gauge(
+            "dht_virtual_ranges",
+            "Number of virtual ranges the node is responsible for",
+            &[],
+        );
+        pub static REQUEST_RATE: Metric = Metric::gauge(
+            "dht_requests_per_minute",
+            "Value requests the node answered itself, per minute",
+            &[],
+        );
+        pub static STATE_TRANSITIONS: Metric = Metric::counter(
+            "dht_state_transitions_total",
+            "Transitions into each state",
+            &["state"],
+        );
+        pub static FORWARDED: Metric = Metric::counter(
+            "dht_pdus_forwarded_total",
+            "PDUs forwarded to the successor",
+            &["type"],
+        );
+        pub static TRANSFERS: Metric = Metric::counter(
+            "dht_transfers_total",
+            "Range transfers to a neighbour",
+            &["direction"],
+        );
+        pub static TRANSFERRED_ENTRIES: Metric = Metric::counter(
+            "dht_transferred_entries_total",
+            "Entries moved to a neighbour",
+            &["direction"],
+        );
+        pub static REBALANCES: Metric = Metric::counter(
+            "dht_rebalances_total",
+            "Parts of a range moved to a lighter neighbour",
+            &["direction"],
+        );
+        pub static HOP_LIMIT_EXCEEDED: Metric = Metric::counter(
+            "dht_hop_limit_exceeded_total",
+            "Value PDUs dropped because they ran out of hops",
+            &["type"],
+        );
+        pub static EXPIRED: Metric = Metric::counter(
+            "dht_entries_expired_total",
+            "Entries removed by their TTL",
+            &[],
+        );
+        pub static ERRORS_SENT: Metric = Metric::counter(
+            "dht_errors_sent_total",
+            "ERROR PDUs sent to originators",
+            &["code"],
+        );
+        pub static LAST_TRANSFER_SIZE: Metric = Metric::gauge(
+            "dht_last_transfer_size",
+            "Entries moved by the most recent transfer",
+            &[],
+        );
+        pub static SUBSCRIPTIONS: Metric = Metric::gauge(
+            "dht_subscriptions",
+            "Subscriptions with an active lease",
+            &[],
+        );
+        pub static NOTIFICATIONS_SENT: Metric = Metric::counter(
+            "dht_notifications_sent_total",
+            "Change notifications sent to subscribers",
+            &[],
+        );
+    }
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy)]
     enum Source {
-        UDP(SocketAddr),
+        Udp(SocketAddr),
         Predecessor(SocketAddr),
         Successor(SocketAddr),
     }
@@ -67,13 +259,95 @@ mod node {
         Q18,
     }
 
+    pub struct Config {
+        pub hop_limit: u8,
+        pub max_entries: Option<usize>,
+        pub index: bool,
+        pub luhn: bool,
+        pub hasher: Box<dyn KeyHasher>,
+        pub vnodes: u32,
+        pub join_weight: JoinWeight,
+        pub rebalance_every: Option<Duration>,
+        pub rebalance_skew: f64,
+        pub accept_timeout: Duration,
+        pub workers: usize,
+        pub drain_timeout: Duration,
+        pub control_key: Option<Vec<u8>>,
+    }
+
+    /// Why the node waits for a predecessor to connect.
+    #[derive(Debug)]
+    enum Awaiting {
+        /// The node sent NET_JOIN and waits for the node it joins at.
+        Join,
+        /// The node was alone and gave `given` to the node that joined it.
+        Split { given: Vec<(u64, u64)> },
+        /// The predecessor closed the connection to let a joining node in.
+        Repair,
+    }
+
+    /// What the NET_JOIN search for the node to join at compares. With any
+    /// other weight than the span, ranges are split at the median hash of their
+    /// entries instead of the midpoint, so the new node takes half the load.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum JoinWeight {
+        Span,
+        Entries,
+        Requests,
+        Combined,
+    }
+
+    impl std::str::FromStr for JoinWeight {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "span" => Ok(JoinWeight::Span),
+                "entries" => Ok(JoinWeight::Entries),
+                "requests" => Ok(JoinWeight::Requests),
+                "combined" => Ok(JoinWeight::Combined),
+                _ => Err(format!("unknown join weight {:?}", s)),
+            }
+        }
+    }
+
     pub struct Node {
+        config: Config,
         state: State,
         own_address: Option<Ipv4Addr>,
-        values: Vec<Entry>,
-        hash_range: (u8, u8),
+        storage: Storage,
+        stored: Arc<Stored>,
+        subscriptions: Arc<RwLock<Vec<Subscription>>>,
+        /// Virtual ranges this node is responsible for, sorted and disjoint.
+        ranges: Vec<(u64, u64)>,
+        /// Neighbour that sent the `NetNewRangePdu` being handled.
+        range_sender: Option<Source>,
+        /// NET_JOINs waiting to be handled. Joins are handled one at a time, so
+        /// several nodes joining at once cannot race for the same range.
+        joins: VecDeque<NetJoinPdu>,
+        /// NET_JOINs this node has sent without a predecessor connecting.
+        join_attempts: u32,
+        /// The predecessor the node waits for and when it gives up on it.
+        /// Connections are only accepted while waiting.
+        awaiting: Option<(Awaiting, Instant)>,
+        /// The neighbour q11 sent the NET_NEW_RANGE of a leave to, true for the
+        /// successor. The entries go to the same neighbour in q18.
+        leaving_to: Option<bool>,
+        /// When a node asked to leave stops waiting for PDUs still under way.
+        drain_until: Option<Instant>,
+        /// Timestamp of the last control PDU taken, older ones are refused.
+        last_control: u64,
         last_alive: Instant,
+        last_offer: Instant,
+        /// When part of a range was last moved to a neighbour.
+        last_move: Option<Instant>,
         last_pdu: Option<PDU>,
+        trace: Option<TraceContext>,
+        route: Option<RouteContext>,
+        version: Option<u64>,
+        ttl: Option<u32>,
+        clock: HybridClock,
+        requests: RequestRate,
         successor_listen: Option<SocketAddr>,
         should_close: Arc<AtomicBool>,
         running: bool,
@@ -90,10 +364,15 @@ mod node {
         predecessor: Option<TcpStream>,
         // D
         listen_socket: TcpListener,
+        /// Polls every socket of the node. Sockets are registered once, and
+        /// neighbour connections again when they are replaced.
+        poll: Poll,
+        events: Events,
+        span: Span,
     }
 
     impl Node {
-        pub fn new(tracker_addr: SocketAddr) -> Self {
+        pub fn new(tracker_addr: SocketAddr, config: Config) -> Self {
             let should_close = Arc::new(AtomicBool::new(false));
             let sc = should_close.clone();
             ctrlc::set_handler(move || {
@@ -101,26 +380,67 @@ mod node {
             })
             .expect("Error setting sigint handler");
 
+            let udp_socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+            udp_socket.set_nonblocking(true).unwrap();
+            let stored = Arc::new(Stored::default());
+            let subscriptions = Arc::new(RwLock::new(Vec::new()));
+            let storage = Storage::new(&config, &udp_socket, &stored, &subscriptions);
+            let mut udp_socket = UdpSocket::from_std(udp_socket);
+            let mut listen_socket = TcpListener::bind("0.0.0.0:0".parse().unwrap()).unwrap();
+            let poll = Poll::new().unwrap();
+            poll.registry()
+                .register(&mut udp_socket, UDP, Interest::READABLE)
+                .unwrap();
+            poll.registry()
+                .register(&mut listen_socket, LISTEN, Interest::READABLE)
+                .unwrap();
+            let span = info_span!(
+                "node",
+                udp = %udp_socket.local_addr().unwrap(),
+                listen = %listen_socket.local_addr().unwrap(),
+            );
+
             let n = Node {
+                config,
                 running: true,
                 state: Q1,
                 tracker_addr,
-                udp_socket: UdpSocket::bind("0.0.0.0:0".parse().unwrap()).unwrap(),
+                udp_socket,
                 udp_wrapper: UdpWrapper::new(),
-                successor_wrapper: TcpWrapper::new(),
-                predecessor_wrapper: TcpWrapper::new(),
+                successor_wrapper: TcpWrapper::with_name("successor"),
+                predecessor_wrapper: TcpWrapper::with_name("predecessor"),
                 successor: None,
                 successor_listen: None,
                 predecessor: None,
-                listen_socket: TcpListener::bind("0.0.0.0:0").unwrap(),
+                listen_socket,
+                poll,
+                events: Events::with_capacity(16),
                 own_address: None,
-                values: Vec::new(),
-                hash_range: (0, 0),
+                storage,
+                stored,
+                subscriptions,
+                ranges: Vec::new(),
+                range_sender: None,
+                joins: VecDeque::new(),
+                join_attempts: 0,
+                awaiting: None,
+                leaving_to: None,
+                drain_until: None,
+                last_control: 0,
                 last_alive: Instant::now() - Duration::from_secs(100),
+                last_offer: Instant::now(),
+                last_move: None,
                 last_pdu: None,
+                trace: None,
+                route: None,
+                version: None,
+                ttl: None,
+                clock: HybridClock::default(),
+                requests: RequestRate::default(),
                 should_close,
+                span,
             };
-            println!(
+            info!(
                 "Node listening on UDP {:?}, accepts TCP connections on {:?}",
                 n.udp_socket.local_addr().unwrap(),
                 n.get_listen_addr(),
@@ -130,7 +450,17 @@ mod node {
         }
 
         pub fn run(&mut self) {
+            let mut previous = None;
             while self.running {
+                // The span is replaced whenever the hash range changes
+                let _entered = self.span.clone().entered();
+
+                let current = std::mem::discriminant(&self.state);
+                if previous != Some(current) {
+                    metrics::STATE_TRANSITIONS.inc(&[&format!("{:?}", self.state)]);
+                    previous = Some(current);
+                }
+
                 match &self.state {
                     Q1 => self.q1(),
                     Q2 => self.q2(),
@@ -155,801 +485,2992 @@ mod node {
         }
     }
 
+    /// Trace context of the value PDU currently being handled.
+    struct TraceContext {
+        trace_id: u64,
+        hop_count: u8,
+        report_address: u32,
+        report_port: u16,
+    }
+
+    /// Forwards left and originator of the value PDU currently being handled.
+    struct RouteContext {
+        hops_left: u8,
+        origin: Option<SocketAddr>,
+    }
+
+    /// Hybrid logical clock used to version entries. The upper 48 bits are
+    /// milliseconds since the epoch and the lower 16 bits a counter, so versions
+    /// from different nodes are roughly ordered by wall time and never repeat
+    /// on one node.
+    #[derive(Default)]
+    struct HybridClock {
+        last: u64,
+    }
+
+    impl HybridClock {
+        fn now(&mut self) -> u64 {
+            let millis = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+            self.last = (millis << 16).max(self.last + 1);
+            self.last
+        }
+
+        /// Makes sure versions issued later are newer than `version`.
+        fn observe(&mut self, version: u64) {
+            self.last = self.last.max(version);
+        }
+    }
+
+    /// Requests per minute, kept as a count that halves every minute.
+    #[derive(Default)]
+    struct RequestRate {
+        count: f64,
+        last: Option<Instant>,
+    }
+
+    impl RequestRate {
+        const HALF_LIFE: Duration = Duration::from_secs(60);
+
+        fn decayed(&self, now: Instant) -> f64 {
+            match self.last {
+                Some(t) => {
+                    let halvings = (now - t).as_secs_f64() / Self::HALF_LIFE.as_secs_f64();
+                    self.count * 0.5f64.powf(halvings)
+                }
+                None => 0.0,
+            }
+        }
+
+        fn record(&mut self, now: Instant) {
+            self.count = self.decayed(now) + 1.0;
+            self.last = Some(now);
+        }
+
+        /// A steady rate of `r` per minute keeps the count at `r / ln 2`.
+        fn per_minute(&self, now: Instant) -> u64 {
+            (self.decayed(now) * std::f64::consts::LN_2) as u64
+        }
+    }
+
     #[derive(Debug)]
     struct Entry {
+        hash: u64,
         ssn: String,
         name: String,
         email: String,
+        version: u64,
+        expires: Option<Instant>,
     }
 
     impl Entry {
-        pub fn new(ssn: String, name: String, email: String) -> Self {
-            Entry { ssn, name, email }
+        pub fn new(hash: u64, ssn: String, name: String, email: String, version: u64) -> Self {
+            Entry {
+                hash,
+                ssn,
+                name,
+                email,
+                version,
+                expires: None,
+            }
+        }
+
+        pub fn is_expired(&self, now: Instant) -> bool {
+            self.expires.is_some_and(|t| t <= now)
+        }
+
+        /// Whole seconds left to live, rounded up so that a transferred entry
+        /// never expires earlier than it would have.
+        pub fn ttl(&self, now: Instant) -> Option<u32> {
+            self.expires.map(|t| {
+                let left = t.saturating_duration_since(now).as_millis();
+                left.div_ceil(1000) as u32
+            })
+        }
+
+        /// Whether this entry wins over `other` for the same ssn. Equal versions
+        /// are ordered by the values so that every node picks the same entry.
+        pub fn supersedes(&self, other: &Entry) -> bool {
+            (self.version, &self.name, &self.email) > (other.version, &other.name, &other.email)
         }
 
-        pub fn hash(&self) -> u8 {
-            return Entry::hash_ssn(&self.ssn);
+        /// Position of the ssn on the ring, set by the node that created the entry.
+        pub fn hash(&self) -> u64 {
+            self.hash
         }
 
-        pub fn hash_ssn(ssn: &String) -> u8 {
-            let mut hash: u32 = 5381;
-            for c in ssn.chars() {
-                let (h, _) = (hash << 5)
-                    .overflowing_add(hash)
-                    .0
-                    .overflowing_add(c as u32);
-                hash = h;
+        pub fn to_scan_entry(&self) -> ScanEntry {
+            ScanEntry {
+                ssn: self.ssn.clone(),
+                name: self.name.clone(),
+                email: self.email.clone(),
             }
+        }
 
-            (hash % 256) as u8
+        /// The value of a `QUERY_FIELD_*` field.
+        pub fn field(&self, field: u8) -> Option<&str> {
+            match field {
+                QUERY_FIELD_EMAIL => Some(&self.email),
+                QUERY_FIELD_NAME => Some(&self.name),
+                _ => None,
+            }
         }
     }
 
-    #[test]
-    fn test_hash() {
-        let ssn = String::from("aaaaabbbbbcc");
-        assert_eq!(Entry::hash_ssn(&ssn), 26);
+    /// A value stored under an arbitrary key, next to the person entries.
+    #[derive(Debug)]
+    struct KvEntry {
+        hash: u64,
+        value: Vec<u8>,
+        version: u64,
     }
 
-    /// States
-    impl Node {
-        fn q1(&mut self) {
-            println!("[Q1]");
-            let lookup = StunLookupPdu::new();
-            println!(
-                "    Node started, sending STUN_LOOKUP to tracker: {:?}",
-                self.tracker_addr
-            );
-            self.udp_wrapper
-                .send(&mut self.udp_socket, lookup.into(), self.tracker_addr);
-            self.state = Q2;
+    impl KvEntry {
+        /// Whether this value wins over `other` for the same key, see `Entry::supersedes`.
+        pub fn supersedes(&self, other: &KvEntry) -> bool {
+            (self.version, &self.value) > (other.version, &other.value)
         }
+    }
 
-        fn q2(&mut self) {
-            println!("[Q2]");
-            if let (PDU::StunResponse(pdu), _) = self.await_pdu_udp() {
-                self.own_address = Some(pdu.address.into());
-                println!(
-                    "    Got STUN_RESPONSE, my address is: {:?}",
-                    self.own_address.unwrap()
-                );
-                self.state = Q3;
-            } else {
-                panic!("Expected stun response, got something else :(");
+    /// The ssns of the stored entries by email and name, both lowercased.
+    #[derive(Default)]
+    struct Index {
+        by_email: HashMap<String, BTreeSet<String>>,
+        by_name: HashMap<String, BTreeSet<String>>,
+    }
+
+    impl Index {
+        pub fn add(&mut self, e: &Entry) {
+            for (map, key) in [(&mut self.by_email, &e.email), (&mut self.by_name, &e.name)] {
+                map.entry(key.to_lowercase())
+                    .or_default()
+                    .insert(e.ssn.clone());
             }
         }
 
-        fn q3(&mut self) {
-            println!("[Q3]");
-            let get_node = NetGetNodePdu::new();
-            self.udp_wrapper
-                .send(&mut self.udp_socket, get_node.into(), self.tracker_addr);
-            if let (PDU::NetGetNodeResponse(pdu), _) = self.await_pdu_udp() {
-                if pdu.address == 0 && pdu.port == 0 {
-                    println!("    I am the first node to join the network");
-                    self.state = Q4;
-                } else {
-                    self.last_pdu = Some(pdu.into());
-                    self.state = Q7;
+        pub fn remove(&mut self, e: &Entry) {
+            for (map, key) in [(&mut self.by_email, &e.email), (&mut self.by_name, &e.name)] {
+                let key = key.to_lowercase();
+                if let Some(ssns) = map.get_mut(&key) {
+                    ssns.remove(&e.ssn);
+                    if ssns.is_empty() {
+                        map.remove(&key);
+                    }
                 }
-            } else {
-                panic!("Expected NET_GET_NODE_RESPONSE , got something else :(");
             }
         }
 
-        fn q4(&mut self) {
-            println!("[Q4]");
-            self.hash_range = (0, 255);
-            self.state = Q6;
+        /// Ssns of the entries whose `field` is `value`, which must be lowercase.
+        pub fn get(&self, field: u8, value: &str) -> impl Iterator<Item = &String> {
+            let map = match field {
+                QUERY_FIELD_EMAIL => Some(&self.by_email),
+                QUERY_FIELD_NAME => Some(&self.by_name),
+                _ => None,
+            };
+            map.and_then(|m| m.get(value)).into_iter().flatten()
         }
+    }
 
-        fn q5(&mut self) {
-            println!("[Q5]");
+    /// A subscriber to changes of one ssn, or of all entries in a hash range.
+    #[derive(Debug)]
+    struct Subscription {
+        ssn: Option<String>,
+        range: (u64, u64),
+        addr: SocketAddr,
+        expires: Instant,
+    }
 
-            let next_node = match self.last_pdu.take() {
-                Some(PDU::NetJoin(pdu)) => pdu,
-                _ => panic!("Invalid state change, last_pdu is not NetGetNodeResponse"),
-            };
+    impl Subscription {
+        pub fn matches(&self, e: &Entry) -> bool {
+            match &self.ssn {
+                Some(ssn) => *ssn == e.ssn,
+                None => self.range.0 <= e.hash() && e.hash() <= self.range.1,
+            }
+        }
 
-            let addr = next_node.get_src_socket_addr();
-            let mut socket = self.connect_to_successor(addr);
-            let (mins, maxs) = self.split_range();
-            println!("    Other hash-range is {:?}", (mins, maxs));
-            println!("    New hash-range is {:?}", self.hash_range);
+        /// Whether this subscription is replaced by a new one from the same
+        /// subscriber for `ssn`, or for a range overlapping `range`.
+        pub fn is_renewed_by(
+            &self,
+            addr: SocketAddr,
+            ssn: &Option<String>,
+            range: (u64, u64),
+        ) -> bool {
+            self.addr == addr
+                && self.ssn == *ssn
+                && (ssn.is_some() || (self.range.0 <= range.1 && range.0 <= self.range.1))
+        }
 
-            let local = self.get_listen_addr();
-            let join_response =
-                NetJoinResponsePdu::new(self.own_address.unwrap().into(), local.port(), mins, maxs);
-            self.successor_wrapper
-                .send(&mut socket, join_response.into());
-            self.successor = Some(socket);
-            self.transfer(true, mins, maxs);
+        /// Whole seconds left of the lease, rounded up.
+        pub fn lease(&self, now: Instant) -> u32 {
+            let left = self.expires.saturating_duration_since(now).as_millis();
+            left.div_ceil(1000) as u32
+        }
+    }
 
-            self.accept_predecessor();
+    /// Totals over all shards, which each shard updates after every job so the
+    /// node can read them without asking the workers.
+    #[derive(Default)]
+    struct Stored {
+        entries: AtomicUsize,
+        kv: AtomicUsize,
+    }
 
-            self.state = Q6;
+    impl Stored {
+        pub fn entries(&self) -> usize {
+            self.entries.load(Ordering::Relaxed)
         }
 
-        fn q6(&mut self) {
-            println!("[Q6] ({} entries stored)", self.values.len());
+        pub fn kv(&self) -> usize {
+            self.kv.load(Ordering::Relaxed)
+        }
+    }
 
-            self.send_alive();
+    /// What a storage job needs to know about the request that stores a value.
+    struct Request {
+        request_type: u8,
+        /// Where an error about the request is sent.
+        origin: Option<SocketAddr>,
+        node: SocketAddrV4,
+        /// Whether the value was moved from a neighbour, which is always kept.
+        moved: bool,
+    }
 
-            let mut poll = Poll::new().unwrap();
-            if let Some(s) = self.successor.as_mut() {
-                poll.registry()
-                    .register(s, SUCCESSOR, Interest::READABLE)
-                    .unwrap();
-            }
-            if let Some(p) = self.predecessor.as_mut() {
-                poll.registry()
-                    .register(p, PREDECESSOR, Interest::READABLE)
-                    .unwrap();
-            }
+    type Job = Box<dyn FnOnce(&mut Shard) + Send>;
 
-            poll.registry()
-                .register(&mut self.udp_socket, UDP, Interest::READABLE)
-                .unwrap();
+    /// The entries and key/value pairs of one hash bucket. A shard answers the
+    /// requests it handles itself, from whichever thread it runs on.
+    struct Shard {
+        values: Vec<Entry>,
+        index: Option<Index>,
+        kv: HashMap<Vec<u8>, KvEntry>,
+        /// No entry expires before this, entries are only looked through for
+        /// expired ones once it has passed.
+        next_expiry: Option<Instant>,
+        /// Entries and key/value pairs last added to `stored`.
+        counted: (usize, usize),
+        stored: Arc<Stored>,
+        subscriptions: Arc<RwLock<Vec<Subscription>>>,
+        max_entries: Option<usize>,
+        udp_wrapper: UdpWrapper,
+        udp_socket: UdpSocket,
+    }
 
-            let mut events = Events::with_capacity(10);
+    /// Where the node keeps its entries: in one shard on the node's own thread,
+    /// or in one shard per worker thread, which holds the hashes that
+    /// `hash % workers` picks it for. Jobs given for the same hash run in the
+    /// order they were given.
+    enum Storage {
+        Inline(Box<Shard>),
+        Workers(Vec<(Sender<Job>, JoinHandle<()>)>),
+    }
 
-            poll.poll(&mut events, Some(Duration::from_secs(5))).ok();
+    impl Storage {
+        /// Creates `workers` shards on threads of their own, or a single shard
+        /// if it is 0. Every shard answers from its own clone of `socket`.
+        fn new(
+            config: &Config,
+            socket: &std::net::UdpSocket,
+            stored: &Arc<Stored>,
+            subscriptions: &Arc<RwLock<Vec<Subscription>>>,
+        ) -> Self {
+            let shard = || Shard {
+                values: Vec::new(),
+                index: config.index.then(Index::default),
+                kv: HashMap::new(),
+                next_expiry: None,
+                counted: (0, 0),
+                stored: stored.clone(),
+                subscriptions: subscriptions.clone(),
+                max_entries: config.max_entries,
+                udp_wrapper: UdpWrapper::new(),
+                udp_socket: UdpSocket::from_std(socket.try_clone().unwrap()),
+            };
+            if config.workers == 0 {
+                return Storage::Inline(Box::new(shard()));
+            }
 
-            for event in events.iter() {
-                match event.token() {
-                    UDP => {
-                        self.udp_wrapper.try_read(&mut self.udp_socket);
-                        while let Some((pdu, sender)) = self.udp_wrapper.next_pdu() {
-                            self.handle_pdu(pdu, Source::UDP(sender));
-                        }
-                    }
-                    SUCCESSOR => {
-                        if let Some(suc) = &mut self.successor {
-                            if self.successor_wrapper.try_read(suc) {
-                                println!("    Successor disconnected, removing..");
-                                self.successor = None;
-                            }
-                            while let Some((pdu, sender)) = self.successor_wrapper.next_pdu() {
-                                self.handle_pdu(pdu, Source::Successor(sender));
-                            }
-                        }
-                    }
-                    PREDECESSOR => {
-                        if let Some(pred) = &mut self.predecessor {
-                            if self.predecessor_wrapper.try_read(pred) {
-                                println!("    Predecessor disconnected, removing..");
+            let workers = (0..config.workers)
+                .map(|i| {
+                    let (sender, jobs) = mpsc::channel::<Job>();
+                    let mut shard = shard();
+                    let handle = thread::Builder::new()
+                        .name(format!("storage-{}", i))
+                        .spawn(move || {
+                            for job in jobs {
+                                shard.run(job);
                             }
+                        })
+                        .expect("Failed to start storage worker");
+                    (sender, handle)
+                })
+                .collect();
+            Storage::Workers(workers)
+        }
 
-                            while let Some((pdu, sender)) = self.predecessor_wrapper.next_pdu() {
-                                self.handle_pdu(pdu, Source::Predecessor(sender));
-                            }
-                        }
-                    }
-                    _ => panic!("What token is this?"),
+        /// Runs `job` on the shard holding `hash`, without waiting for it.
+        fn run(&mut self, hash: u64, job: impl FnOnce(&mut Shard) + Send + 'static) {
+            match self {
+                Storage::Inline(shard) => shard.run(job),
+                Storage::Workers(workers) => {
+                    let (sender, _) = &workers[(hash % workers.len() as u64) as usize];
+                    send_job(sender, job);
                 }
             }
+        }
 
-            poll.registry().deregister(&mut self.udp_socket).unwrap();
-            if let Some(s) = self.successor.as_mut() {
-                poll.registry().deregister(s).unwrap();
+        /// Runs `f` on every shard and waits for the results, which come in
+        /// no particular order.
+        fn gather<T: Send + 'static>(
+            &mut self,
+            f: impl Fn(&mut Shard) -> T + Clone + Send + 'static,
+        ) -> Vec<T> {
+            match self {
+                Storage::Inline(shard) => {
+                    let mut result = None;
+                    shard.run(|s| result = Some(f(s)));
+                    result.into_iter().collect()
+                }
+                Storage::Workers(workers) => {
+                    let (sender, results) = mpsc::channel();
+                    for (worker, _) in workers.iter() {
+                        let (f, sender) = (f.clone(), sender.clone());
+                        send_job(worker, move |s| sender.send(f(s)).unwrap());
+                    }
+                    drop(sender);
+                    results.iter().collect()
+                }
             }
+        }
 
-            if let Some(p) = self.predecessor.as_mut() {
-                poll.registry().deregister(p).unwrap();
+        /// Removes expired entries from every shard, without waiting for it.
+        fn expire(&mut self) {
+            match self {
+                Storage::Inline(shard) => shard.run(|_| {}),
+                Storage::Workers(workers) => {
+                    for (worker, _) in workers.iter() {
+                        send_job(worker, |_| {});
+                    }
+                }
             }
+        }
+    }
 
-            if self.should_close.load(Ordering::SeqCst) {
-                println!("    Close requested!");
-                self.state = Q10;
+    impl Drop for Storage {
+        /// Lets the workers finish the jobs they were given.
+        fn drop(&mut self) {
+            if let Storage::Workers(workers) = self {
+                for (sender, handle) in workers.drain(..) {
+                    drop(sender);
+                    handle.join().ok();
+                }
             }
         }
+    }
 
-        fn q7(&mut self) {
-            println!("[Q7]");
-            let next_node = match self.last_pdu.take() {
-                Some(PDU::NetGetNodeResponse(pdu)) => pdu,
-                _ => panic!("Invalid state change, last_pdu is not NetGetNodeResponse"),
-            };
+    /// Gives `job` to a worker, in the span it was given in.
+    fn send_job(worker: &Sender<Job>, job: impl FnOnce(&mut Shard) + Send + 'static) {
+        let span = Span::current();
+        worker
+            .send(Box::new(move |s| span.in_scope(|| job(s))))
+            .expect("Storage worker stopped");
+    }
 
-            let remote = next_node.get_addr();
-            println!(
-                "    I am not the first node, sending NET_JOIN to {:?}",
-                remote
-            );
+    impl Shard {
+        /// Runs `job` with expired entries removed first, and updates the totals
+        /// in `Stored` after.
+        fn run(&mut self, job: impl FnOnce(&mut Shard)) {
+            self.expire_entries();
+            job(self);
+
+            let (entries, kv) = (self.values.len(), self.kv.len());
+            let (old_entries, old_kv) = std::mem::replace(&mut self.counted, (entries, kv));
+            for (total, old, new) in [
+                (&self.stored.entries, old_entries, entries),
+                (&self.stored.kv, old_kv, kv),
+            ] {
+                if new > old {
+                    total.fetch_add(new - old, Ordering::Relaxed);
+                } else {
+                    total.fetch_sub(old - new, Ordering::Relaxed);
+                }
+            }
+        }
 
-            let local = self.get_listen_addr();
-            let net_join = NetJoinPdu::new(
-                self.own_address.unwrap().into(),
-                local.port(),
-                0,
-                0,
-                0,
-            );
+        fn position(&self, ssn: &str) -> Option<usize> {
+            self.values.iter().position(|x| x.ssn == ssn)
+        }
 
-            self.udp_wrapper
-                .send(&mut self.udp_socket, net_join.into(), remote.into());
+        fn insert(&mut self, e: Entry, request: Request) {
+            let existing = self.position(&e.ssn);
 
-            self.accept_predecessor();
+            // Entries moved from a neighbour carry a version and are always kept
+            if existing.is_none() && !request.moved && self.is_full() {
+                self.reply_storage_full(&request);
+                return;
+            }
 
-            self.state = Q8;
+            // An entry moved from a neighbour has not changed
+            match existing {
+                Some(i) if e.supersedes(&self.values[i]) => {
+                    debug!("Replacing {:?} with {:?}", self.values[i], e);
+                    if !request.moved {
+                        self.notify(&e, false);
+                    }
+                    self.store_entry(Some(i), e);
+                }
+                Some(i) => {
+                    debug!("Keeping {:?}, it is newer than {:?}", self.values[i], e);
+                }
+                None => {
+                    debug!("Inserting ssn {:?}", e);
+                    if !request.moved {
+                        self.notify(&e, false);
+                    }
+                    self.store_entry(None, e);
+                }
+            }
         }
 
-        fn q8(&mut self) {
-            println!("[Q8]");
-            let (pdu, sender) = match self.await_pdu(false) {
-                (PDU::NetJoinResponse(pdu), sender) => (pdu, sender),
-                _ => panic!("Expected NET_JOIN_RESPONSE, got something else :("),
+        fn cond_insert(&mut self, e: Entry, expected_version: u64, request: Request) {
+            // The outcome can't be told to anyone, so nothing is changed
+            let addr = match request.origin {
+                Some(addr) => addr,
+                None => {
+                    warn!("Conditional insert of {} has no sender, dropping it", e.ssn);
+                    return;
+                }
             };
+            let existing = self.position(&e.ssn);
+            let current = existing.map_or(0, |i| self.values[i].version);
+            if current != expected_version {
+                debug!(
+                    "Not inserting ssn {}, version is {} and not {}",
+                    e.ssn, current, expected_version
+                );
+                let response = ValCondResponsePdu::new(request.request_type, false, e.ssn, current);
+                self.udp_wrapper
+                    .send(&mut self.udp_socket, response.into(), addr);
+                return;
+            }
 
-            self.hash_range = (pdu.range_start, pdu.range_end);
-            println!(
-                "    Got NET_JOIN_RESPONSE from {:?}, my range is {:?}",
-                sender, self.hash_range
-            );
-            println!("    Connecting to successor {:?}", pdu.get_next_addr());
-
-            let addr = pdu.get_next_addr();
-            let successor = self.connect_to_successor(addr);
-            self.successor_listen = Some(addr);
-
-            self.successor = Some(successor);
+            if existing.is_none() && self.is_full() {
+                self.reply_storage_full(&request);
+                return;
+            }
 
-            self.state = Q6;
+            let response =
+                ValCondResponsePdu::new(request.request_type, true, e.ssn.clone(), e.version);
+            debug!("Conditionally inserting ssn {:?}", e);
+            self.notify(&e, false);
+            self.store_entry(existing, e);
+            self.udp_wrapper
+                .send(&mut self.udp_socket, response.into(), addr);
         }
 
-        fn q9(&mut self) {
-            println!("[Q9]");
-            match self.last_pdu.take().unwrap() {
-                PDU::ValInsert(p) => {
-                    self.handle_val_insert(p);
+        fn cond_remove(&mut self, pdu: ValCondRemovePdu) {
+            let existing = self.position(&pdu.ssn);
+            let current = existing.map_or(0, |i| self.values[i].version);
+            let response = match existing {
+                Some(i) if current == pdu.expected_version => {
+                    debug!("Conditionally removing ssn {}", pdu.ssn);
+                    let e = self.remove_entry(i);
+                    self.notify(&e, true);
+                    ValCondResponsePdu::new(pdu.pdu_type, true, pdu.ssn.clone(), 0)
                 }
-                PDU::ValRemove(p) => {
-                    self.handle_val_remove(p);
+                _ => {
+                    debug!(
+                        "Not removing ssn {}, version is {} and not {}",
+                        pdu.ssn, current, pdu.expected_version
+                    );
+                    ValCondResponsePdu::new(pdu.pdu_type, false, pdu.ssn.clone(), current)
                 }
-                PDU::ValLookup(p) => {
-                    self.handle_val_lookup(p);
+            };
+            self.udp_wrapper
+                .send(&mut self.udp_socket, response.into(), pdu.get_sender_addr());
+        }
+
+        fn lookup(&mut self, pdu: ValLookupPdu) {
+            let response = match self.position(&pdu.ssn) {
+                Some(i) => {
+                    let entry = &self.values[i];
+                    debug!("Value found (ssn: {}).", entry.ssn);
+                    ValLookupResponsePdu::new(
+                        pdu.pdu_type,
+                        entry.ssn.clone(),
+                        entry.name.clone(),
+                        entry.email.clone(),
+                        entry.version,
+                    )
                 }
-                _ => panic!("Invalid PDU for state Q9"),
+                None => {
+                    debug!("Value does not exist, responding with empty pdu");
+                    ValLookupResponsePdu::new(
+                        pdu.pdu_type,
+                        "000000000000".into(),
+                        String::new(),
+                        String::new(),
+                        0,
+                    )
+                }
+            };
+            self.udp_wrapper
+                .send(&mut self.udp_socket, response.into(), pdu.get_sender_addr());
+        }
+
+        fn remove(&mut self, ssn: &str) {
+            debug!("Removing ssn {}", ssn);
+            if let Some(i) = self.position(ssn) {
+                let e = self.remove_entry(i);
+                self.notify(&e, true);
             }
-            self.state = Q6;
         }
 
-        fn q10(&mut self) {
-            println!("[Q10]");
-            if self.successor.is_none() {
-                println!("    I am the last node, bye!");
-                self.running = false;
+        fn kv_put(&mut self, key: Vec<u8>, e: KvEntry, request: Request) {
+            // Values moved from a neighbour carry a version and are always kept
+            let exists = self.kv.contains_key(&key);
+            if !exists && !request.moved && self.is_full() {
+                self.reply_storage_full(&request);
                 return;
             }
 
-            self.state = Q11;
+            let name = String::from_utf8_lossy(&key).into_owned();
+            match self.kv.get(&key) {
+                Some(old) if !e.supersedes(old) => {
+                    debug!("Keeping the newer value of key {:?}", name);
+                }
+                _ => {
+                    debug!("Storing {} bytes under key {:?}", e.value.len(), name);
+                    self.kv.insert(key, e);
+                }
+            }
         }
 
-        fn q11(&mut self) {
-            println!("[Q11]");
+        fn kv_delete(&mut self, key: &[u8]) {
+            debug!("Deleting key {:?}", String::from_utf8_lossy(key));
+            self.kv.remove(key);
+        }
 
-            let (min, max) = self.hash_range;
+        fn kv_get(&mut self, pdu: ValKvGetPdu) {
+            let value = self.kv.get(&pdu.key).map(|e| (e.value.clone(), e.version));
+            let key = String::from_utf8_lossy(&pdu.key);
+            debug!("Key {:?} found: {}", key, value.is_some());
+            let addr = pdu.get_sender_addr();
+            let response = ValKvGetResponsePdu::new(pdu.key, value);
+            self.udp_wrapper
+                .send(&mut self.udp_socket, response.into(), addr);
+        }
 
-            let successor = match self.successor.as_mut() {
-                Some(s) => s,
-                _ => panic!("Missing successor socket >("),
-            };
+        /// The first `limit` entries in `(start, end)` after `after`, in the
+        /// order of their hashes and ssns, and one more if there are more.
+        fn scan(
+            &self,
+            (start, end): (u64, u64),
+            after: Option<&(u64, String)>,
+            limit: usize,
+        ) -> Vec<(u64, ScanEntry)> {
+            let mut matching: Vec<&Entry> = self
+                .values
+                .iter()
+                .filter(|e| start <= e.hash() && e.hash() <= end)
+                .filter(|e| after.is_none_or(|(h, s)| (e.hash(), &e.ssn) > (*h, s)))
+                .collect();
+            matching.sort_by(|a, b| (a.hash(), &a.ssn).cmp(&(b.hash(), &b.ssn)));
+            matching
+                .into_iter()
+                .take(limit + 1)
+                .map(|e| (e.hash(), e.to_scan_entry()))
+                .collect()
+        }
 
-            let predecessor = match self.predecessor.as_mut() {
-                Some(s) => s,
-                _ => panic!("Missing predecessor socket >("),
+        /// The entries in `(start, end)` whose `field` is `value`, which must
+        /// be lowercase.
+        fn query(&self, field: u8, value: &str, (start, end): (u64, u64)) -> Vec<ScanEntry> {
+            let matching: Vec<&Entry> = match &self.index {
+                Some(index) => index
+                    .get(field, value)
+                    .filter_map(|ssn| self.values.iter().find(|e| e.ssn == *ssn))
+                    .collect(),
+                None => self
+                    .values
+                    .iter()
+                    .filter(|e| e.field(field).is_some_and(|v| v.to_lowercase() == value))
+                    .collect(),
             };
+            matching
+                .into_iter()
+                .filter(|e| start <= e.hash() && e.hash() <= end)
+                .map(Entry::to_scan_entry)
+                .collect()
+        }
 
-            let new_range = NetNewRangePdu::new(min, max);
+        /// Hashes of the entries and key/value pairs in `(min, max)`.
+        fn hashes(&self, (min, max): (u64, u64)) -> Vec<u64> {
+            let keys = self.kv.values().map(|e| e.hash);
+            self.values
+                .iter()
+                .map(Entry::hash)
+                .chain(keys)
+                .filter(|h| (min..=max).contains(h))
+                .collect()
+        }
+
+        /// Removes and returns the entries and key/value pairs in `(start, end)`.
+        fn take_range(
+            &mut self,
+            (start, end): (u64, u64),
+        ) -> (Vec<Entry>, Vec<(Vec<u8>, KvEntry)>) {
+            let moves = |hash| start <= hash && hash <= end;
+            let entries = self.take_entries(|e| moves(e.hash()));
+            let (kv, kept) = std::mem::take(&mut self.kv)
+                .into_iter()
+                .partition(|(_, e)| moves(e.hash));
+            self.kv = kept;
+            (entries, kv.into_iter().collect())
+        }
+
+        fn expire_entries(&mut self) {
+            let now = Instant::now();
+            if self.next_expiry.is_none_or(|t| t > now) {
+                return;
+            }
+            let expired = self.take_entries(|e| e.is_expired(now));
+            self.next_expiry = self.values.iter().filter_map(|e| e.expires).min();
+
+            for e in &expired {
+                debug!("Entry expired: {:?}", e);
+                self.notify(e, true);
+            }
+            if !expired.is_empty() {
+                metrics::EXPIRED.add(&[], expired.len() as u64);
+            }
+        }
+
+        fn store_entry(&mut self, existing: Option<usize>, e: Entry) {
+            if let Some(t) = e.expires {
+                self.next_expiry = Some(self.next_expiry.map_or(t, |next| next.min(t)));
+            }
+            if let Some(index) = &mut self.index {
+                if let Some(i) = existing {
+                    index.remove(&self.values[i]);
+                }
+                index.add(&e);
+            }
+            match existing {
+                Some(i) => self.values[i] = e,
+                None => self.values.push(e),
+            }
+        }
+
+        fn remove_entry(&mut self, i: usize) -> Entry {
+            let e = self.values.remove(i);
+            if let Some(index) = &mut self.index {
+                index.remove(&e);
+            }
+            e
+        }
+
+        /// Removes and returns the entries for which `f` is true.
+        fn take_entries(&mut self, f: impl Fn(&Entry) -> bool) -> Vec<Entry> {
+            let (taken, kept): (Vec<Entry>, Vec<Entry>) =
+                std::mem::take(&mut self.values).into_iter().partition(f);
+            self.values = kept;
+            if let Some(index) = &mut self.index {
+                for e in &taken {
+                    index.remove(e);
+                }
+            }
+            taken
+        }
+
+        /// Sends a `ValNotifyPdu` about `e` to every subscriber it matches.
+        fn notify(&mut self, e: &Entry, removed: bool) {
+            let now = Instant::now();
+            let mut subscribers: Vec<SocketAddr> = self
+                .subscriptions
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|s| s.expires > now && s.matches(e))
+                .map(|s| s.addr)
+                .collect();
+            subscribers.sort();
+            subscribers.dedup();
+
+            for addr in subscribers {
+                debug!("Notifying {} that {} changed", addr, e.ssn);
+                let notify = ValNotifyPdu::new(
+                    removed,
+                    e.ssn.clone(),
+                    e.version,
+                    e.name.clone(),
+                    e.email.clone(),
+                );
+                self.udp_wrapper
+                    .send(&mut self.udp_socket, notify.into(), addr);
+                metrics::NOTIFICATIONS_SENT.inc(&[]);
+            }
+        }
+
+        fn is_full(&self) -> bool {
+            match self.max_entries {
+                Some(max) => self.stored.entries() + self.stored.kv() >= max,
+                None => false,
+            }
+        }
+
+        fn reply_storage_full(&mut self, request: &Request) {
+            let entries = self.stored.entries();
+            warn!(
+                "Storage is full ({} entries), rejecting PDU type {}",
+                entries, request.request_type
+            );
+            let message = format!("Node {} stores {} entries", request.node, entries);
+            match request.origin {
+                Some(addr) => send_error(
+                    &self.udp_wrapper,
+                    &mut self.udp_socket,
+                    addr,
+                    ERROR_STORAGE_FULL,
+                    request.request_type,
+                    message,
+                ),
+                None => warn!("Originator is unknown, no error is sent"),
+            }
+        }
+    }
+
+    /// Position of an ssn on the ring of `hasher`.
+    fn hash_ssn(hasher: &dyn KeyHasher, ssn: &str) -> u64 {
+        hash_key(hasher, ssn.as_bytes())
+    }
+
+    /// Position of a key on the ring of `hasher`, ssns and key/value keys
+    /// alike.
+    fn hash_key(hasher: &dyn KeyHasher, key: &[u8]) -> u64 {
+        hasher.hash(key)
+    }
+
+    /// The part of its load a node carrying `own` moves to a neighbour
+    /// carrying `load`, which evens the two out. `own` is more than `load`.
+    fn rebalance_fraction(own: u64, load: u64) -> f64 {
+        (own - load) as f64 / (2.0 * own as f64)
+    }
+
+    /// Sends an ERROR about a request of type `request_type` to `addr`.
+    fn send_error(
+        wrapper: &UdpWrapper,
+        socket: &mut UdpSocket,
+        addr: SocketAddr,
+        code: u8,
+        request_type: u8,
+        message: String,
+    ) {
+        debug!("Sending ERROR (code {}) to {}: {}", code, addr, message);
+        metrics::ERRORS_SENT.inc(&[&code.to_string()]);
+        let error = ErrorPdu::new(code, request_type, message);
+        wrapper.send(socket, error.into(), addr);
+    }
+
+    #[test]
+    fn test_hash() {
+        let djb2 = ou2::hash::by_name("djb2").unwrap();
+        assert_eq!(hash_ssn(djb2.as_ref(), "aaaaabbbbbcc"), 26);
+    }
+
+    #[test]
+    fn test_hash_key() {
+        let djb2 = ou2::hash::by_name("djb2").unwrap();
+        assert_eq!(hash_key(djb2.as_ref(), b"aaaaabbbbbcc"), 26);
+        assert_eq!(
+            hash_key(djb2.as_ref(), b"197223058909"),
+            hash_ssn(djb2.as_ref(), "197223058909")
+        );
+    }
+
+    #[test]
+    fn test_rebalance_fraction() {
+        assert_eq!(rebalance_fraction(10, 2), 0.4);
+        // A node owning most of the sip64 ring
+        let span = ou2::hash::by_name("sip64").unwrap().max_hash();
+        let fraction = rebalance_fraction(span - 10, 1 << 40);
+        assert!(0.49 < fraction && fraction <= 0.5, "{}", fraction);
+    }
+
+    #[test]
+    fn test_clock_is_monotonic() {
+        let mut clock = HybridClock::default();
+        let a = clock.now();
+        clock.observe(a + 1000);
+        let b = clock.now();
+        assert!(b > a + 1000);
+        assert!(clock.now() > b);
+    }
+
+    #[test]
+    fn test_request_rate() {
+        let mut rate = RequestRate::default();
+        let start = Instant::now();
+        assert_eq!(rate.per_minute(start), 0);
+        for _ in 0..100 {
+            rate.record(start);
+        }
+        assert_eq!(rate.per_minute(start), 69);
+        let later = start + RequestRate::HALF_LIFE;
+        assert_eq!(rate.per_minute(later), 34);
+    }
+
+    #[test]
+    fn test_index() {
+        let mut index = Index::default();
+        let a = Entry::new(0, "1".into(), "Ann".into(), "Ann@x.se".into(), 1);
+        let b = Entry::new(0, "2".into(), "ann".into(), "b@x.se".into(), 1);
+        index.add(&a);
+        index.add(&b);
+        let names: Vec<&String> = index.get(QUERY_FIELD_NAME, "ann").collect();
+        assert_eq!(names, ["1", "2"]);
+        assert_eq!(index.get(QUERY_FIELD_EMAIL, "ann@x.se").count(), 1);
+
+        index.remove(&a);
+        assert_eq!(index.get(QUERY_FIELD_EMAIL, "ann@x.se").count(), 0);
+        assert!(!index.by_email.contains_key("ann@x.se"));
+        assert_eq!(index.get(QUERY_FIELD_NAME, "ann").count(), 1);
+    }
+
+    /// An empty shard with `subscriptions`, and a socket for what it sends.
+    fn test_shard(subscriptions: Vec<Subscription>) -> (Shard, std::net::UdpSocket) {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let shard = Shard {
+            values: Vec::new(),
+            index: None,
+            kv: HashMap::new(),
+            next_expiry: None,
+            counted: (0, 0),
+            stored: Arc::new(Stored::default()),
+            subscriptions: Arc::new(RwLock::new(subscriptions)),
+            max_entries: None,
+            udp_wrapper: UdpWrapper::new(),
+            udp_socket: UdpSocket::from_std(socket),
+        };
+        (shard, receiver)
+    }
+
+    /// A request from `receiver`.
+    fn test_request(receiver: &std::net::UdpSocket, request_type: u8, moved: bool) -> Request {
+        Request {
+            request_type,
+            origin: Some(receiver.local_addr().unwrap()),
+            node: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+            moved,
+        }
+    }
+
+    /// The next PDU `receiver` gets, which must be a `T`.
+    fn receive<T: ParsePdu>(receiver: &std::net::UdpSocket) -> T {
+        let mut buffer = [0; 1024];
+        let (size, _) = receiver.recv_from(&mut buffer).expect("Nothing was sent");
+        T::try_parse(&buffer[..size]).unwrap().0
+    }
+
+    #[test]
+    fn test_cond_insert() {
+        let (mut shard, receiver) = test_shard(Vec::new());
+        let ssn = "198001010000";
+        let entry = |version| Entry::new(0, ssn.into(), "Ann".into(), "a@b.c".into(), version);
+        let request = || test_request(&receiver, VAL_COND_INSERT_ID, false);
+
+        // A missing entry only matches version 0
+        shard.cond_insert(entry(5), 3, request());
+        let response: ValCondResponsePdu = receive(&receiver);
+        assert!(!response.success);
+        assert_eq!(response.version, 0);
+        assert_eq!(response.request_type, VAL_COND_INSERT_ID);
+        assert!(shard.values.is_empty());
+
+        // Without a sender to tell, nothing is inserted
+        let mut unknown = request();
+        unknown.origin = None;
+        shard.cond_insert(entry(5), 0, unknown);
+        assert!(shard.values.is_empty());
+
+        shard.cond_insert(entry(5), 0, request());
+        let response: ValCondResponsePdu = receive(&receiver);
+        assert!(response.success);
+        assert_eq!(response.version, 5);
+        assert_eq!(response.request_type, VAL_COND_INSERT_ID);
+
+        // A mismatch reports the stored version and keeps the entry
+        shard.cond_insert(entry(6), 0, request());
+        let response: ValCondResponsePdu = receive(&receiver);
+        assert!(!response.success);
+        assert_eq!(response.version, 5);
+        assert_eq!(shard.values[0].version, 5);
+
+        shard.cond_insert(entry(6), 5, request());
+        let response: ValCondResponsePdu = receive(&receiver);
+        assert!(response.success);
+        assert_eq!((response.ssn.as_str(), response.version), (ssn, 6));
+        assert_eq!(shard.values.len(), 1);
+        assert_eq!(shard.values[0].version, 6);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let (shard, receiver) = test_shard(Vec::new());
+        let stored = shard.stored.clone();
+        let mut storage = Storage::Inline(Box::new(shard));
+        let now = Instant::now();
+        for (i, expires) in [
+            now + Duration::from_secs(60),
+            now + Duration::from_millis(50),
+            now,
+        ]
+        .iter()
+        .enumerate()
+        {
+            let ssn = format!("1980010100{:02}", i);
+            let mut e = Entry::new(0, ssn, "Ann".into(), "a@b.c".into(), 1);
+            e.expires = Some(*expires);
+            storage.run(0, move |s| s.store_entry(None, e));
+        }
+        assert_eq!(stored.entries(), 3);
+
+        // What the main loop does between polls
+        storage.expire();
+        assert_eq!(stored.entries(), 2);
+        let next_expiry = storage.gather(|s| s.next_expiry);
+        assert_eq!(next_expiry, [Some(now + Duration::from_millis(50))]);
+
+        // A lookup does not find an entry that expired since
+        thread::sleep(Duration::from_millis(100));
+        let port = receiver.local_addr().unwrap().port();
+        let lookup = move |ssn: &str| {
+            let pdu = ValLookupPdu::new(ssn.into(), Ipv4Addr::LOCALHOST.into(), port);
+            move |s: &mut Shard| s.lookup(pdu)
+        };
+        storage.run(0, lookup("198001010001"));
+        let response: ValLookupResponsePdu = receive(&receiver);
+        assert_eq!(
+            (response.ssn.as_str(), response.name.as_str()),
+            ("000000000000", "")
+        );
+        storage.run(0, lookup("198001010000"));
+        let response: ValLookupResponsePdu = receive(&receiver);
+        assert_eq!(
+            (response.ssn.as_str(), response.name.as_str()),
+            ("198001010000", "Ann")
+        );
+        assert_eq!(stored.entries(), 1);
+        let next_expiry = storage.gather(|s| s.next_expiry);
+        assert_eq!(next_expiry, [Some(now + Duration::from_secs(60))]);
+    }
+
+    #[test]
+    fn test_notify() {
+        let (mut shard, receiver) = test_shard(Vec::new());
+        let ssn = "198001010000";
+        shard.subscriptions.write().unwrap().push(Subscription {
+            ssn: Some(ssn.into()),
+            range: (0, 0),
+            addr: receiver.local_addr().unwrap(),
+            expires: Instant::now() + Duration::from_secs(60),
+        });
+        let entry =
+            |ssn: &str, version| Entry::new(0, ssn.into(), "Ann".into(), "a@b.c".into(), version);
+
+        shard.insert(entry(ssn, 1), test_request(&receiver, VAL_INSERT_ID, false));
+        let notify: ValNotifyPdu = receive(&receiver);
+        assert!(!notify.removed);
+        assert_eq!((notify.ssn.as_str(), notify.version), (ssn, 1));
+
+        // Moved entries have not changed, and other ssns are not subscribed to
+        shard.insert(entry(ssn, 2), test_request(&receiver, VAL_INSERT_ID, true));
+        shard.insert(
+            entry("198001010001", 1),
+            test_request(&receiver, VAL_INSERT_ID, false),
+        );
+        let mut e = entry(ssn, 2);
+        e.expires = Some(Instant::now());
+        shard.store_entry(Some(0), e);
+        shard.run(|_| {});
+        let notify: ValNotifyPdu = receive(&receiver);
+        assert!(notify.removed);
+        assert_eq!((notify.ssn.as_str(), notify.version), (ssn, 2));
+    }
+
+    /// `ranges` without the hashes in `(start, end)`.
+    fn subtract_range(ranges: &[(u64, u64)], (start, end): (u64, u64)) -> Vec<(u64, u64)> {
+        let mut left = Vec::with_capacity(ranges.len() + 1);
+        for &(min, max) in ranges {
+            if max < start || end < min {
+                left.push((min, max));
+                continue;
+            }
+            if min < start {
+                left.push((min, start - 1));
+            }
+            if end < max {
+                left.push((end + 1, max));
+            }
+        }
+        left
+    }
+
+    #[test]
+    fn test_subtract_range() {
+        let ranges = [(0, 9), (20, 29)];
+        assert_eq!(subtract_range(&ranges, (25, 29)), [(0, 9), (20, 24)]);
+        assert_eq!(subtract_range(&ranges, (0, 3)), [(4, 9), (20, 29)]);
+        assert_eq!(subtract_range(&ranges, (5, 24)), [(0, 4), (25, 29)]);
+        assert_eq!(subtract_range(&ranges, (12, 14)), ranges);
+        assert_eq!(
+            subtract_range(&[(0, u64::MAX)], (3, 4)),
+            [(0, 2), (5, u64::MAX)]
+        );
+    }
+
+    /// States
+    impl Node {
+        fn q1(&mut self) {
+            debug!("[Q1]");
+            let lookup = StunLookupPdu::new();
+            info!(
+                "Node started, sending STUN_LOOKUP to tracker: {:?}",
+                self.tracker_addr
+            );
+            self.udp_wrapper
+                .send(&mut self.udp_socket, lookup.into(), self.tracker_addr);
+            self.state = Q2;
+        }
+
+        fn q2(&mut self) {
+            debug!("[Q2]");
+            let stun = |pdu: &PDU| matches!(pdu, PDU::StunResponse(_));
+            if let Some((PDU::StunResponse(pdu), _)) = self.udp_wrapper.take_pdu(stun) {
+                self.own_address = Some(pdu.address.into());
+                info!(
+                    "Got STUN_RESPONSE, my address is: {:?}",
+                    self.own_address.unwrap()
+                );
+                self.request_node();
+            } else if self.close_requested() {
+                info!("Close requested before joining, bye!");
+                self.running = false;
+            } else {
+                self.poll_sockets(Duration::from_secs(5));
+            }
+        }
+
+        fn q3(&mut self) {
+            debug!("[Q3]");
+            let node = |pdu: &PDU| matches!(pdu, PDU::NetGetNodeResponse(_));
+            if let Some((PDU::NetGetNodeResponse(pdu), _)) = self.udp_wrapper.take_pdu(node) {
+                if pdu.address == 0 && pdu.port == 0 {
+                    info!("I am the first node to join the network");
+                    self.state = Q4;
+                } else {
+                    self.last_pdu = Some(pdu.into());
+                    self.state = Q7;
+                }
+            } else if self.close_requested() {
+                info!("Close requested before joining, bye!");
+                self.running = false;
+            } else {
+                self.poll_sockets(Duration::from_secs(5));
+            }
+        }
+
+        fn q4(&mut self) {
+            debug!("[Q4]");
+            let ring = u128::from(self.config.hasher.max_hash()) + 1;
+            let count = u128::from(self.config.vnodes.max(1)).min(ring);
+            let ranges = (0..count)
+                .map(|i| {
+                    let end = (i + 1) * ring / count - 1;
+                    ((i * ring / count) as u64, end as u64)
+                })
+                .collect();
+            self.set_ranges(ranges);
+            self.state = Q6;
+        }
+
+        fn q5(&mut self) {
+            debug!("[Q5]");
+
+            let next_node = match self.last_pdu.take() {
+                Some(PDU::NetJoin(pdu)) => pdu,
+                _ => panic!("Invalid state change, last_pdu is not NetGetNodeResponse"),
+            };
+
+            let addr = next_node.get_src_socket_addr();
+            let mut socket = self.connect_to_successor(addr);
+            let given = self.split_ranges();
+            info!("Other hash-ranges are {:?}", given);
+            info!("New hash-ranges are {:?}", self.ranges);
+
+            let local = self.get_listen_addr();
+            let (mins, maxs) = given[0];
+            let join_response =
+                NetJoinResponsePdu::new(self.own_address.unwrap().into(), local.port(), mins, maxs);
+            self.successor_wrapper
+                .send(&mut socket, join_response.into());
+            self.set_successor(Some(socket));
+            self.hand_over(&given);
+
+            let timeout = self.config.accept_timeout;
+            self.await_predecessor(Awaiting::Split { given }, timeout);
+
+            self.state = Q6;
+        }
+
+        fn q6(&mut self) {
+            trace!("[Q6] ({} entries stored)", self.stored.entries());
+            self.expire_subscriptions();
+            self.storage.expire();
+            metrics::ENTRIES.set(&[], self.stored.entries() as i64);
+            metrics::KV_ENTRIES.set(&[], self.stored.kv() as i64);
+            let requests = self.requests.per_minute(Instant::now());
+            metrics::REQUEST_RATE.set(&[], requests as i64);
+
+            // A joining node registers with the tracker once it has a range
+            if !self.ranges.is_empty() {
+                self.send_alive();
+            }
+            if let Some(every) = self.config.rebalance_every {
+                if self.last_offer.elapsed() >= every {
+                    self.offer_load();
+                }
+            }
+
+            if self.awaiting.is_some() {
+                // A predecessor that connected before the wait began raised no
+                // event of its own
+                self.accept_predecessor();
+            }
+            self.handle_queued_pdus();
+            if !matches!(self.state, Q6) {
+                return;
+            }
+
+            if self.drain_until.is_none() && self.close_requested() {
+                let timeout = self.config.drain_timeout;
+                info!("Close requested! Draining for up to {:?}", timeout);
+                self.drain_until = Some(Instant::now() + timeout);
+            }
+            if self.drain_until.is_some() {
+                // The joining nodes send NET_JOIN again and are placed elsewhere
+                for join in self.joins.drain(..) {
+                    let src = join.get_src_socket_addr();
+                    debug!("Leaving, dropping the NET_JOIN from {}", src);
+                }
+            }
+            // Joins are taken one at a time, a split still awaiting its
+            // predecessor would be undone if it timed out after another
+            if self.awaiting.is_none() {
+                if let Some(join) = self.joins.pop_front() {
+                    self.last_pdu = Some(join.into());
+                    self.state = Q12;
+                    return;
+                }
+            }
+
+            let mut timeout = Duration::from_secs(5);
+            if let Some((_, deadline)) = &self.awaiting {
+                timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+            }
+            if self.drain_until.is_some() {
+                timeout = timeout.min(DRAIN_QUIET);
+            }
+            let quiet = !self.poll_sockets(timeout);
+
+            let now = Instant::now();
+            // A join still under way when the drain ends is given up
+            let drained = matches!(self.drain_until, Some(until) if until <= now);
+            if matches!(&self.awaiting, Some((_, deadline)) if *deadline <= now || drained) {
+                let (awaiting, _) = self.awaiting.take().unwrap();
+                self.give_up_predecessor(awaiting);
+            }
+
+            if self.drain_until.is_some()
+                && self.awaiting.is_none()
+                && matches!(self.state, Q6)
+                && (quiet || drained)
+            {
+                self.state = Q10;
+            }
+        }
+
+        fn q7(&mut self) {
+            debug!("[Q7]");
+            let next_node = match self.last_pdu.take() {
+                Some(PDU::NetGetNodeResponse(pdu)) => pdu,
+                _ => panic!("Invalid state change, last_pdu is not NetGetNodeResponse"),
+            };
+
+            let remote = next_node.get_addr();
+            info!("I am not the first node, sending NET_JOIN to {:?}", remote);
+
+            let local = self.get_listen_addr();
+            let net_join = NetJoinPdu::new(
+                self.own_address.unwrap().into(),
+                local.port(),
+                0,
+                0,
+                0,
+            );
+
+            self.udp_wrapper
+                .send(&mut self.udp_socket, net_join.into(), remote);
+
+            // The NET_JOIN may be lost, or sent to a node that has left
+            let timeout = JOIN_TIMEOUT * 2u32.pow(self.join_attempts.min(4));
+            self.await_predecessor(Awaiting::Join, timeout.min(JOIN_TIMEOUT_MAX));
+
+            self.state = Q6;
+        }
+
+        fn q8(&mut self) {
+            debug!("[Q8]");
+            // The entries the node joined at hands over follow the response
+            let response = |pdu: &PDU| matches!(pdu, PDU::NetJoinResponse(_));
+            let (pdu, sender) = match self.predecessor_wrapper.take_pdu(response) {
+                Some((PDU::NetJoinResponse(pdu), sender)) => (pdu, sender),
+                _ => {
+                    self.poll_sockets(Duration::from_secs(5));
+                    return;
+                }
+            };
+
+            self.set_ranges(vec![(pdu.range_start, pdu.range_end)]);
+            info!(
+                "Got NET_JOIN_RESPONSE from {:?}, my range is {:?}",
+                sender, self.ranges
+            );
+            info!("Connecting to successor {:?}", pdu.get_next_addr());
+
+            let addr = pdu.get_next_addr();
+            let successor = self.connect_to_successor(addr);
+            self.successor_listen = Some(addr);
+
+            self.set_successor(Some(successor));
+
+            self.state = Q6;
+        }
+
+        fn q9(&mut self) {
+            debug!("[Q9]");
+            match self.last_pdu.take().unwrap() {
+                PDU::ValInsert(p) => {
+                    self.handle_val_insert(p);
+                }
+                PDU::ValRemove(p) => {
+                    self.handle_val_remove(p);
+                }
+                PDU::ValLookup(p) => {
+                    self.handle_val_lookup(p);
+                }
+                PDU::ValCondInsert(p) => {
+                    self.handle_val_cond_insert(p);
+                }
+                PDU::ValCondRemove(p) => {
+                    self.handle_val_cond_remove(p);
+                }
+                PDU::ValScan(p) => {
+                    self.handle_val_scan(p);
+                }
+                PDU::ValBatch(p) => {
+                    self.handle_val_batch(p);
+                }
+                PDU::ValSubscribe(p) => {
+                    self.handle_val_subscribe(p);
+                }
+                PDU::ValQuery(p) => {
+                    self.handle_val_query(p);
+                }
+                PDU::ValKvPut(p) => {
+                    self.handle_val_kv_put(p);
+                }
+                PDU::ValKvDelete(p) => {
+                    self.handle_val_kv_delete(p);
+                }
+                PDU::ValKvGet(p) => {
+                    self.handle_val_kv_get(p);
+                }
+                _ => panic!("Invalid PDU for state Q9"),
+            }
+            self.state = Q6;
+        }
+
+        fn q10(&mut self) {
+            debug!("[Q10]");
+            if self.successor.is_some() || self.predecessor.is_some() {
+                self.state = Q11;
+                return;
+            }
+
+            if self.owned_hashes() > u128::from(self.config.hasher.max_hash()) {
+                info!("I am the last node, bye!");
+            } else if self.ranges.is_empty() {
+                info!("Leaving before the join completed, bye!");
+            } else {
+                warn!("No neighbour to hand {:?} to, bye!", self.ranges);
+            }
+            self.running = false;
+        }
+
+        fn q11(&mut self) {
+            debug!("[Q11]");
+
+            let (first, rest) = match self.ranges.split_first() {
+                Some((&first, rest)) => (first, rest.to_vec()),
+                None => {
+                    self.state = Q18;
+                    return;
+                }
+            };
+
+            if let Some(to_successor) = self.leaving_to {
+                // Other PDUs from the neighbour stay queued, the node is leaving
+                let response = |pdu: &PDU| matches!(pdu, PDU::NetNewRangeResponse(_));
+                let (wrapper, gone) = if to_successor {
+                    (&mut self.successor_wrapper, self.successor.is_none())
+                } else {
+                    (&mut self.predecessor_wrapper, self.predecessor.is_none())
+                };
+                if wrapper.take_pdu(response).is_some() {
+                    self.state = Q18;
+                } else if gone {
+                    warn!("The neighbour disconnected before it took over my ranges");
+                    self.leaving_to = None;
+                    self.state = Q10;
+                } else {
+                    self.poll_sockets(Duration::from_secs(5));
+                }
+                return;
+            }
+
+            // The ranges go to the neighbour next to hash 0 when there are both,
+            // which keeps the ranges of a node in one piece without virtual ranges
+            let to_successor = match (&self.successor, &self.predecessor) {
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                _ => self.owns_hash(0),
+            };
+
+            // The other virtual ranges go first, so the neighbour has added
+            // them all when it answers the NET_NEW_RANGE
+            let mut pdus: Vec<PDU> = rest
+                .iter()
+                .map(|&(start, end)| NetAddRangePdu::new(start, end).into())
+                .collect();
+            pdus.push(NetNewRangePdu::new(first.0, first.1).into());
+
+            let (socket, wrapper, direction) = if to_successor {
+                (
+                    self.successor.as_mut().unwrap(),
+                    &self.successor_wrapper,
+                    "successor",
+                )
+            } else {
+                (
+                    self.predecessor.as_mut().unwrap(),
+                    &self.predecessor_wrapper,
+                    "predecessor",
+                )
+            };
+            info!("Sending NET_NEW_RANGE to {}", direction);
+            for pdu in pdus {
+                wrapper.send(socket, pdu);
+            }
+            socket.flush().unwrap();
+            self.leaving_to = Some(to_successor);
+        }
+
+        fn q12(&mut self) {
+            debug!("[Q12]");
+
+            if self.successor.is_none() {
+                info!("I am alone, moving to Q5");
+                self.state = Q5;
+                return;
+            }
+
+            let pdu = match self.last_pdu.take() {
+                Some(PDU::NetJoin(pdu)) => pdu,
+                _ => panic!("Invalid state change, last_pdu is not NetJoin"),
+            };
+
+            // A joining node that gave up on a NET_JOIN sends another, and the
+            // first may still get through
+            let own: SocketAddr = self.get_listen_addr().into();
+            let src = pdu.get_src_socket_addr();
+            if src == own || Some(src) == self.successor_listen {
+                info!("{} has already joined, dropping its NET_JOIN", src);
+                self.state = Q6;
+                return;
+            }
+
+            if own == pdu.get_max_socket_addr() {
+                // Another join may have split this node since the NET_JOIN
+                // passed, then a node with more may be further along the ring
+                let weight = self.join_weight();
+                if weight < pdu.max_span {
+                    info!(
+                        "My weight dropped from {} to {}, searching again",
+                        pdu.max_span, weight
+                    );
+                    let mut pdu = pdu;
+                    pdu.max_span = weight;
+                    self.forward_to_successor(pdu.into());
+                    self.state = Q6;
+                    return;
+                }
+                info!("I am the node with the maximum span! ({})", pdu.max_span);
+                self.last_pdu = Some(pdu.into());
+                self.state = Q13;
+                return;
+            }
+            self.last_pdu = Some(pdu.into());
+
+            self.state = Q14;
+        }
+
+        fn q13(&mut self) {
+            debug!("[Q13]");
+            let net_close = NetCloseConnectionPdu::new();
+            let successor_addr = self.get_successor_addr();
+            let socket = self.successor.as_mut().unwrap();
+
+            let pdu = match self.last_pdu.take() {
+                Some(PDU::NetJoin(pdu)) => pdu,
+                _ => panic!("Invalid state change, last_pdu is not NetJoin"),
+            };
+
+            info!("Sending NET_CLOSE_CONNECTION to successor");
+            self.successor_wrapper.send(socket, net_close.into());
+            socket.flush().unwrap();
+            socket.shutdown(Shutdown::Both).unwrap();
+            self.set_successor(None);
+            let addr = pdu.get_src_socket_addr();
+            let stream = self.connect_to_successor(addr);
+            self.successor_listen = Some(addr);
+
+            info!("Connected to new successor {:?}", stream.peer_addr());
+            self.set_successor(Some(stream));
+
+            self.last_pdu = None;
+
+            let given = self.split_ranges();
+            info!("Other hash-ranges are {:?}", given);
+            info!("New hash-ranges are {:?}", self.ranges);
+
+            let (mins, maxs) = given[0];
+            let join_response = NetJoinResponsePdu::new(
+                (*successor_addr.ip()).into(),
+                successor_addr.port(),
+                mins,
+                maxs,
+            );
+
+            let socket = self.successor.as_mut().unwrap();
+            debug!("Sending join response");
+            self.successor_wrapper.send(socket, join_response.into());
+
+            //Transfer all given ranges
+            self.hand_over(&given);
+
+            self.state = Q6;
+        }
+
+        fn q14(&mut self) {
+            debug!("[Q14]");
+
+            let mut pdu = match self.last_pdu.take() {
+                Some(PDU::NetJoin(pdu)) => pdu,
+                _ => panic!("Invalid state change, last_pdu is not NetJoin"),
+            };
+
+            // The first node to see the join takes it even with a weight of 0,
+            // so that it has somewhere to go. A node that handed off its
+            // ranges has nothing to split.
+            let weight = self.join_weight();
+            if !self.ranges.is_empty() && (weight > pdu.max_span || pdu.max_port == 0) {
+                debug!("Updating max fields");
+                pdu.max_span = weight;
+                let a = self.get_listen_addr();
+                pdu.max_address = (*a.ip()).into();
+                pdu.max_port = a.port();
+            }
+
+            self.forward_to_successor(pdu.into());
+
+            self.state = Q6;
+        }
+
+        fn q15(&mut self) {
+            debug!("[Q15]");
+
+            let new_range = match self.last_pdu.take() {
+                Some(PDU::NetNewRange(pdu)) => pdu,
+                _ => panic!("Invalid state change, last_pdu is not NetNewRange"),
+            };
+            debug!("Current ranges are: {:?}", self.ranges);
+
+            self.add_range((new_range.range_start, new_range.range_end));
+            let new_range_response = NetNewRangeResponsePdu::new();
+            if let Some(Source::Successor(_)) = self.range_sender.take() {
+                debug!("Sending NET_NEW_RANGE_RESPONSE to successor");
+                self.successor_wrapper.send(self.successor.as_mut().unwrap(), new_range_response.into());
+            } else {
+                debug!("Sending NET_NEW_RANGE_RESPONSE to predecessor");
+                self.predecessor_wrapper.send(self.predecessor.as_mut().unwrap(), new_range_response.into());
+            }
+            info!("New ranges are: {:?}", self.ranges);
+
+
+            self.state = Q6;
+        }
+
+        fn q16(&mut self) {
+            debug!("[Q16]");
+
+            let net_leaving = match self.last_pdu.take() {
+                Some(PDU::NetLeaving(pdu)) => pdu,
+                _ => panic!("Invalid state change, last_pdu is not NetLeaving"),
+            };
+
+            if net_leaving.new_address == u32::from(self.own_address.unwrap()) &&
+               net_leaving.new_port == self.get_listen_addr().port() {
+                info!("I am the last node.");
+                self.set_successor(None);
+                self.set_predecessor(None);
+
+            } else {
+                self.set_successor(None);
+
+                let successor = self.connect_to_successor(net_leaving.get_new_addr());
+                self.set_successor(Some(successor));
+            }
+
+
+            self.state = Q6;
+        }
+
+        fn q17(&mut self) {
+            debug!("[Q17]");
+            if let Some(s) = self.predecessor.as_mut() {
+                info!("Disconnecting from predecessor");
+                s.flush().unwrap();
+                s.shutdown(Shutdown::Both).unwrap();
+                self.set_predecessor(None);
+            }
+            if self.owned_hashes() > u128::from(self.config.hasher.max_hash()) {
+                info!("I am the last node");
+            } else {
+                info!("Awaiting new predecessor");
+                let timeout = self.config.accept_timeout;
+                self.await_predecessor(Awaiting::Repair, timeout);
+            }
+
+            self.state = Q6;
+        }
+
+        fn q18(&mut self) {
+            debug!("[Q18]");
+
+            if let Some(to_successor) = self.leaving_to.take() {
+                if to_successor {
+                    info!("Transferring all entries to successor");
+                } else {
+                    info!("Transferring all entries to predecessor");
+                }
+                for (min, max) in self.ranges.clone() {
+                    self.transfer(to_successor, min, max);
+                }
+            }
+
+            // The predecessor connects to the successor in place of this node.
+            // The successor was connected to at the address it listens on.
+            let to_connect = match &self.successor {
+                Some(s) => self.successor_listen.or_else(|| s.peer_addr().ok()),
+                None => None,
+            };
+
+            if let Some(successor) = self.successor.as_mut() {
+                let close = NetCloseConnectionPdu::new();
+                self.successor_wrapper.send(successor, close.into());
+                successor.flush().ok();
+                successor.shutdown(Shutdown::Both).ok();
+            }
+
+            match (self.predecessor.as_mut(), to_connect) {
+                (Some(predecessor), Some(SocketAddr::V4(addr))) => {
+                    let leaving = NetLeavingPdu::new((*addr.ip()).into(), addr.port());
+                    info!("Sending NET_LEAVING to predecessor");
+                    self.predecessor_wrapper.send(predecessor, leaving.into());
+                    predecessor.flush().ok();
+                    predecessor.shutdown(Shutdown::Both).ok();
+                }
+                (Some(predecessor), _) => {
+                    warn!("No successor for the predecessor to connect to");
+                    predecessor.shutdown(Shutdown::Both).ok();
+                }
+                (None, _) => warn!("No predecessor to send NET_LEAVING to"),
+            }
+
+            self.running = false;
+        }
+
+        fn handle_pdu(&mut self, pdu: PDU, sender: Source) {
+            match pdu {
+                PDU::NetJoin(p) => {
+                    let src = p.get_src_socket_addr();
+                    if self.joins.iter().any(|j| j.get_src_socket_addr() == src) {
+                        debug!("A NET_JOIN from {} is already queued, dropping it", src);
+                    } else {
+                        self.joins.push_back(p);
+                    }
+                }
+                PDU::NetCloseConnection(_) => match sender {
+                    Source::Predecessor(_) => {
+                        self.state = Q17;
+                    }
+                    _ => {
+                        warn!("Got NET_CLOSE from {:?}, ignoring it", sender);
+                    }
+                },
+                PDU::NetNewRange(p) => {
+                    self.last_pdu = Some(p.into());
+                    self.range_sender = Some(sender);
+                    self.state = Q15;
+                }
+                PDU::NetAddRange(p) => {
+                    self.add_range((p.range_start, p.range_end));
+                    info!(
+                        "Got NET_ADD_RANGE from {:?}, my ranges are {:?}",
+                        sender, self.ranges
+                    );
+                }
+                PDU::NetLeaving(p) => {
+                    self.last_pdu = Some(p.into());
+                    self.state = Q16;
+                }
+                PDU::NetRebalance(p) => match sender {
+                    Source::Udp(addr) => {
+                        info!("Rebalance requested by {}", addr);
+                        self.offer_load();
+                    }
+                    Source::Successor(_) => self.handle_rebalance(p.load, true),
+                    Source::Predecessor(_) => self.handle_rebalance(p.load, false),
+                },
+                PDU::NetControl(p) => match sender {
+                    Source::Udp(addr) => self.handle_control(p, addr),
+                    _ => warn!("Got NET_CONTROL from {:?}, ignoring it", sender),
+                },
+                PDU::ValInsert(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValRemove(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValLookup(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValCondInsert(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValCondRemove(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValScan(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValBatch(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValSubscribe(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValQuery(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValKvPut(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValKvDelete(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValKvGet(p) => {
+                    self.handle_value_pdu(p.into(), sender);
+                }
+                PDU::ValTrace(p) => {
+                    self.handle_val_trace(p, sender);
+                }
+                PDU::ValHopLimit(p) => {
+                    self.handle_val_hop_limit(p, sender);
+                }
+                PDU::ValVersion(p) => {
+                    self.handle_val_version(p, sender);
+                }
+                PDU::ValTtl(p) => {
+                    self.handle_val_ttl(p, sender);
+                }
+                x => {
+                    warn!(
+                        "Got PDU that node does not accept in the current state (Q6), was: {:?}",
+                        x
+                    );
+                    // Never answer an error with an error, two nodes would bounce them forever
+                    if let (Source::Udp(addr), false) = (sender, matches!(x, PDU::Error(_))) {
+                        let message = format!("{} is not accepted in the current state", x.name());
+                        self.send_error(addr, ERROR_INVALID_STATE, x.pdu_type(), message);
+                    }
+                }
+            }
+        }
+    }
 
-            let to_successor = min == 0;
-            if to_successor {
-                println!("    Sending NET_NEW_RANGE to successor");
-                self.successor_wrapper.send(successor, new_range.into());
-                successor.flush().unwrap();
-            } else {
-                println!("    Sending NET_NEW_RANGE to predecessor");
-                self.predecessor_wrapper.send(predecessor, new_range.into());
-                predecessor.flush().unwrap();
+    // Util functions
+    impl Node {
+        /// Gives away the upper half of every virtual range and returns those
+        /// halves. Ranges of a single hash cannot be split, if no range can be
+        /// every other range is given away instead.
+        fn split_ranges(&mut self) -> Vec<(u64, u64)> {
+            let mut kept = Vec::new();
+            let mut given = Vec::new();
+            for (min, max) in self.ranges.clone() {
+                if min < max {
+                    let mid = self.split_point((min, max));
+                    kept.push((min, mid));
+                    given.push((mid + 1, max));
+                } else {
+                    kept.push((min, max));
+                }
+            }
+            if given.is_empty() {
+                let (odd, even): (Vec<_>, Vec<_>) =
+                    kept.iter().enumerate().partition(|(i, _)| i % 2 == 1);
+                given = odd.into_iter().map(|(_, &r)| r).collect();
+                kept = even.into_iter().map(|(_, &r)| r).collect();
             }
+            self.set_ranges(kept);
+            given
+        }
 
+        /// Adds a range given by a neighbour, merged with adjacent ranges.
+        fn add_range(&mut self, range: (u64, u64)) {
+            let mut ranges = std::mem::take(&mut self.ranges);
+            ranges.push(range);
+            ranges.sort_unstable();
+            let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+            for (min, max) in ranges {
+                match merged.last_mut() {
+                    Some(last) if last.1 < min && min - last.1 == 1 => last.1 = max,
+                    _ => merged.push((min, max)),
+                }
+            }
+            self.set_ranges(merged);
+        }
 
-            let (_pdu, _sender) = match self.await_pdu(to_successor) {
-                (PDU::NetNewRangeResponse(pdu), sender) => (pdu, sender),
-                x => panic!("Expected NET_NEW_RANGE_RESPONSE, got {:?}", x),
-            };
-            self.state = Q18;
+        fn set_ranges(&mut self, ranges: Vec<(u64, u64)>) {
+            self.ranges = ranges;
+            let size = self.owned_hashes().min(i64::MAX as u128) as i64;
+            metrics::RANGE_SIZE.set(&[], size);
+            metrics::VIRTUAL_RANGES.set(&[], self.ranges.len() as i64);
+            self.span = info_span!(
+                parent: None,
+                "node",
+                udp = %self.udp_socket.local_addr().unwrap(),
+                listen = %self.get_listen_addr(),
+                ranges = ?self.ranges,
+            );
         }
 
-        fn q12(&mut self) {
-            println!("[Q12]");
+        /// Number of hashes this node is responsible for, over all its ranges.
+        fn owned_hashes(&self) -> u128 {
+            self.ranges
+                .iter()
+                .map(|&(min, max)| u128::from(max - min) + 1)
+                .sum()
+        }
 
-            if self.successor.is_none() {
-                println!("    I am alone, moving to Q5");
-                self.state = Q5;
+        /// The span compared by NET_JOIN, which is the number of hashes less one
+        /// like `max - min` of a single range.
+        fn span(&self) -> u64 {
+            self.owned_hashes().saturating_sub(1).min(u64::MAX.into()) as u64
+        }
+
+        /// What the NET_JOIN search compares, see `JoinWeight`.
+        fn join_weight(&self) -> u64 {
+            let entries = (self.stored.entries() + self.stored.kv()) as u64;
+            let requests = self.requests.per_minute(Instant::now());
+            match self.config.join_weight {
+                JoinWeight::Span => self.span(),
+                JoinWeight::Entries => entries,
+                JoinWeight::Requests => requests,
+                JoinWeight::Combined => entries + requests,
+            }
+        }
+
+        /// Where to split `(min, max)`, which holds more than one hash. The
+        /// lower part ends at the median hash of the entries in the range when
+        /// joins are weighted by load, and at the midpoint otherwise.
+        fn split_point(&mut self, (min, max): (u64, u64)) -> u64 {
+            let midpoint = (max - min) / 2 + min;
+            if self.config.join_weight == JoinWeight::Span {
+                return midpoint;
+            }
+
+            let mut hashes = self.storage.gather(move |s| s.hashes((min, max))).concat();
+            if hashes.is_empty() {
+                return midpoint;
+            }
+            hashes.sort_unstable();
+            hashes[(hashes.len() - 1) / 2].min(max - 1)
+        }
+
+        /// End of the run of adjacent ranges of this node that starts with the
+        /// range holding `hash`, or None if the node is not responsible for it.
+        fn run_end(&self, hash: u64) -> Option<u64> {
+            let i = self
+                .ranges
+                .iter()
+                .position(|&(min, max)| min <= hash && hash <= max)?;
+            let mut end = self.ranges[i].1;
+            for &(min, max) in &self.ranges[i + 1..] {
+                if end == u64::MAX || min != end + 1 {
+                    break;
+                }
+                end = max;
+            }
+            Some(end)
+        }
+
+        /// Carries out a control PDU from `addr` if it is signed with the control
+        /// key, names this node as its target and is newer than the last one
+        /// taken.
+        fn handle_control(&mut self, pdu: NetControlPdu, addr: SocketAddr) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let now = now.as_millis() as u64;
+            let refused = match &self.config.control_key {
+                None => Some("This node takes no control PDUs"),
+                Some(key) if !pdu.verify(key) => Some("Not signed with the control key"),
+                _ if pdu.get_target_addr() != SocketAddr::V4(self.get_udp_addr()) => {
+                    Some("The PDU is for another node")
+                }
+                _ if now.abs_diff(pdu.timestamp) > CONTROL_WINDOW.as_millis() as u64 => {
+                    Some("The timestamp is too far from the clock of the node")
+                }
+                _ if pdu.timestamp <= self.last_control => Some("The PDU was already used"),
+                _ => None,
+            };
+            if let Some(message) = refused {
+                warn!("Refusing NET_CONTROL from {}: {}", addr, message);
+                self.send_error(addr, ERROR_UNAUTHORIZED, pdu.pdu_type, message.into());
                 return;
             }
+            self.last_control = pdu.timestamp;
 
-            let pdu = match self.last_pdu.take() {
-                Some(PDU::NetJoin(pdu)) => pdu,
-                _ => panic!("Invalid state change, last_pdu is not NetJoin"),
+            match pdu.command {
+                CONTROL_LEAVE => {
+                    info!("Leave requested by {}", addr);
+                    self.should_close.store(true, Ordering::SeqCst);
+                }
+                CONTROL_HANDOFF_SUCCESSOR => self.hand_off(true, addr),
+                CONTROL_HANDOFF_PREDECESSOR => self.hand_off(false, addr),
+                x => {
+                    let message = format!("Unknown control command {}", x);
+                    self.send_error(addr, ERROR_INVALID_STATE, pdu.pdu_type, message);
+                }
+            }
+        }
+
+        /// Gives all ranges of this node to a neighbour along with their
+        /// entries, as asked by `addr`. The node stays in the ring and
+        /// forwards requests, but owns nothing until it leaves.
+        fn hand_off(&mut self, to_successor: bool, addr: SocketAddr) {
+            let (socket, wrapper, direction) = if to_successor {
+                (
+                    self.successor.as_mut(),
+                    &self.successor_wrapper,
+                    "successor",
+                )
+            } else {
+                (
+                    self.predecessor.as_mut(),
+                    &self.predecessor_wrapper,
+                    "predecessor",
+                )
+            };
+            let message = match socket {
+                _ if self.awaiting.is_some() || self.drain_until.is_some() => {
+                    Some("A join or leave is under way".to_owned())
+                }
+                None => Some(format!("There is no {} to hand the ranges to", direction)),
+                Some(_) if self.ranges.is_empty() => Some("There are no ranges to hand off".into()),
+                Some(socket) => {
+                    let ranges = &self.ranges;
+                    info!("Handing {:?} to {}, asked by {}", ranges, direction, addr);
+                    for &(start, end) in ranges {
+                        wrapper.send(socket, NetAddRangePdu::new(start, end).into());
+                    }
+                    None
+                }
             };
+            if let Some(message) = message {
+                warn!("Not handing off: {}", message);
+                self.send_error(addr, ERROR_INVALID_STATE, NET_CONTROL_ID, message);
+                return;
+            }
 
-            let own: SocketAddr = self.get_listen_addr().into();
-            if own == pdu.get_max_socket_addr() {
-                println!(
-                    "    I am the node with the maximum span! ({})",
-                    pdu.max_span
-                );
-                self.last_pdu = Some(pdu.into());
-                self.state = Q13;
+            let ranges = self.ranges.clone();
+            self.set_ranges(Vec::new());
+            for (start, end) in ranges {
+                self.transfer(to_successor, start, end);
+            }
+        }
+
+        /// Sends the ranges after the first, which went in the join response, to
+        /// the new successor, and moves the entries of all of them there.
+        fn hand_over(&mut self, given: &[(u64, u64)]) {
+            let socket = self.successor.as_mut().unwrap();
+            for &(start, end) in &given[1..] {
+                let add = NetAddRangePdu::new(start, end);
+                self.successor_wrapper.send(socket, add.into());
+            }
+            for &(start, end) in given {
+                self.transfer(true, start, end);
+            }
+        }
+
+        /// Tells both neighbours the load of this node, see `handle_rebalance`.
+        /// In a ring of two nodes the neighbour gets the offer twice, which
+        /// `REBALANCE_COOLDOWN` keeps from moving load twice. A node that
+        /// handed off its ranges takes no load back.
+        fn offer_load(&mut self) {
+            self.last_offer = Instant::now();
+            if self.ranges.is_empty() || self.awaiting.is_some() || self.drain_until.is_some() {
                 return;
             }
-            self.last_pdu = Some(pdu.into());
+            let load = self.join_weight();
+            debug!("Offering load {} to neighbours", load);
+            if let Some(s) = self.successor.as_mut() {
+                self.successor_wrapper
+                    .send(s, NetRebalancePdu::new(load).into());
+            }
+            if let Some(p) = self.predecessor.as_mut() {
+                self.predecessor_wrapper
+                    .send(p, NetRebalancePdu::new(load).into());
+            }
+        }
 
-            self.state = Q14;
+        /// Handles a load offer from a neighbour. A node that carries more than
+        /// `rebalance_skew` times `load` moves the end of its range facing the
+        /// neighbour there, sized to even out the loads. A node that carries
+        /// less answers with its own load, so the neighbour does the same.
+        /// Nothing moves while a join or leave is under way.
+        fn handle_rebalance(&mut self, load: u64, to_successor: bool) {
+            if self.awaiting.is_some() || self.drain_until.is_some() {
+                debug!("A join or leave is under way, not rebalancing");
+                return;
+            }
+            let own = self.join_weight();
+            let skew = self.config.rebalance_skew;
+            if own as f64 > load as f64 * skew && own - load >= 2 {
+                if let Some(t) = self.last_move {
+                    if t.elapsed() < REBALANCE_COOLDOWN {
+                        debug!("Moved part of a range {:?} ago, not again yet", t.elapsed());
+                        return;
+                    }
+                }
+                let fraction = rebalance_fraction(own, load);
+                match self.rebalance_range(to_successor, fraction) {
+                    Some(range) => self.move_range(range, to_successor),
+                    None => debug!("Load {} vs {}, but nothing to move", own, load),
+                }
+            } else if load as f64 > own as f64 * skew && load - own >= 2 && !self.ranges.is_empty()
+            {
+                let pdu = NetRebalancePdu::new(own).into();
+                if to_successor {
+                    let socket = self.successor.as_mut().unwrap();
+                    self.successor_wrapper.send(socket, pdu);
+                } else {
+                    let socket = self.predecessor.as_mut().unwrap();
+                    self.predecessor_wrapper.send(socket, pdu);
+                }
+            }
         }
 
-        fn q13(&mut self) {
-            println!("[Q13]");
-            let net_close = NetCloseConnectionPdu::new();
-            let successor_addr = self.get_successor_addr();
-            let mut socket = self.successor.as_mut().unwrap();
+        /// The part of the range facing a neighbour that carries about
+        /// `fraction` of the load of this node: the top of the last range
+        /// towards the successor and the bottom of the first range towards
+        /// the predecessor. Load is counted in hashes when joins are weighted
+        /// by span, and in entries otherwise. At least one hash of the range is
+        /// kept, and None is returned if nothing would move.
+        fn rebalance_range(&mut self, to_successor: bool, fraction: f64) -> Option<(u64, u64)> {
+            let &(min, max) = if to_successor {
+                self.ranges.last()?
+            } else {
+                self.ranges.first()?
+            };
+            if min == max {
+                return None;
+            }
 
-            let pdu = match self.last_pdu.take() {
-                Some(PDU::NetJoin(pdu)) => pdu,
-                _ => panic!("Invalid state change, last_pdu is not NetJoin"),
+            if self.config.join_weight == JoinWeight::Span {
+                let count = (self.owned_hashes() as f64 * fraction) as u128;
+                let count = count.min(u128::from(max - min)) as u64;
+                return match (count, to_successor) {
+                    (0, _) => None,
+                    (_, true) => Some((max - count + 1, max)),
+                    (_, false) => Some((min, min + count - 1)),
+                };
+            }
+
+            let mut hashes = self.storage.gather(move |s| s.hashes((min, max))).concat();
+            hashes.sort_unstable();
+            if to_successor {
+                hashes.reverse();
+            }
+            let stored = self.stored.entries() + self.stored.kv();
+            let count = (stored as f64 * fraction).round() as usize;
+            let boundary = *hashes.get(count.min(hashes.len()).checked_sub(1)?)?;
+            if to_successor {
+                Some((boundary.max(min + 1), max))
+            } else {
+                Some((min, boundary.min(max - 1)))
+            }
+        }
+
+        /// Gives `range`, at the end of one of our ranges facing the neighbour,
+        /// to that neighbour along with its entries.
+        fn move_range(&mut self, range: (u64, u64), to_successor: bool) {
+            let (start, end) = range;
+            let add = NetAddRangePdu::new(start, end).into();
+            let direction = if to_successor {
+                let socket = self.successor.as_mut().unwrap();
+                self.successor_wrapper.send(socket, add);
+                "successor"
+            } else {
+                let socket = self.predecessor.as_mut().unwrap();
+                self.predecessor_wrapper.send(socket, add);
+                "predecessor"
             };
+            self.set_ranges(subtract_range(&self.ranges, range));
+            self.transfer(to_successor, start, end);
+            self.last_move = Some(Instant::now());
+            metrics::REBALANCES.inc(&[direction]);
+            info!("Moved {:?} away, my ranges are {:?}", range, self.ranges);
+        }
 
-            println!("    Sending NET_CLOSE_CONNECTION to successor");
-            self.successor_wrapper.send(&mut socket, net_close.into());
-            socket.flush().unwrap();
-            socket.shutdown(Shutdown::Both).unwrap();
-            self.successor = None;
-            let addr = pdu.get_src_socket_addr();
-            let stream = self.connect_to_successor(addr);
-            self.successor_listen = Some(addr);
+        fn get_successor_addr(&self) -> SocketAddrV4 {
+            if let SocketAddr::V4(a) = self.successor.as_ref().unwrap().peer_addr().unwrap() {
+                return a;
+            }
 
-            println!("    Connected to new successor {:?}", stream.peer_addr());
-            self.successor = Some(stream);
+            panic!("Not bound to an IPv4 address!");
+        }
 
-            self.last_pdu = None;
+        fn get_predecessor_addr(&self) -> SocketAddrV4 {
+            if let SocketAddr::V4(a) = self.predecessor.as_ref().unwrap().peer_addr().unwrap() {
+                return a;
+            }
 
-            let (mins, maxs) = self.split_range();
-            println!("    Other hash-range is {:?}", (mins, maxs));
-            println!("    New hash-range is {:?}", self.hash_range);
+            panic!("Not bound to an IPv4 address!");
+        }
 
-            let join_response = NetJoinResponsePdu::new(
-                (*successor_addr.ip()).into(),
-                successor_addr.port(),
-                mins,
-                maxs,
+        /// The address clients reach this node on, as reported by the tracker.
+        fn get_udp_addr(&self) -> SocketAddrV4 {
+            let port = self.udp_socket.local_addr().unwrap().port();
+            SocketAddrV4::new(self.own_address.unwrap(), port)
+        }
+
+        fn get_listen_addr(&self) -> SocketAddrV4 {
+            if let SocketAddr::V4(a) = self.listen_socket.local_addr().unwrap() {
+                return a;
+            }
+
+            panic!("Not bound to an IPv4 address!");
+        }
+
+        fn transfer(&mut self, to_successor: bool, range_start: u64, range_end: u64) {
+            self.expire_subscriptions();
+            let range = (range_start, range_end);
+            let taken = self.storage.gather(move |s| s.take_range(range));
+            let now = Instant::now();
+            let (socket, wrapper, direction) = if to_successor {
+                (
+                    self.successor.as_mut().unwrap(),
+                    &self.successor_wrapper,
+                    "successor",
+                )
+            } else {
+                (
+                    self.predecessor.as_mut().unwrap(),
+                    &self.predecessor_wrapper,
+                    "predecessor",
+                )
+            };
+            let mut transferred = 0;
+            for e in taken.iter().flat_map(|(entries, _)| entries) {
+                trace!("Transferring: {:?}", e);
+                let insert = ValInsertPdu::new(e.ssn.clone(), e.name.clone(), e.email.clone());
+                let pdu: PDU = ValVersionPdu::new(e.version, insert.into()).into();
+                let pdu = match e.ttl(now) {
+                    Some(ttl) => ValTtlPdu::new(ttl, pdu).into(),
+                    None => pdu,
+                };
+                wrapper.send(socket, pdu);
+                transferred += 1;
+            }
+            for (key, e) in taken.iter().flat_map(|(_, kv)| kv) {
+                trace!("Transferring key {:?}: {:?}", key, e);
+                let put = ValKvPutPdu::new(key.clone(), e.value.clone());
+                wrapper.send(socket, ValVersionPdu::new(e.version, put.into()).into());
+                transferred += 1;
+            }
+
+            // Subscriptions follow the entries they are about
+            let mut moved = 0;
+            let mut subscriptions = self.subscriptions.write().unwrap();
+            for s in subscriptions
+                .iter()
+                .filter(|s| s.range.0 <= range_end && range_start <= s.range.1)
+            {
+                let (address, port) = match s.addr {
+                    SocketAddr::V4(a) => ((*a.ip()).into(), a.port()),
+                    _ => continue,
+                };
+                let (start, end) = (s.range.0.max(range_start), s.range.1.min(range_end));
+                let pdu =
+                    ValSubscribePdu::new(start, end, s.ssn.clone(), s.lease(now), address, port);
+                trace!("Transferring: {:?}", s);
+                wrapper.send(socket, pdu.into());
+                moved += 1;
+            }
+            socket.flush().unwrap();
+
+            // The transferred range is at one end of ours, so what is left of
+            // a subscription is still one range
+            subscriptions.retain(|s| s.range.0 < range_start || s.range.1 > range_end);
+            for s in subscriptions.iter_mut() {
+                if range_start <= s.range.0 && s.range.0 <= range_end {
+                    s.range.0 = range_end + 1;
+                }
+                if range_start <= s.range.1 && s.range.1 <= range_end {
+                    s.range.1 = range_start - 1;
+                }
+            }
+
+            info!(
+                "Transferred {} entries and {} subscriptions to {}",
+                transferred, moved, direction
             );
+            metrics::TRANSFERS.inc(&[direction]);
+            metrics::TRANSFERRED_ENTRIES.add(&[direction], transferred);
+            metrics::LAST_TRANSFER_SIZE.set(&[], transferred as i64);
+            metrics::ENTRIES.set(&[], self.stored.entries() as i64);
+            metrics::KV_ENTRIES.set(&[], self.stored.kv() as i64);
+            metrics::SUBSCRIPTIONS.set(&[], subscriptions.len() as i64);
+        }
 
-            let mut socket = self.successor.as_mut().unwrap();
-            println!("    Sending join response");
-            self.successor_wrapper
-                .send(&mut socket, join_response.into());
+        fn send_alive(&mut self) {
+            if self.last_alive.elapsed().as_secs() > 10 {
+                self.last_alive = Instant::now();
+                let alive = NetAlivePdu::new();
+                self.udp_wrapper
+                    .send(&mut self.udp_socket, alive.into(), self.tracker_addr);
+            }
+        }
+
+        /// Waits up to `timeout` for the sockets and reads what arrived into the
+        /// wrappers, and accepts a connecting predecessor if one is awaited.
+        /// Handling the PDUs is up to the state. Returns whether anything
+        /// happened before the timeout.
+        fn poll_sockets(&mut self, timeout: Duration) -> bool {
+            self.poll.poll(&mut self.events, Some(timeout)).ok();
+            let tokens: Vec<Token> = self.events.iter().map(|e| e.token()).collect();
+            let happened = !tokens.is_empty();
+            for token in tokens {
+                match token {
+                    UDP => {
+                        let rejected = self.udp_wrapper.try_read(&mut self.udp_socket);
+                        for (pdu_type, error, addr) in rejected {
+                            let message = format!("Could not parse the PDU: {}", error);
+                            self.send_error(addr, ERROR_INVALID_PDU, pdu_type, message);
+                        }
+                    }
+                    SUCCESSOR => {
+                        if let Some(suc) = &mut self.successor {
+                            if self.successor_wrapper.try_read(suc) {
+                                info!("Successor disconnected, removing..");
+                                self.set_successor(None);
+                            }
+                        }
+                    }
+                    PREDECESSOR => {
+                        if let Some(pred) = &mut self.predecessor {
+                            if self.predecessor_wrapper.try_read(pred) {
+                                info!("Predecessor disconnected, removing..");
+                                self.set_predecessor(None);
+                            }
+                        }
+                    }
+                    LISTEN if self.awaiting.is_some() => self.accept_predecessor(),
+                    LISTEN => {}
+                    _ => panic!("What token is this?"),
+                }
+            }
+            happened
+        }
+
+        /// Handles the PDUs read from the neighbours and over UDP, until one of
+        /// them moves the node out of Q6.
+        fn handle_queued_pdus(&mut self) {
+            while matches!(self.state, Q6) {
+                let (pdu, sender) = if let Some((p, s)) = self.predecessor_wrapper.next_pdu() {
+                    (p, Source::Predecessor(s))
+                } else if let Some((p, s)) = self.successor_wrapper.next_pdu() {
+                    (p, Source::Successor(s))
+                } else if let Some((p, s)) = self.udp_wrapper.next_pdu() {
+                    (p, Source::Udp(s))
+                } else {
+                    break;
+                };
+                self.handle_pdu(pdu, sender);
+            }
+        }
+
+        /// Replaces the successor connection and its poll registration.
+        fn set_successor(&mut self, successor: Option<TcpStream>) {
+            if let Some(mut old) = self.successor.take() {
+                self.poll.registry().deregister(&mut old).unwrap();
+            }
+            self.successor = successor;
+            if let Some(s) = self.successor.as_mut() {
+                self.poll
+                    .registry()
+                    .register(s, SUCCESSOR, Interest::READABLE)
+                    .unwrap();
+            }
+        }
+
+        /// Replaces the predecessor connection and its poll registration.
+        fn set_predecessor(&mut self, predecessor: Option<TcpStream>) {
+            if let Some(mut old) = self.predecessor.take() {
+                self.poll.registry().deregister(&mut old).unwrap();
+            }
+            self.predecessor = predecessor;
+            if let Some(p) = self.predecessor.as_mut() {
+                self.poll
+                    .registry()
+                    .register(p, PREDECESSOR, Interest::READABLE)
+                    .unwrap();
+            }
+        }
+
+        /// Whether the node was asked to leave, by Ctrl-C.
+        fn close_requested(&self) -> bool {
+            self.should_close.load(Ordering::SeqCst)
+        }
+
+        /// Asks the tracker for a node to join at, which q3 waits for.
+        fn request_node(&mut self) {
+            let get_node = NetGetNodePdu::new();
+            self.udp_wrapper
+                .send(&mut self.udp_socket, get_node.into(), self.tracker_addr);
+            self.state = Q3;
+        }
+
+        /// Makes `q6` accept the next node that connects as the predecessor,
+        /// until `timeout` runs out.
+        fn await_predecessor(&mut self, awaiting: Awaiting, timeout: Duration) {
+            self.awaiting = Some((awaiting, Instant::now() + timeout));
+        }
 
-            //Transfer all between mins and maxs
-            self.transfer(true, mins, maxs);
+        fn accept_predecessor(&mut self) {
+            let (predecessor, addr) = match self.listen_socket.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => panic!("Failed to accept connection {:?}", e),
+            };
+            info!("Accepted new predecessor {:?}", addr);
+            self.set_predecessor(Some(predecessor));
+            if let Some((Awaiting::Join, _)) = self.awaiting.take() {
+                self.join_attempts = 0;
+                self.state = Q8;
+            }
+        }
 
-            self.state = Q6;
+        /// Ends a wait for a predecessor that never connected.
+        fn give_up_predecessor(&mut self, awaiting: Awaiting) {
+            warn!("No predecessor connected in time ({:?})", awaiting);
+            match awaiting {
+                Awaiting::Join if self.drain_until.is_some() => self.state = Q10,
+                Awaiting::Join => {
+                    info!("Asking the tracker for a node again");
+                    self.join_attempts += 1;
+                    self.request_node();
+                }
+                Awaiting::Split { given } => {
+                    // The joining node is gone, and with it the entries it got
+                    info!("Taking back {:?}, I am alone again", given);
+                    if let Some(s) = self.successor.as_mut() {
+                        s.shutdown(Shutdown::Both).ok();
+                    }
+                    self.set_successor(None);
+                    self.successor_listen = None;
+                    for range in given {
+                        self.add_range(range);
+                    }
+                }
+                Awaiting::Repair => {
+                    info!("Going on without a predecessor");
+                }
+            }
         }
 
-        fn q14(&mut self) {
-            println!("[Q14]");
+        fn handle_val_insert(&mut self, pdu: ValInsertPdu) {
+            if !self.in_my_range(&pdu.ssn) {
+                self.forward_to_successor(pdu.into());
+                return;
+            }
 
-            let mut pdu = match self.last_pdu.take() {
-                Some(PDU::NetJoin(pdu)) => pdu,
-                _ => panic!("Invalid state change, last_pdu is not NetJoin"),
+            let request = self.request(pdu.pdu_type, None);
+            let version = match self.version {
+                Some(v) => v,
+                None => self.clock.now(),
             };
+            let hash = self.hash_ssn(&pdu.ssn);
+            let mut e = Entry::new(hash, pdu.ssn, pdu.name, pdu.email, version);
+            e.expires = self.get_expiry();
+            self.storage.run(hash, move |s| s.insert(e, request));
+        }
 
-            let (min, max) = self.hash_range;
-            if max - min > pdu.max_span {
-                println!("    Updating max fields");
-                pdu.max_span = max - min;
-                let a = self.get_listen_addr();
-                pdu.max_address = (*a.ip()).into();
-                pdu.max_port = a.port();
+        fn handle_val_cond_insert(&mut self, pdu: ValCondInsertPdu) {
+            if !self.in_my_range(&pdu.ssn) {
+                self.forward_to_successor(pdu.into());
+                return;
             }
 
-            println!("    Forwarding to successor");
+            let request = self.request(pdu.pdu_type, Some(pdu.get_sender_addr()));
+            let hash = self.hash_ssn(&pdu.ssn);
+            let mut e = Entry::new(hash, pdu.ssn, pdu.name, pdu.email, self.clock.now());
+            e.expires = self.get_expiry();
+            let expected = pdu.expected_version;
+            self.storage
+                .run(hash, move |s| s.cond_insert(e, expected, request));
+        }
 
-            if let Some(socket) = &mut self.successor {
-                self.successor_wrapper.send(socket, pdu.into());
-            } else {
-                panic!("Successor is not set, impossible!");
+        fn handle_val_cond_remove(&mut self, pdu: ValCondRemovePdu) {
+            if !self.in_my_range(&pdu.ssn) {
+                self.forward_to_successor(pdu.into());
+                return;
             }
 
-            self.state = Q6;
+            let hash = self.hash_ssn(&pdu.ssn);
+            self.storage.run(hash, move |s| s.cond_remove(pdu));
         }
 
-        fn q15(&mut self) {
-            println!("[Q15]");
-
-            let new_range = match self.last_pdu.take() {
-                Some(PDU::NetNewRange(pdu)) => pdu,
-                _ => panic!("Invalid state change, last_pdu is not NetNewRange"),
+        fn handle_val_scan(&mut self, pdu: ValScanPdu) {
+            let max = match self.run_end(pdu.range_start) {
+                Some(max) => max,
+                None => {
+                    self.forward_to_successor(pdu.into());
+                    return;
+                }
             };
-            let (min, max) = self.hash_range;
-            println!("    Current range is: ({}, {})", min, max);
 
-            let new_range_response = NetNewRangeResponsePdu::new();
-            if max != 255 && new_range.range_start == max + 1 {
-                println!("    Sending NET_NEW_RANGE_RESPONSE to successor");
-                self.hash_range = (min, new_range.range_end);
-                self.successor_wrapper.send(self.successor.as_mut().unwrap(), new_range_response.into());
+            let end = pdu.range_end.min(max);
+            let after = pdu
+                .cursor
+                .as_deref()
+                .map(|c| (self.hash_ssn(c), c.to_owned()));
+            let limit = pdu.limit.clamp(1, MAX_SCAN_ENTRIES) as usize;
+            let range = (pdu.range_start, end);
+            let mut matching: Vec<(u64, ScanEntry)> = self
+                .storage
+                .gather(move |s| s.scan(range, after.as_ref(), limit))
+                .into_iter()
+                .flatten()
+                .collect();
+            matching.sort_by(|a, b| (a.0, &a.1.ssn).cmp(&(b.0, &b.1.ssn)));
+
+            let found = matching.len();
+            let mut entries: Vec<ScanEntry> =
+                matching.into_iter().take(limit).map(|(_, e)| e).collect();
+            entries.truncate(ScanEntry::fitting(&entries));
+            let cursor = if found > entries.len() {
+                entries.last().map(|e| e.ssn.clone())
             } else {
-                println!("    Sending NET_NEW_RANGE_RESPONSE to predecessor");
-                self.hash_range = (new_range.range_start, max);
-                self.predecessor_wrapper.send(self.predecessor.as_mut().unwrap(), new_range_response.into());
-            }
-            println!("    New range is: ({}, {})", self.hash_range.0, self.hash_range.1);
-
+                None
+            };
 
-            self.state = Q6;
+            debug!(
+                "Scanned {} entries in ({}, {}), more: {}",
+                entries.len(),
+                pdu.range_start,
+                end,
+                cursor.is_some()
+            );
+            let response = ValScanResponsePdu::new(pdu.range_start, end, cursor, entries);
+            self.udp_wrapper
+                .send(&mut self.udp_socket, response.into(), pdu.get_sender_addr());
         }
 
-        fn q16(&mut self) {
-            println!("[Q16]");
+        /// Subscribes the sender to the part of the range this node is
+        /// responsible for, and forwards the rest of the range.
+        fn handle_val_subscribe(&mut self, pdu: ValSubscribePdu) {
+            let run_end = self.run_end(pdu.range_start);
+            let owned = match &pdu.ssn {
+                Some(ssn) => self.in_my_range(ssn),
+                None => run_end.is_some(),
+            };
+            if !owned {
+                self.forward_to_successor(pdu.into());
+                return;
+            }
 
-            let net_leaving = match self.last_pdu.take() {
-                Some(PDU::NetLeaving(pdu)) => pdu,
-                _ => panic!("Invalid state change, last_pdu is not NetLeaving"),
+            let max = run_end.unwrap_or(u64::MAX);
+            let range = match &pdu.ssn {
+                Some(ssn) => (self.hash_ssn(ssn), self.hash_ssn(ssn)),
+                None => (pdu.range_start, pdu.range_end.min(max)),
             };
+            let addr = pdu.get_sender_addr();
+            self.subscribe(addr, pdu.ssn.clone(), range, pdu.lease);
+
+            if pdu.ssn.is_none() && pdu.range_end > max {
+                let rest = ValSubscribePdu::new(
+                    max + 1,
+                    pdu.range_end,
+                    None,
+                    pdu.lease,
+                    pdu.sender_address,
+                    pdu.sender_port,
+                );
+                self.forward_rest(rest.into());
+            }
+        }
 
-            if net_leaving.new_address == self.own_address.unwrap().into() &&
-               net_leaving.new_port == self.get_listen_addr().port() {
-                println!("    I am the last node.");
-                self.successor = None;
-                self.predecessor = None;
+        /// Sends the matches in this node's range to the sender, and passes the
+        /// query on to the rest of the ring.
+        fn handle_val_query(&mut self, pdu: ValQueryPdu) {
+            let max = match self.run_end(pdu.range_start) {
+                Some(max) => max,
+                None => {
+                    self.forward_to_successor(pdu.into());
+                    return;
+                }
+            };
 
-            } else {
-                self.successor = None;
+            let (field, value) = (pdu.field, pdu.value.to_lowercase());
+            let range = (pdu.range_start, max);
+            let mut matching: Vec<ScanEntry> = self
+                .storage
+                .gather(move |s| s.query(field, &value, range))
+                .into_iter()
+                .flatten()
+                .collect();
+            matching.sort_by(|a, b| a.ssn.cmp(&b.ssn));
+            debug!(
+                "Query for {:?} matched {} entries",
+                pdu.value,
+                matching.len()
+            );
 
-                self.successor = Some(self.connect_to_successor(net_leaving.get_new_addr()));
+            // Every part is sent, even an empty one, so the sender knows the
+            // range has answered
+            let mut rest = matching;
+            let mut parts = Vec::new();
+            while parts.len() < u8::MAX as usize && (parts.is_empty() || !rest.is_empty()) {
+                let count = ScanEntry::fitting(&rest).min(MAX_SCAN_ENTRIES as usize);
+                let tail = rest.split_off(count);
+                parts.push(std::mem::replace(&mut rest, tail));
             }
 
+            let count = parts.len() as u8;
+            let addr = pdu.get_sender_addr();
+            for (i, entries) in parts.into_iter().enumerate() {
+                let response =
+                    ValQueryResponsePdu::new(pdu.range_start, max, i as u8, count, entries);
+                self.udp_wrapper
+                    .send(&mut self.udp_socket, response.into(), addr);
+            }
 
-            self.state = Q6;
+            if max < self.config.hasher.max_hash() {
+                let rest = ValQueryPdu::new(
+                    pdu.field,
+                    pdu.value,
+                    max + 1,
+                    pdu.sender_address,
+                    pdu.sender_port,
+                );
+                self.forward_rest(rest.into());
+            }
         }
 
-        fn q17(&mut self) {
-            println!("[Q17]");
-            if let Some(mut s) = self.predecessor.take() {
-                println!("    Disconnecting from predecessor");
-                s.flush().unwrap();
-                s.shutdown(Shutdown::Both).unwrap();
-                self.predecessor = None;
-            }
-            let (min, max) = self.hash_range;
-            if min == 0 && max == 255 {
-                println!("    I am the last node");
-            } else {
-                println!("    Awaiting new predecessor");
-                self.accept_predecessor();
+        fn handle_val_kv_put(&mut self, pdu: ValKvPutPdu) {
+            let hash = self.hash_key(&pdu.key);
+            if !self.owns_hash(hash) {
+                self.forward_to_successor(pdu.into());
+                return;
             }
 
-            self.state = Q6;
+            let request = self.request(pdu.pdu_type, None);
+            let version = match self.version {
+                Some(v) => v,
+                None => self.clock.now(),
+            };
+            let e = KvEntry {
+                hash,
+                value: pdu.value,
+                version,
+            };
+            let key = pdu.key;
+            self.storage.run(hash, move |s| s.kv_put(key, e, request));
         }
 
-        fn q18(&mut self) {
-            println!("[Q18]");
-
-            let (min, max) = self.hash_range;
-            let to_successor = min == 0;
-
-            if to_successor {
-                println!("    Transferring all entries to successor");
-                self.transfer(true, min, max);
-            } else {
-                println!("    Transferring all entries to predecessor");
-                self.transfer(false, min, max);
+        fn handle_val_kv_delete(&mut self, pdu: ValKvDeletePdu) {
+            let hash = self.hash_key(&pdu.key);
+            if !self.owns_hash(hash) {
+                self.forward_to_successor(pdu.into());
+                return;
             }
 
-            let successor = match self.successor.as_mut() {
-                Some(s) => s,
-                _ => panic!("Missing successor socket >("),
-            };
-
-            let close = NetCloseConnectionPdu::new();
-            self.successor_wrapper.send(successor, close.into());
-            successor.flush().unwrap();
-            successor.shutdown(Shutdown::Both).unwrap();
+            self.storage.run(hash, move |s| s.kv_delete(&pdu.key));
+        }
 
-            let to_connect = match self.successor_listen {
-                Some(SocketAddr::V4(addr)) => addr,
-                _ => panic!("Successor listen address is not set"),
-            };
+        fn handle_val_kv_get(&mut self, pdu: ValKvGetPdu) {
+            let hash = self.hash_key(&pdu.key);
+            if !self.owns_hash(hash) {
+                self.forward_to_successor(pdu.into());
+                return;
+            }
 
-            let leaving = NetLeavingPdu::new((*to_connect.ip()).into(), to_connect.port());
+            self.storage.run(hash, move |s| s.kv_get(pdu));
+        }
 
-            let predecessor = match self.predecessor.as_mut() {
-                Some(s) => s,
-                _ => panic!("Missing predecessor socket >("),
-            };
+        /// Applies the entries of the batch that are in range and forwards the
+        /// rest. All of them go through the successor, so they stay in one batch.
+        fn handle_val_batch(&mut self, pdu: ValBatchPdu) {
+            debug!("Handling batch of {} entries", pdu.entries.len());
+            let mut forward = Vec::new();
+            for entry in pdu.entries {
+                let ssn = match &entry {
+                    PDU::ValInsert(p) => &p.ssn,
+                    PDU::ValRemove(p) => &p.ssn,
+                    PDU::ValTtl(p) => match &*p.inner {
+                        PDU::ValInsert(i) => &i.ssn,
+                        x => {
+                            warn!("Got TTL around a non-insert PDU in batch: {:?}", x);
+                            continue;
+                        }
+                    },
+                    x => {
+                        warn!("Got {:?} in batch, only inserts and removes are allowed", x);
+                        continue;
+                    }
+                };
 
-            println!("    Sending NET_LEAVING to predecessor");
-            self.predecessor_wrapper.send(predecessor, leaving.into());
-            predecessor.flush().unwrap();
-            predecessor.shutdown(Shutdown::Both).unwrap();
+                if self.in_my_range(ssn) {
+                    self.apply_batch_entry(entry);
+                } else {
+                    forward.push(entry);
+                }
+            }
 
-            self.running = false;
+            if !forward.is_empty() {
+                self.forward_to_successor(ValBatchPdu::new(forward).into());
+            }
         }
 
-        fn handle_pdu(&mut self, pdu: PDU, sender: Source) {
-            match pdu {
-                PDU::NetJoin(p) => {
-                    self.last_pdu = Some(p.into());
-                    self.state = Q12;
+        fn apply_batch_entry(&mut self, entry: PDU) {
+            match entry {
+                PDU::ValTtl(p) => {
+                    self.ttl = Some(p.ttl);
+                    self.apply_batch_entry(*p.inner);
+                    self.ttl = None;
                 }
-                PDU::NetCloseConnection(_) => match sender {
-                    Source::Predecessor(_) => {
-                        self.state = Q17;
-                    }
-                    _ => {
-                        panic!("Got NET_CLOSE from someone other than my predecessor!");
+                p => {
+                    if !self.reject_invalid(&p) {
+                        self.last_pdu = Some(p);
+                        self.q9();
                     }
-                },
-                PDU::NetNewRange(p) => {
-                    self.last_pdu = Some(p.into());
-                    self.state = Q15;
-                }
-                PDU::NetLeaving(p) => {
-                    self.last_pdu = Some(p.into());
-                    self.state = Q16;
-                }
-                PDU::ValInsert(p) => {
-                    self.last_pdu = Some(p.into());
-                    self.q9();
-                }
-                PDU::ValRemove(p) => {
-                    self.last_pdu = Some(p.into());
-                    self.q9();
-                }
-                PDU::ValLookup(p) => {
-                    self.last_pdu = Some(p.into());
-                    self.q9();
-                }
-                x => {
-                    println!(
-                        "Got PDU that node does not accept in the current state (Q6), was: {:?}",
-                        x
-                    );
                 }
             }
         }
-    }
 
-    // Util functions
-    impl Node {
-        fn split_range(&mut self) -> (u8, u8) {
-            let (min, max) = self.hash_range;
-            let (minp, maxp) = (min, (max - min) / 2 + min);
-            let (mins, maxs) = (maxp + 1, max);
-            self.hash_range = (minp, maxp);
-            (mins, maxs)
+        fn handle_val_lookup(&mut self, pdu: ValLookupPdu) {
+            if self.in_my_range(&pdu.ssn) {
+                let hash = self.hash_ssn(&pdu.ssn);
+                self.storage.run(hash, move |s| s.lookup(pdu));
+            } else {
+                self.forward_to_successor(pdu.into());
+            }
         }
 
-        fn get_successor_addr(&self) -> SocketAddrV4 {
-            if let SocketAddr::V4(a) = self.successor.as_ref().unwrap().peer_addr().unwrap() {
-                return a;
+        fn handle_val_remove(&mut self, pdu: ValRemovePdu) {
+            if self.in_my_range(&pdu.ssn) {
+                let hash = self.hash_ssn(&pdu.ssn);
+                self.storage.run(hash, move |s| s.remove(&pdu.ssn));
+            } else {
+                self.forward_to_successor(pdu.into());
             }
+        }
 
-            panic!("Not bound to an IPv4 address!");
+        fn handle_val_trace(&mut self, pdu: ValTracePdu, sender: Source) {
+            let hop_count = pdu.hop_count.saturating_add(1);
+            let trace_id = format!("{:016x}", pdu.trace_id);
+            let span = info_span!("hop", trace_id = %trace_id, hop = hop_count);
+            let _entered = span.enter();
+            let report_addr = pdu.get_report_addr();
+
+            let inner = match *pdu.inner {
+                p @ PDU::ValVersion(_) | p @ PDU::ValTtl(_) => p,
+                p if p.is_value_request() => p,
+                x => {
+                    warn!("Got trace context around a non-value PDU: {:?}", x);
+                    return;
+                }
+            };
+            info!("Handling traced {:?} from {:?}", inner, sender);
+
+            let own = self.get_udp_addr();
+            let hop = ValTraceHopPdu::new(pdu.trace_id, hop_count, (*own.ip()).into(), own.port());
+            self.udp_wrapper
+                .send(&mut self.udp_socket, hop.into(), report_addr);
+
+            self.trace = Some(TraceContext {
+                trace_id: pdu.trace_id,
+                hop_count,
+                report_address: pdu.report_address,
+                report_port: pdu.report_port,
+            });
+            self.handle_pdu(inner, sender);
+            self.trace = None;
         }
 
-        fn get_predecessor_addr(&self) -> SocketAddrV4 {
-            if let SocketAddr::V4(a) = self.predecessor.as_ref().unwrap().peer_addr().unwrap() {
-                return a;
+        /// Handles a value PDU in Q9. Unless it arrived in a `ValHopLimitPdu` the
+        /// full hop limit applies, and only UDP senders can be sent errors.
+        fn handle_value_pdu(&mut self, pdu: PDU, sender: Source) {
+            let owns_route = self.route.is_none();
+            if owns_route {
+                let origin = match sender {
+                    Source::Udp(addr) => Some(addr),
+                    _ => None,
+                };
+                self.route = Some(RouteContext {
+                    hops_left: self.config.hop_limit,
+                    origin,
+                });
             }
 
-            panic!("Not bound to an IPv4 address!");
+            if !self.reject_invalid(&pdu) {
+                if self.handles(&pdu) {
+                    self.requests.record(Instant::now());
+                }
+                self.last_pdu = Some(pdu);
+                self.q9();
+            }
+
+            if owns_route {
+                self.route = None;
+            }
         }
 
-        fn get_listen_addr(&self) -> SocketAddrV4 {
-            if let SocketAddr::V4(a) = self.listen_socket.local_addr().unwrap() {
-                return a;
+        /// Replies with an error if the request has an ssn that is not valid,
+        /// or a name, email, key or value too large to store.
+        fn reject_invalid(&mut self, pdu: &PDU) -> bool {
+            let (size, max) = match pdu {
+                PDU::ValKvPut(p) => (p.key.len() + p.value.len(), MAX_KV_SIZE),
+                PDU::ValInsert(p) => (p.name.len().max(p.email.len()), MAX_TEXT_LENGTH),
+                PDU::ValCondInsert(p) => (p.name.len().max(p.email.len()), MAX_TEXT_LENGTH),
+                _ => (0, 0),
+            };
+            if size > max {
+                warn!("Got {} bytes where at most {} are allowed", size, max);
+                let message = format!("{} bytes is more than {}", size, max);
+                self.reply_error(pdu, ERROR_TOO_LARGE, message);
+                return true;
             }
 
-            panic!("Not bound to an IPv4 address!");
+            let ssn = match pdu.value_ssn() {
+                Some(ssn) => ssn,
+                None => return false,
+            };
+            match Ssn::parse_checked(ssn, self.config.luhn) {
+                Ok(_) => false,
+                Err(e) => {
+                    let ssn = ssn.trim_end_matches('\0');
+                    warn!("Got malformed ssn {:?}: {}", ssn, e);
+                    let message = format!("{:?} {}", ssn, e);
+                    self.reply_error(pdu, ERROR_MALFORMED_SSN, message);
+                    true
+                }
+            }
         }
 
-        fn transfer(&mut self, to_successor: bool, range_start: u8, range_end: u8) {
-            let mut socket = if to_successor {
-                self.successor.as_mut().unwrap()
-            } else {
-                self.predecessor.as_mut().unwrap()
+        fn handle_val_hop_limit(&mut self, pdu: ValHopLimitPdu, sender: Source) {
+            let route = RouteContext {
+                hops_left: pdu.hop_limit,
+                origin: pdu.get_origin_addr(),
             };
-            // Could use drain_filter from nightly
-            for e in self
-                .values
-                .iter()
-                .filter(|&x| x.hash() >= range_start && x.hash() <= range_end)
-            {
-                println!("Transferring: {:?}", e);
-                let insert = ValInsertPdu::new(e.ssn.clone(), e.name.clone(), e.email.clone());
-                self.successor_wrapper.send(&mut socket, insert.into());
+
+            let previous = self.route.replace(route);
+            match *pdu.inner {
+                PDU::ValTrace(p) => self.handle_val_trace(p, sender),
+                PDU::ValVersion(p) => self.handle_val_version(p, sender),
+                PDU::ValTtl(p) => self.handle_val_ttl(p, sender),
+                p if p.is_value_request() => self.handle_value_pdu(p, sender),
+                x => warn!("Got hop limit around a non-value PDU: {:?}", x),
             }
-            socket.flush().unwrap();
-            self.values
-                .retain(|x| x.hash() < range_start || x.hash() > range_end);
+            self.route = previous;
         }
 
-        fn send_alive(&mut self) {
-            if self.last_alive.elapsed().as_secs() > 10 {
-                self.last_alive = Instant::now();
-                let alive = NetAlivePdu::new();
-                self.udp_wrapper
-                    .send(&mut self.udp_socket, alive.into(), self.tracker_addr);
+        fn handle_val_version(&mut self, pdu: ValVersionPdu, sender: Source) {
+            // A version skips the storage limit and notifications, and moves
+            // the clock, so only neighbours moving entries may set one
+            if let Source::Udp(addr) = sender {
+                warn!("Got a version from {}, which is not a neighbour", addr);
+                let message = "Versions are only taken from neighbouring nodes".to_owned();
+                self.send_error(addr, ERROR_INVALID_PDU, pdu.pdu_type, message);
+                return;
+            }
+            let inner = match *pdu.inner {
+                p @ PDU::ValInsert(_) | p @ PDU::ValKvPut(_) => p,
+                x => {
+                    warn!("Got version around a non-insert PDU: {:?}", x);
+                    return;
+                }
+            };
+
+            self.clock.observe(pdu.version);
+            self.version = Some(pdu.version);
+            self.handle_value_pdu(inner, sender);
+            self.version = None;
+        }
+
+        fn handle_val_ttl(&mut self, pdu: ValTtlPdu, sender: Source) {
+            self.ttl = Some(pdu.ttl);
+            match *pdu.inner {
+                PDU::ValVersion(p) => self.handle_val_version(p, sender),
+                p @ PDU::ValInsert(_) | p @ PDU::ValCondInsert(_) => {
+                    self.handle_value_pdu(p, sender)
+                }
+                x => warn!("Got TTL around a non-insert PDU: {:?}", x),
             }
+            self.ttl = None;
         }
 
-        fn await_pdu_udp(&mut self) -> Message {
-            let mut poll = Poll::new().unwrap();
-            poll.registry()
-                .register(&mut self.udp_socket, UDP, Interest::READABLE)
-                .unwrap();
-            let mut events = Events::with_capacity(1);
-            let x = loop {
-                poll.poll(&mut events, None).unwrap();
-                self.udp_wrapper.try_read(&mut self.udp_socket);
+        /// Forwards the part of a query or subscription past the ranges this
+        /// node answered for. It starts with the full hop limit, as every node
+        /// that answers moves the start of the range forward, so the walk ends
+        /// at the top of the ring rather than after a hop limit of nodes.
+        fn forward_rest(&mut self, pdu: PDU) {
+            let limit = self.config.hop_limit;
+            let spent = self
+                .route
+                .as_mut()
+                .map(|r| std::mem::replace(&mut r.hops_left, limit));
+            self.forward_to_successor(pdu);
+            if let (Some(route), Some(hops_left)) = (self.route.as_mut(), spent) {
+                route.hops_left = hops_left;
+            }
+        }
 
-                if let Some(x) = self.udp_wrapper.next_pdu() {
-                    break x;
+        fn forward_to_successor(&mut self, pdu: PDU) {
+            if self.successor.is_none() {
+                if !pdu.is_value_request() {
+                    panic!("Successor is not set, impossible!");
                 }
+                warn!("No successor to forward {:?} to", pdu);
+                let message = format!("Node {} has no successor", self.get_udp_addr());
+                self.reply_error(&pdu, ERROR_NO_SUCCESSOR, message);
+                return;
+            }
+
+            let pdu = match pdu {
+                p if p.is_value_request() => match self.limit_hops(p) {
+                    Some(p) => p,
+                    None => return,
+                },
+                p => p,
             };
 
-            poll.registry().deregister(&mut self.udp_socket).unwrap();
+            let socket = self.successor.as_mut().unwrap();
+            debug!("Forwarding {:?} to successor", pdu);
+            metrics::FORWARDED.inc(&[pdu.name()]);
+            self.successor_wrapper.send(socket, pdu);
+        }
 
-            x
+        /// When an entry inserted by the current request expires.
+        fn get_expiry(&self) -> Option<Instant> {
+            self.ttl
+                .map(|t| Instant::now() + Duration::from_secs(t.into()))
         }
 
-        fn await_pdu(&mut self, successor: bool) -> Message {
-            let mut poll = Poll::new().unwrap();
-            if successor {
-                poll.registry()
-                    .register(
-                        self.successor.as_mut().unwrap(),
-                        SUCCESSOR,
-                        Interest::READABLE,
-                    )
-                    .unwrap();
+        fn expire_subscriptions(&mut self) {
+            let now = Instant::now();
+            let mut subscriptions = self.subscriptions.write().unwrap();
+            subscriptions.retain(|s| {
+                if s.expires <= now {
+                    debug!("Subscription expired: {:?}", s);
+                }
+                s.expires > now
+            });
+            metrics::SUBSCRIPTIONS.set(&[], subscriptions.len() as i64);
+        }
+
+        /// Adds or renews the subscription of `addr`, or ends it if the lease is 0.
+        fn subscribe(
+            &mut self,
+            addr: SocketAddr,
+            ssn: Option<String>,
+            range: (u64, u64),
+            lease: u32,
+        ) {
+            let mut subscriptions = self.subscriptions.write().unwrap();
+            subscriptions.retain(|s| !s.is_renewed_by(addr, &ssn, range));
+
+            if lease > 0 {
+                let lease = lease.min(MAX_LEASE);
+                let s = Subscription {
+                    ssn,
+                    range,
+                    addr,
+                    expires: Instant::now() + Duration::from_secs(lease.into()),
+                };
+                debug!("Subscribing {:?}", s);
+                subscriptions.push(s);
             } else {
-                poll.registry()
-                    .register(
-                        self.predecessor.as_mut().unwrap(),
-                        PREDECESSOR,
-                        Interest::READABLE,
-                    )
-                    .unwrap();
+                debug!("Unsubscribing {} from {:?} {:?}", addr, ssn, range);
             }
-            let mut events = Events::with_capacity(1);
-            loop {
-                poll.poll(&mut events, None).unwrap();
-
-                if successor {
-                    self.successor_wrapper
-                        .try_read(self.successor.as_mut().unwrap());
+            metrics::SUBSCRIPTIONS.set(&[], subscriptions.len() as i64);
+        }
 
-                    if let Some(x) = self.successor_wrapper.next_pdu() {
-                        poll.registry()
-                            .deregister(self.successor.as_mut().unwrap())
-                            .unwrap();
-                        return x;
-                    }
-                } else {
-                    self.predecessor_wrapper
-                        .try_read(self.predecessor.as_mut().unwrap());
-
-                    if let Some(x) = self.predecessor_wrapper.next_pdu() {
-                        poll.registry()
-                            .deregister(self.predecessor.as_mut().unwrap())
-                            .unwrap();
-                        return x;
-                    }
-                }
+        /// Sends an error about `request` to whoever sent it: the sender address
+        /// in the request if it has one, or else the originator of the route.
+        fn reply_error(&mut self, request: &PDU, code: u8, message: String) {
+            match self.origin(request.sender_addr()) {
+                Some(addr) => self.send_error(addr, code, request.pdu_type(), message),
+                None => warn!("Originator is unknown, no error is sent"),
             }
         }
 
-        fn accept_predecessor(&mut self) {
-            let predecessor = match self.listen_socket.accept() {
-                Ok((socket, addr)) => {
-                    println!("    Accepted new predecessor {:?}", addr);
-                    socket
-                }
-                Err(e) => {
-                    panic!("Failed to accept connection {:?}", e);
-                }
-            };
-            predecessor.set_nonblocking(true).unwrap();
-            self.predecessor = Some(TcpStream::from_std(predecessor));
+        fn send_error(&mut self, addr: SocketAddr, code: u8, request_type: u8, message: String) {
+            let socket = &mut self.udp_socket;
+            send_error(&self.udp_wrapper, socket, addr, code, request_type, message);
         }
 
-        fn handle_val_insert(&mut self, pdu: ValInsertPdu) {
-            if self.in_my_range(&pdu.ssn) {
-                let e = Entry::new(pdu.ssn, pdu.name, pdu.email);
-                println!("    Inserting ssn {:?}", e);
-                self.values.push(e);
-            } else {
-                if let Some(socket) = &mut self.successor {
-                    self.successor_wrapper.send(socket, pdu.into());
-                    println!("    Forwarding val_insert to successor");
-                } else {
-                    panic!("Successor is not set, impossible!");
-                }
+        /// Where errors about a request go: its sender if it has one, or else
+        /// the originator of the route.
+        fn origin(&self, sender: Option<SocketAddr>) -> Option<SocketAddr> {
+            sender.or_else(|| self.route.as_ref().and_then(|r| r.origin))
+        }
+
+        /// Describes a request of type `request_type` that stores a value, for
+        /// the storage job that handles it.
+        fn request(&self, request_type: u8, sender: Option<SocketAddr>) -> Request {
+            Request {
+                request_type,
+                origin: self.origin(sender),
+                node: self.get_udp_addr(),
+                moved: self.version.is_some(),
             }
         }
 
-        fn handle_val_lookup(&mut self, pdu: ValLookupPdu) {
-            if self.in_my_range(&pdu.ssn) {
-                let mut found = false;
-                for entry in &self.values {
-                    if entry.ssn == pdu.ssn {
-                        let pdu_response = ValLookupResponsePdu::new(
-                            entry.ssn.clone(),
-                            entry.name.clone(),
-                            entry.email.clone(),
-                        );
-                        println!("    Value found (ssn: {}).", entry.ssn);
-                        let ip = Ipv4Addr::from(pdu.sender_address);
-                        let addr = SocketAddr::new(IpAddr::V4(ip), pdu.sender_port.into());
-                        self.udp_wrapper
-                            .send(&mut self.udp_socket, pdu_response.into(), addr);
-                        found = true;
-                        break;
-                    }
-                }
+        /// Wraps a value PDU in its trace context and hop limit before it is
+        /// forwarded, or drops it and notifies the originator if it is out of hops.
+        fn limit_hops(&mut self, pdu: PDU) -> Option<PDU> {
+            let (hops_left, origin) = match &self.route {
+                Some(r) => (r.hops_left, r.origin),
+                None => (self.config.hop_limit, None),
+            };
 
-                if !found {
-                    println!("    Value does not exist, responding with empty pdu");
-                    let pdu_response = ValLookupResponsePdu::new(
-                        "000000000000".into(),
-                        String::new(),
-                        String::new(),
-                    );
-                    let ip = Ipv4Addr::from(pdu.sender_address);
-                    let addr = SocketAddr::new(IpAddr::V4(ip), pdu.sender_port.into());
-                    self.udp_wrapper
-                        .send(&mut self.udp_socket, pdu_response.into(), addr);
-                }
-            } else {
-                if let Some(socket) = &mut self.successor {
-                    self.successor_wrapper.send(socket, pdu.into());
-                    println!("    Forwarding val_lookup to successor");
-                } else {
-                    panic!("Successor is not set, impossible!");
-                }
+            if hops_left == 0 {
+                warn!("Hop limit exceeded, dropping {:?}", pdu);
+                metrics::HOP_LIMIT_EXCEEDED.inc(&[pdu.name()]);
+                let message = format!("Hop limit exceeded at {}", self.get_udp_addr());
+                self.reply_error(&pdu, ERROR_HOP_LIMIT_EXCEEDED, message);
+                return None;
             }
+
+            let pdu = match self.version {
+                Some(v) => ValVersionPdu::new(v, pdu).into(),
+                None => pdu,
+            };
+            let pdu = match self.ttl {
+                Some(t) => ValTtlPdu::new(t, pdu).into(),
+                None => pdu,
+            };
+            let pdu = match &self.trace {
+                Some(t) => ValTracePdu::new(
+                    t.trace_id,
+                    t.hop_count,
+                    t.report_address,
+                    t.report_port,
+                    pdu,
+                )
+                .into(),
+                None => pdu,
+            };
+
+            let (address, port) = match origin {
+                Some(SocketAddr::V4(a)) => ((*a.ip()).into(), a.port()),
+                _ => (0, 0),
+            };
+            Some(ValHopLimitPdu::new(hops_left - 1, address, port, pdu).into())
         }
 
-        fn handle_val_remove(&mut self, pdu: ValRemovePdu) {
-            if self.in_my_range(&pdu.ssn) {
-                println!("    Removing ssn {}", pdu.ssn);
-                self.values.retain(|x| x.ssn != pdu.ssn);
-            } else {
-                if let Some(socket) = &mut self.successor {
-                    self.successor_wrapper.send(socket, pdu.into());
-                    println!("    Forwarding val_remove to successor");
-                } else {
-                    panic!("Successor is not set, impossible!");
-                }
+        fn in_my_range(&mut self, ssn: &str) -> bool {
+            self.owns_hash(self.hash_ssn(ssn))
+        }
+
+        fn hash_ssn(&self, ssn: &str) -> u64 {
+            hash_ssn(self.config.hasher.as_ref(), ssn)
+        }
+
+        /// Position of a key on the ring, ssns and key/value keys alike.
+        fn hash_key(&self, key: &[u8]) -> u64 {
+            hash_key(self.config.hasher.as_ref(), key)
+        }
+
+        /// Whether this node answers `pdu` rather than passing it on, for a
+        /// batch whether it stores any of its records.
+        fn handles(&self, pdu: &PDU) -> bool {
+            if let Some(ssn) = pdu.value_ssn() {
+                return self.owns_hash(self.hash_ssn(ssn));
+            }
+            if let Some(key) = pdu.value_key() {
+                return self.owns_hash(self.hash_key(key));
+            }
+            match pdu {
+                PDU::ValScan(p) => self.owns_hash(p.range_start),
+                PDU::ValSubscribe(p) => self.owns_hash(p.range_start),
+                PDU::ValQuery(p) => self.owns_hash(p.range_start),
+                PDU::ValBatch(p) => p.entries.iter().any(|e| self.handles(e)),
+                _ => false,
             }
         }
 
-        fn in_my_range(&mut self, ssn: &String) -> bool {
-            let (min, max) = self.hash_range;
-            let ssn_hash = Entry::hash_ssn(&ssn);
-            min <= ssn_hash && ssn_hash <= max
+        fn owns_hash(&self, hash: u64) -> bool {
+            self.ranges
+                .iter()
+                .any(|&(min, max)| min <= hash && hash <= max)
         }
 
         fn connect_to_successor(&mut self, addr: SocketAddr) -> TcpStream {
             let socket = std::net::TcpStream::connect(addr).expect("Failed to connect to successor");
             self.successor_listen = Some(addr);
 
-            println!(
-                "    Connected to new successor {:?}",
+            info!(
+                "Connected to new successor {:?}",
                 socket.peer_addr().unwrap()
             );
             socket.set_nonblocking(true).expect("Failed to set socket non-blocking");
@@ -1,6 +1,7 @@
 use std::net::SocketAddr;
 use structopt::StructOpt;
 
+use ou2::metrics::Metric;
 use ou2::pdu::PDU::*;
 use ou2::pdu::*;
 use ou2::socket_wrapper::UdpWrapper;
@@ -12,6 +13,8 @@ use std::collections::HashMap;
 use std::time;
 use time::Instant;
 
+use tracing::{debug, info, warn};
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "Node")]
 struct Opt {
@@ -21,8 +24,23 @@ struct Opt {
     /// Tracker node timeout (in seconds)
     #[structopt(long, short, default_value = "30")]
     timeout: u64,
+
+    /// Write log output as JSON, one object per line
+    #[structopt(long)]
+    log_json: bool,
+
+    /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9100
+    #[structopt(long)]
+    metrics: Option<SocketAddr>,
 }
 
+static NODES: Metric = Metric::gauge("dht_tracker_nodes", "Nodes known to be alive", &[]);
+static DROPPED: Metric = Metric::counter(
+    "dht_tracker_dropped_nodes_total",
+    "Nodes dropped due to inactivity",
+    &[],
+);
+
 struct Node {
     pub last_alive: Instant,
 }
@@ -37,11 +55,15 @@ impl Node {
 
 fn main() {
     let opt = Opt::from_args();
+    ou2::logging::init(opt.log_json);
+    if let Some(addr) = opt.metrics {
+        ou2::metrics::serve(addr).expect("Failed to start metrics endpoint");
+    }
 
     let mut socket =
         UdpSocket::bind(format!("0.0.0.0:{}", opt.tracker_port).parse().unwrap()).unwrap();
     let mut nodes = HashMap::new();
-    println!("Tracker listening on {:?}", socket.local_addr().unwrap());
+    info!("Tracker listening on {:?}", socket.local_addr().unwrap());
     let mut poll = Poll::new().unwrap();
     let mut events = Events::with_capacity(1);
     let timeout = std::time::Duration::from_secs(5);
@@ -57,7 +79,7 @@ fn main() {
             while let Some((pdu, sender)) = wrapper.next_pdu() {
                 match pdu {
                     StunLookup(_) => {
-                        println!("Got STUN_LOOKUP from {:?}", sender);
+                        debug!("Got STUN_LOOKUP from {:?}", sender);
                         if let SocketAddr::V4(s) = sender {
                             let r = StunResponsePdu::new((*s.ip()).into());
                             wrapper.send(&mut socket, r.into(), sender);
@@ -66,21 +88,21 @@ fn main() {
                         }
                     }
                     NetAlive(_) => {
-                        println!("Got NET_ALIVE from {:?}", sender);
+                        debug!("Got NET_ALIVE from {:?}", sender);
                         let node = nodes.entry(sender).or_insert(Node::new());
                         node.last_alive = Instant::now();
                     }
                     NetGetNode(_) => {
-                        println!("Got NET_GET_NODE from {:?}", sender);
+                        debug!("Got NET_GET_NODE from {:?}", sender);
 
                         let r = if nodes.is_empty() {
-                            println!("    No nodes connected. Giving empty response.");
+                            info!("No nodes connected. Giving empty response.");
                             NetGetNodeResponsePdu::new(0, 0)
                         } else {
-                            println!("    {} nodes connected.", nodes.len());
+                            debug!("{} nodes connected.", nodes.len());
                             let (k, _) = nodes.iter().next().unwrap();
                             if let SocketAddr::V4(k) = k {
-                                println!("    Responding with {:?}", k);
+                                debug!("Responding with {:?}", k);
                                 NetGetNodeResponsePdu::new((*k.ip()).into(), k.port())
                             } else {
                                 panic!("Somehow i got contacted over IPV6");
@@ -90,7 +112,7 @@ fn main() {
                         wrapper.send(&mut socket, r.into(), sender);
                     }
                     _ => {
-                        println!("What did I just receive??");
+                        warn!("What did I just receive?? {:?} from {:?}", pdu, sender);
                     }
                 }
             }
@@ -98,11 +120,13 @@ fn main() {
 
         nodes.retain(|&k, v| {
             if v.last_alive.elapsed().as_secs() >= opt.timeout {
-                println!("Dropping {:?} due to inactivity.", k);
+                info!("Dropping {:?} due to inactivity.", k);
+                DROPPED.inc(&[]);
                 false
             } else {
                 true
             }
         });
+        NODES.set(&[], nodes.len() as i64);
     }
 }
@@ -0,0 +1,156 @@
+use std::convert::TryInto;
+
+/// Places keys on the ring, which is the positions `0..=max_hash()`.
+///
+/// Every node and client in a network must use the same hasher, see
+/// [`by_name`].
+pub trait KeyHasher: Send + Sync {
+    /// Position of `key` on the ring.
+    fn hash(&self, key: &[u8]) -> u64;
+
+    /// The last position on the ring.
+    fn max_hash(&self) -> u64;
+}
+
+/// Names of the hashers that [`by_name`] knows.
+pub const HASHERS: &[&str] = &["djb2", "sip32", "sip64"];
+
+/// Returns the hasher called `name`, one of [`HASHERS`].
+pub fn by_name(name: &str) -> Option<Box<dyn KeyHasher>> {
+    match name {
+        "djb2" => Some(Box::new(Djb2)),
+        "sip32" => Some(Box::new(SipHash::new(32))),
+        "sip64" => Some(Box::new(SipHash::new(64))),
+        _ => None,
+    }
+}
+
+/// The djb2 hash reduced to a ring of 256 positions, as in the specification.
+pub struct Djb2;
+
+impl KeyHasher for Djb2 {
+    fn hash(&self, key: &[u8]) -> u64 {
+        let mut hash: u32 = 5381;
+        for &b in key {
+            hash = (hash << 5).wrapping_add(hash).wrapping_add(b.into());
+        }
+
+        (hash % 256).into()
+    }
+
+    fn max_hash(&self) -> u64 {
+        u8::MAX.into()
+    }
+}
+
+/// SipHash-2-4 with a fixed key, reduced to a ring of `2^bits` positions.
+/// Unlike djb2 it spreads keys that differ in a few digits, like ssns born
+/// on the same day, evenly over the ring.
+pub struct SipHash {
+    max: u64,
+}
+
+impl SipHash {
+    /// The key is fixed since every node must place keys alike. It is not a
+    /// secret, so the hash does not protect against chosen keys.
+    const KEY: (u64, u64) = (0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908);
+
+    pub fn new(bits: u32) -> Self {
+        assert!((1..=64).contains(&bits), "A ring needs 1 to 64 bits");
+        SipHash {
+            max: u64::MAX >> (64 - bits),
+        }
+    }
+
+    fn sip_2_4(key: (u64, u64), data: &[u8]) -> u64 {
+        let mut v = [
+            key.0 ^ 0x736f_6d65_7073_6575,
+            key.1 ^ 0x646f_7261_6e64_6f6d,
+            key.0 ^ 0x6c79_6765_6e65_7261,
+            key.1 ^ 0x7465_6462_7974_6573,
+        ];
+
+        let mut chunks = data.chunks_exact(8);
+        for chunk in &mut chunks {
+            let m = u64::from_le_bytes(chunk.try_into().unwrap());
+            v[3] ^= m;
+            Self::rounds(&mut v, 2);
+            v[0] ^= m;
+        }
+
+        let mut last = [0; 8];
+        last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+        let m = u64::from_le_bytes(last) | (data.len() as u64) << 56;
+        v[3] ^= m;
+        Self::rounds(&mut v, 2);
+        v[0] ^= m;
+
+        v[2] ^= 0xff;
+        Self::rounds(&mut v, 4);
+        v[0] ^ v[1] ^ v[2] ^ v[3]
+    }
+
+    fn rounds(v: &mut [u64; 4], count: usize) {
+        for _ in 0..count {
+            v[0] = v[0].wrapping_add(v[1]);
+            v[1] = v[1].rotate_left(13) ^ v[0];
+            v[0] = v[0].rotate_left(32);
+            v[2] = v[2].wrapping_add(v[3]);
+            v[3] = v[3].rotate_left(16) ^ v[2];
+            v[0] = v[0].wrapping_add(v[3]);
+            v[3] = v[3].rotate_left(21) ^ v[0];
+            v[2] = v[2].wrapping_add(v[1]);
+            v[1] = v[1].rotate_left(17) ^ v[2];
+            v[2] = v[2].rotate_left(32);
+        }
+    }
+}
+
+impl KeyHasher for SipHash {
+    fn hash(&self, key: &[u8]) -> u64 {
+        Self::sip_2_4(Self::KEY, key) & self.max
+    }
+
+    fn max_hash(&self) -> u64 {
+        self.max
+    }
+}
+
+#[cfg(test)]
+mod hash_test {
+    use crate::hash::*;
+
+    #[test]
+    fn test_djb2() {
+        assert_eq!(Djb2.hash(b"aaaaabbbbbcc"), 26);
+        assert_eq!(Djb2.max_hash(), 255);
+    }
+
+    #[test]
+    fn test_siphash() {
+        // Test vectors from the SipHash paper, for the messages 00, 00 01, ...
+        let message: Vec<u8> = (0..15).collect();
+        assert_eq!(SipHash::sip_2_4(SipHash::KEY, &[]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(
+            SipHash::sip_2_4(SipHash::KEY, &message[..8]),
+            0x93f5_f579_9a93_2462
+        );
+        assert_eq!(
+            SipHash::sip_2_4(SipHash::KEY, &message),
+            0xa129_ca61_49be_45e5
+        );
+
+        let sip32 = SipHash::new(32);
+        assert_eq!(sip32.max_hash(), u64::from(u32::MAX));
+        assert!(sip32.hash(b"197223058909") <= sip32.max_hash());
+        assert_eq!(SipHash::new(64).max_hash(), u64::MAX);
+    }
+
+    #[test]
+    fn test_by_name() {
+        for name in HASHERS {
+            assert!(by_name(name).is_some());
+        }
+        assert!(by_name("md5").is_none());
+    }
+}
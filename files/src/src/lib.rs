@@ -1,2 +1,5 @@
+pub mod hash;
+pub mod logging;
+pub mod metrics;
 pub mod pdu;
 pub mod socket_wrapper;
@@ -0,0 +1,24 @@
+use tracing_subscriber::EnvFilter;
+
+/// Default filter used when `RUST_LOG` is not set.
+const DEFAULT_FILTER: &str = "info";
+
+/// Installs the global `tracing` subscriber.
+///
+/// The log level is controlled per module through `RUST_LOG`, e.g.
+/// `RUST_LOG=node=debug,ou2::socket_wrapper=trace`. If `json` is set, one
+/// JSON object is written per event instead of the human readable format.
+pub fn init(json: bool) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    if json {
+        builder.json().with_current_span(true).init();
+    } else {
+        builder.init();
+    }
+}
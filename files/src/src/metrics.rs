@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Mutex;
+use std::thread;
+
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        }
+    }
+}
+
+/// A named metric with a fixed set of label names.
+///
+/// Metrics are declared as statics and all values are kept in one process
+/// wide registry, which is rendered in the Prometheus text format by
+/// [`render`].
+pub struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    labels: &'static [&'static str],
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    labels: &'static [&'static str],
+    values: BTreeMap<Vec<String>, i64>,
+}
+
+static REGISTRY: Mutex<BTreeMap<&'static str, Family>> = Mutex::new(BTreeMap::new());
+
+impl Metric {
+    pub const fn counter(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+    ) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Counter,
+            labels,
+        }
+    }
+
+    pub const fn gauge(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+    ) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Gauge,
+            labels,
+        }
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        self.add(labels, 1);
+    }
+
+    pub fn add(&self, labels: &[&str], value: u64) {
+        self.update(labels, |v| *v += value as i64);
+    }
+
+    pub fn set(&self, labels: &[&str], value: i64) {
+        debug_assert!(self.kind == Kind::Gauge, "{} is not a gauge", self.name);
+        self.update(labels, |v| *v = value);
+    }
+
+    fn update<F: FnOnce(&mut i64)>(&self, labels: &[&str], f: F) {
+        debug_assert_eq!(labels.len(), self.labels.len(), "{}", self.name);
+        let mut registry = REGISTRY.lock().unwrap();
+        let family = registry.entry(self.name).or_insert_with(|| Family {
+            help: self.help,
+            kind: self.kind,
+            labels: self.labels,
+            values: BTreeMap::new(),
+        });
+        let key = labels.iter().map(|&l| l.to_owned()).collect();
+        f(family.values.entry(key).or_insert(0));
+    }
+}
+
+/// PDUs parsed from a socket, by wrapper and PDU type.
+pub static PDUS_RECEIVED: Metric = Metric::counter(
+    "dht_pdus_received_total",
+    "PDUs received",
+    &["wrapper", "type"],
+);
+
+/// PDUs written to a socket, by wrapper and PDU type.
+pub static PDUS_SENT: Metric =
+    Metric::counter("dht_pdus_sent_total", "PDUs sent", &["wrapper", "type"]);
+
+pub static BYTES_RECEIVED: Metric = Metric::counter(
+    "dht_bytes_received_total",
+    "Bytes read from a socket",
+    &["wrapper"],
+);
+
+pub static BYTES_SENT: Metric = Metric::counter(
+    "dht_bytes_sent_total",
+    "Bytes written to a socket",
+    &["wrapper"],
+);
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+
+    for (name, family) in registry.iter() {
+        writeln!(out, "# HELP {} {}", name, family.help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, family.kind.as_str()).unwrap();
+        for (values, value) in &family.values {
+            out.push_str(name);
+            if !values.is_empty() {
+                let labels: Vec<String> = family
+                    .labels
+                    .iter()
+                    .zip(values)
+                    .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                    .collect();
+                write!(out, "{{{}}}", labels.join(",")).unwrap();
+            }
+            writeln!(out, " {}", value).unwrap();
+        }
+    }
+
+    out
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves `GET /metrics` on `addr` from a background thread.
+pub fn serve(addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    if let Err(e) = respond(&mut stream) {
+                        warn!("Failed to serve metrics request: {}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to accept metrics connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn respond(stream: &mut std::net::TcpStream) -> std::io::Result<()> {
+    let mut buffer = [0; 1024];
+    let amt = stream.read(&mut buffer)?;
+    let request = String::from_utf8_lossy(&buffer[..amt]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", render())
+    } else {
+        ("404 Not Found", String::from("Not found\n"))
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod metrics_test {
+    use crate::metrics::*;
+
+    static TEST_COUNTER: Metric = Metric::counter("test_counter_total", "Test", &["kind"]);
+    static TEST_GAUGE: Metric = Metric::gauge("test_gauge", "Test", &[]);
+
+    #[test]
+    fn test_render() {
+        TEST_COUNTER.inc(&["a"]);
+        TEST_COUNTER.add(&["a"], 2);
+        TEST_GAUGE.set(&[], -4);
+
+        let out = render();
+        assert!(out.contains("# TYPE test_counter_total counter\n"));
+        assert!(out.contains("test_counter_total{kind=\"a\"} 3\n"));
+        assert!(out.contains("# TYPE test_gauge gauge\n"));
+        assert!(out.contains("test_gauge -4\n"));
+    }
+}
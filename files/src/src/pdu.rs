@@ -11,34 +11,231 @@ pub const NET_CLOSE_CONNECTION_ID: u8 = 5;
 pub const NET_NEW_RANGE_ID: u8 = 6;
 pub const NET_LEAVING_ID: u8 = 7;
 pub const NET_NEW_RANGE_RESPONSE_ID: u8 = 8;
+pub const NET_JOIN_WIDE_ID: u8 = 9;
+pub const NET_JOIN_RESPONSE_WIDE_ID: u8 = 10;
+pub const NET_NEW_RANGE_WIDE_ID: u8 = 11;
+pub const NET_ADD_RANGE_ID: u8 = 12;
+pub const NET_REBALANCE_ID: u8 = 13;
+pub const NET_CONTROL_ID: u8 = 14;
 
 pub const VAL_INSERT_ID: u8 = 100;
 pub const VAL_REMOVE_ID: u8 = 101;
 pub const VAL_LOOKUP_ID: u8 = 102;
 pub const VAL_LOOKUP_RESPONSE_ID: u8 = 103;
+pub const VAL_TRACE_ID: u8 = 104;
+pub const VAL_TRACE_HOP_ID: u8 = 105;
+pub const VAL_HOP_LIMIT_ID: u8 = 106;
+pub const VAL_VERSION_ID: u8 = 107;
+pub const VAL_COND_INSERT_ID: u8 = 108;
+pub const VAL_COND_REMOVE_ID: u8 = 109;
+pub const VAL_COND_RESPONSE_ID: u8 = 110;
+pub const VAL_TTL_ID: u8 = 111;
+pub const VAL_SCAN_ID: u8 = 112;
+pub const VAL_SCAN_RESPONSE_ID: u8 = 113;
+pub const VAL_BATCH_ID: u8 = 114;
+pub const VAL_SUBSCRIBE_ID: u8 = 115;
+pub const VAL_NOTIFY_ID: u8 = 116;
+pub const VAL_QUERY_ID: u8 = 117;
+pub const VAL_QUERY_RESPONSE_ID: u8 = 118;
+pub const VAL_KV_PUT_ID: u8 = 119;
+pub const VAL_KV_DELETE_ID: u8 = 120;
+pub const VAL_KV_GET_ID: u8 = 121;
+pub const VAL_KV_GET_RESPONSE_ID: u8 = 122;
+pub const VAL_INSERT_WIDE_ID: u8 = 123;
+pub const VAL_LOOKUP_RESPONSE_WIDE_ID: u8 = 124;
+pub const VAL_LOOKUP_VERSIONED_ID: u8 = 125;
+pub const VAL_LOOKUP_RESPONSE_VERSIONED_ID: u8 = 126;
 
 pub const STUN_LOOKUP_ID: u8 = 200;
 pub const STUN_RESPONSE_ID: u8 = 201;
 
+pub const ERROR_ID: u8 = 250;
+
+/// Most entries in one `ValScanResponsePdu`, which keeps it within one datagram.
+pub const MAX_SCAN_ENTRIES: u8 = 32;
+/// Most entries in one `ValBatchPdu`. Even with the longest names and emails
+/// the batch fits in one datagram and in the receive buffer of a wrapper.
+pub const MAX_BATCH_ENTRIES: u8 = 40;
+/// Longest lease in seconds a node grants a subscription, longer ones are cut.
+pub const MAX_LEASE: u32 = 3600;
+
+/// Commands a `NetControlPdu` can give a node.
+pub const CONTROL_LEAVE: u8 = 0;
+pub const CONTROL_HANDOFF_SUCCESSOR: u8 = 1;
+pub const CONTROL_HANDOFF_PREDECESSOR: u8 = 2;
+
+/// Fields a `ValQueryPdu` can search.
+pub const QUERY_FIELD_EMAIL: u8 = 0;
+pub const QUERY_FIELD_NAME: u8 = 1;
+
+/// Longest name or email in bytes. Longer than 255 bytes needs the wide
+/// variants of the insert and lookup response.
+pub const MAX_TEXT_LENGTH: usize = 4096;
+/// Most bytes of entries in one scan response, query response or batch, which
+/// keeps the PDU within one datagram and the receive buffer of a wrapper.
+pub const MAX_ENTRIES_SIZE: usize = 16 * 1024;
+
+/// Most bytes of key and value together in one key/value entry, which keeps a
+/// `ValKvPutPdu` within one datagram and the receive buffer of a wrapper.
+pub const MAX_KV_SIZE: usize = 16 * 1024;
+
+/// A forwarded request ran out of hops, most likely due to a routing loop.
+pub const ERROR_HOP_LIMIT_EXCEEDED: u8 = 1;
+/// The request had to be forwarded but the node has no successor.
+pub const ERROR_NO_SUCCESSOR: u8 = 2;
+/// The SSN of the request is not 12 digits.
+pub const ERROR_MALFORMED_SSN: u8 = 3;
+/// The responsible node has no room for another entry.
+pub const ERROR_STORAGE_FULL: u8 = 4;
+/// The node does not accept the PDU in its current state.
+pub const ERROR_INVALID_STATE: u8 = 5;
+/// The key and value are larger than `MAX_KV_SIZE`, or a name or email is
+/// longer than `MAX_TEXT_LENGTH`.
+pub const ERROR_TOO_LARGE: u8 = 6;
+/// A control PDU was not signed with the control key of the node, was too
+/// old or already used, or the node takes no control PDUs.
+pub const ERROR_UNAUTHORIZED: u8 = 7;
+/// The PDU could not be parsed, see `ParseError`.
+pub const ERROR_INVALID_PDU: u8 = 8;
+
 const NET_ALIVE_SIZE: usize = 1;
 const NET_GET_NODE_SIZE: usize = 1;
 const NET_GET_NODE_RESPONSE_SIZE: usize = 1 + 4 + 2;
 const NET_JOIN_SIZE: usize = 1 + 4 + 2 + 1 + 4 + 2;
+const NET_JOIN_WIDE_SIZE: usize = 1 + 4 + 2 + 8 + 4 + 2;
 const NET_JOIN_RESPONSE_SIZE: usize = 1 + 4 + 2 + 1 + 1;
+const NET_JOIN_RESPONSE_WIDE_SIZE: usize = 1 + 4 + 2 + 8 + 8;
 const NET_CLOSE_CONNECTION_SIZE: usize = 1;
 
 const NET_NEW_RANGE_SIZE: usize = 1 + 1 + 1;
+const NET_NEW_RANGE_WIDE_SIZE: usize = 1 + 8 + 8;
 const NET_NEW_RANGE_RESPONSE_SIZE: usize = 1;
 const NET_LEAVING_SIZE: usize = 1 + 4 + 2;
+const NET_ADD_RANGE_SIZE: usize = 1 + 8 + 8;
+const NET_REBALANCE_SIZE: usize = 1 + 8;
+const NET_CONTROL_SIZE: usize = 1 + 1 + 4 + 2 + 8 + 32;
 
 const VAL_REMOVE_SIZE: usize = 1 + SSN_LENGTH;
 const VAL_LOOKUP_SIZE: usize = 1 + SSN_LENGTH + 4 + 2;
+const VAL_TRACE_HEADER_SIZE: usize = 1 + 8 + 1 + 4 + 2;
+const VAL_TRACE_HOP_SIZE: usize = 1 + 8 + 1 + 4 + 2;
+const VAL_HOP_LIMIT_HEADER_SIZE: usize = 1 + 1 + 4 + 2;
+const VAL_VERSION_HEADER_SIZE: usize = 1 + 8;
+const VAL_COND_REMOVE_SIZE: usize = 1 + SSN_LENGTH + 8 + 4 + 2;
+const VAL_COND_RESPONSE_SIZE: usize = 1 + 1 + 1 + SSN_LENGTH + 8;
+const VAL_TTL_HEADER_SIZE: usize = 1 + 4;
+const VAL_SCAN_SIZE: usize = 1 + 8 + 8 + 1 + SSN_LENGTH + 4 + 2;
+const VAL_SCAN_RESPONSE_HEADER_SIZE: usize = 1 + 8 + 8 + 1 + SSN_LENGTH + 1;
+const VAL_BATCH_HEADER_SIZE: usize = 1 + 1;
+const VAL_SUBSCRIBE_SIZE: usize = 1 + 8 + 8 + SSN_LENGTH + 4 + 4 + 2;
+const VAL_NOTIFY_HEADER_SIZE: usize = 1 + 1 + SSN_LENGTH + 8;
+const VAL_QUERY_HEADER_SIZE: usize = 1 + 1 + 8 + 4 + 2 + 2;
+const VAL_QUERY_RESPONSE_HEADER_SIZE: usize = 1 + 8 + 8 + 1 + 1 + 1;
+const VAL_KV_PUT_HEADER_SIZE: usize = 1 + 2 + 4;
+const VAL_KV_DELETE_HEADER_SIZE: usize = 1 + 2;
+const VAL_KV_GET_HEADER_SIZE: usize = 1 + 4 + 2 + 2;
+const VAL_KV_GET_RESPONSE_HEADER_SIZE: usize = 1 + 1 + 8 + 2 + 4;
 
 const STUN_LOOKUP_SIZE: usize = 1;
 const STUN_RESPONSE_SIZE: usize = 1 + 4;
 
+const ERROR_HEADER_SIZE: usize = 1 + 1 + 1 + 1;
+
 const SSN_LENGTH: usize = 12;
 
+/// A valid ssn, which is a Swedish personnummer with the century: 12 digits,
+/// YYYYMMDDNNNC, where C is a Luhn check digit.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Ssn(String);
+
+/// Why a string is not an `Ssn`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsnError {
+    /// Something other than digits, or a misplaced separator.
+    NotDigits,
+    /// Digits, but not 12 of them.
+    Length(usize),
+    /// The last digit is not the Luhn check digit of the others.
+    Checksum,
+}
+
+impl Ssn {
+    /// Parses 12 digits, which may also be written as `YYYYMMDD-NNNC`.
+    /// Surrounding whitespace is ignored.
+    pub fn parse(s: &str) -> Result<Ssn, SsnError> {
+        let s = s.trim();
+        let digits = match s.get(8..9) {
+            Some("-") => [&s[..8], &s[9..]].concat(),
+            _ => s.to_owned(),
+        };
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(SsnError::NotDigits);
+        }
+        if digits.len() != SSN_LENGTH {
+            return Err(SsnError::Length(digits.len()));
+        }
+        Ok(Ssn(digits))
+    }
+
+    /// Like `parse`, and if `luhn` is set also requires a valid check digit.
+    pub fn parse_checked(s: &str, luhn: bool) -> Result<Ssn, SsnError> {
+        let ssn = Ssn::parse(s)?;
+        match luhn && !ssn.luhn_valid() {
+            true => Err(SsnError::Checksum),
+            false => Ok(ssn),
+        }
+    }
+
+    /// Whether the last digit is the Luhn check digit of the nine before it.
+    /// The century is not part of the check.
+    pub fn luhn_valid(&self) -> bool {
+        let digits: Vec<u32> = self.0.bytes().skip(2).map(|b| (b - b'0') as u32).collect();
+        let sum: u32 = digits[..9]
+            .iter()
+            .enumerate()
+            .map(|(i, &d)| if i % 2 == 0 { d * 2 } else { d })
+            .map(|d| d / 10 + d % 10)
+            .sum();
+        (10 - sum % 10) % 10 == digits[9]
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for Ssn {
+    type Err = SsnError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ssn::parse(s)
+    }
+}
+
+impl std::fmt::Display for Ssn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<Ssn> for String {
+    fn from(ssn: Ssn) -> Self {
+        ssn.0
+    }
+}
+
+impl std::fmt::Display for SsnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SsnError::NotDigits => write!(f, "is not 12 digits"),
+            SsnError::Length(n) => write!(f, "has {} digits, not 12", n),
+            SsnError::Checksum => write!(f, "fails the Luhn check"),
+        }
+    }
+}
+
+impl std::error::Error for SsnError {}
+
 pub enum PDU {
     NetAlive(NetAlivePdu),
     NetGetNode(NetGetNodePdu),
@@ -49,17 +246,46 @@ pub enum PDU {
     NetNewRange(NetNewRangePdu),
     NetNewRangeResponse(NetNewRangeResponsePdu),
     NetLeaving(NetLeavingPdu),
+    NetAddRange(NetAddRangePdu),
+    NetRebalance(NetRebalancePdu),
+    NetControl(NetControlPdu),
     ValInsert(ValInsertPdu),
     ValRemove(ValRemovePdu),
     ValLookup(ValLookupPdu),
     ValLookupResponse(ValLookupResponsePdu),
+    ValTrace(ValTracePdu),
+    ValTraceHop(ValTraceHopPdu),
+    ValHopLimit(ValHopLimitPdu),
+    ValVersion(ValVersionPdu),
+    ValCondInsert(ValCondInsertPdu),
+    ValCondRemove(ValCondRemovePdu),
+    ValCondResponse(ValCondResponsePdu),
+    ValTtl(ValTtlPdu),
+    ValScan(ValScanPdu),
+    ValScanResponse(ValScanResponsePdu),
+    ValBatch(ValBatchPdu),
+    ValSubscribe(ValSubscribePdu),
+    ValNotify(ValNotifyPdu),
+    ValQuery(ValQueryPdu),
+    ValQueryResponse(ValQueryResponsePdu),
+    ValKvPut(ValKvPutPdu),
+    ValKvDelete(ValKvDeletePdu),
+    ValKvGet(ValKvGetPdu),
+    ValKvGetResponse(ValKvGetResponsePdu),
     StunLookup(StunLookupPdu),
     StunResponse(StunResponsePdu),
+    Error(ErrorPdu),
 }
 
 impl std::fmt::Debug for PDU {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct(match self {
+        f.debug_struct(self.name()).finish()
+    }
+}
+
+impl PDU {
+    pub fn name(&self) -> &'static str {
+        match self {
             Self::NetAlive(_) => "NetAlive",
             Self::NetGetNode(_) => "NetGetNode",
             Self::NetGetNodeResponse(_) => "NetGetNodeResponse",
@@ -69,18 +295,131 @@ impl std::fmt::Debug for PDU {
             Self::NetNewRange(_) => "NetNewRange",
             Self::NetNewRangeResponse(_) => "NetNewRangeResponse",
             Self::NetLeaving(_) => "NetLeaving",
+            Self::NetAddRange(_) => "NetAddRange",
+            Self::NetRebalance(_) => "NetRebalance",
+            Self::NetControl(_) => "NetControl",
             Self::ValInsert(_) => "ValInsert",
             Self::ValRemove(_) => "ValRemove",
             Self::ValLookup(_) => "ValLookup",
             Self::ValLookupResponse(_) => "ValLookupResponse",
+            Self::ValTrace(_) => "ValTrace",
+            Self::ValTraceHop(_) => "ValTraceHop",
+            Self::ValHopLimit(_) => "ValHopLimit",
+            Self::ValVersion(_) => "ValVersion",
+            Self::ValCondInsert(_) => "ValCondInsert",
+            Self::ValCondRemove(_) => "ValCondRemove",
+            Self::ValCondResponse(_) => "ValCondResponse",
+            Self::ValTtl(_) => "ValTtl",
+            Self::ValScan(_) => "ValScan",
+            Self::ValScanResponse(_) => "ValScanResponse",
+            Self::ValBatch(_) => "ValBatch",
+            Self::ValSubscribe(_) => "ValSubscribe",
+            Self::ValNotify(_) => "ValNotify",
+            Self::ValQuery(_) => "ValQuery",
+            Self::ValQueryResponse(_) => "ValQueryResponse",
+            Self::ValKvPut(_) => "ValKvPut",
+            Self::ValKvDelete(_) => "ValKvDelete",
+            Self::ValKvGet(_) => "ValKvGet",
+            Self::ValKvGetResponse(_) => "ValKvGetResponse",
             Self::StunLookup(_) => "StunLookup",
             Self::StunResponse(_) => "StunResponse",
-        })
-        .finish()
+            Self::Error(_) => "Error",
+        }
+    }
+
+    pub fn pdu_type(&self) -> u8 {
+        match self {
+            Self::NetAlive(p) => p.pdu_type,
+            Self::NetGetNode(p) => p.pdu_type,
+            Self::NetGetNodeResponse(p) => p.pdu_type,
+            Self::NetJoin(p) => p.pdu_type,
+            Self::NetJoinResponse(p) => p.pdu_type,
+            Self::NetCloseConnection(p) => p.pdu_type,
+            Self::NetNewRange(p) => p.pdu_type,
+            Self::NetNewRangeResponse(p) => p.pdu_type,
+            Self::NetLeaving(p) => p.pdu_type,
+            Self::NetAddRange(p) => p.pdu_type,
+            Self::NetRebalance(p) => p.pdu_type,
+            Self::NetControl(p) => p.pdu_type,
+            Self::ValInsert(p) => p.pdu_type,
+            Self::ValRemove(p) => p.pdu_type,
+            Self::ValLookup(p) => p.pdu_type,
+            Self::ValLookupResponse(p) => p.pdu_type,
+            Self::ValTrace(p) => p.pdu_type,
+            Self::ValTraceHop(p) => p.pdu_type,
+            Self::ValHopLimit(p) => p.pdu_type,
+            Self::ValVersion(p) => p.pdu_type,
+            Self::ValCondInsert(p) => p.pdu_type,
+            Self::ValCondRemove(p) => p.pdu_type,
+            Self::ValCondResponse(p) => p.pdu_type,
+            Self::ValTtl(p) => p.pdu_type,
+            Self::ValScan(p) => p.pdu_type,
+            Self::ValScanResponse(p) => p.pdu_type,
+            Self::ValBatch(p) => p.pdu_type,
+            Self::ValSubscribe(p) => p.pdu_type,
+            Self::ValNotify(p) => p.pdu_type,
+            Self::ValQuery(p) => p.pdu_type,
+            Self::ValQueryResponse(p) => p.pdu_type,
+            Self::ValKvPut(p) => p.pdu_type,
+            Self::ValKvDelete(p) => p.pdu_type,
+            Self::ValKvGet(p) => p.pdu_type,
+            Self::ValKvGetResponse(p) => p.pdu_type,
+            Self::StunLookup(p) => p.pdu_type,
+            Self::StunResponse(p) => p.pdu_type,
+            Self::Error(p) => p.pdu_type,
+        }
+    }
+
+    /// The ssn of a value request, which is routed to the node responsible
+    /// for the ssn. `None` for all other PDUs.
+    pub fn value_ssn(&self) -> Option<&str> {
+        match self {
+            Self::ValInsert(p) => Some(&p.ssn),
+            Self::ValRemove(p) => Some(&p.ssn),
+            Self::ValLookup(p) => Some(&p.ssn),
+            Self::ValCondInsert(p) => Some(&p.ssn),
+            Self::ValCondRemove(p) => Some(&p.ssn),
+            Self::ValSubscribe(p) => p.ssn.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The key of a key/value request, which decides the node that handles it.
+    pub fn value_key(&self) -> Option<&[u8]> {
+        match self {
+            Self::ValKvPut(p) => Some(&p.key),
+            Self::ValKvDelete(p) => Some(&p.key),
+            Self::ValKvGet(p) => Some(&p.key),
+            _ => None,
+        }
+    }
+
+    /// Whether the PDU is a value request, which is routed through the ring to
+    /// the node responsible for its ssn, key or hash.
+    pub fn is_value_request(&self) -> bool {
+        self.value_ssn().is_some()
+            || self.value_key().is_some()
+            || matches!(
+                self,
+                Self::ValScan(_) | Self::ValBatch(_) | Self::ValSubscribe(_) | Self::ValQuery(_)
+            )
+    }
+
+    /// Where the response to a value request is to be sent, for the requests
+    /// that have a response.
+    pub fn sender_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::ValLookup(p) => Some(p.get_sender_addr()),
+            Self::ValScan(p) => Some(p.get_sender_addr()),
+            Self::ValCondInsert(p) => Some(p.get_sender_addr()),
+            Self::ValCondRemove(p) => Some(p.get_sender_addr()),
+            Self::ValSubscribe(p) => Some(p.get_sender_addr()),
+            Self::ValQuery(p) => Some(p.get_sender_addr()),
+            Self::ValKvGet(p) => Some(p.get_sender_addr()),
+            _ => None,
+        }
     }
-}
 
-impl PDU {
     pub fn to_bytes(self) -> Vec<u8> {
         match self {
             Self::NetAlive(p) => Vec::from(p),
@@ -92,16 +431,50 @@ impl PDU {
             Self::NetNewRange(p) => Vec::from(p),
             Self::NetNewRangeResponse(p) => Vec::from(p),
             Self::NetLeaving(p) => Vec::from(p),
+            Self::NetAddRange(p) => Vec::from(p),
+            Self::NetRebalance(p) => Vec::from(p),
+            Self::NetControl(p) => Vec::from(p),
             Self::ValInsert(p) => Vec::from(p),
             Self::ValRemove(p) => Vec::from(p),
             Self::ValLookup(p) => Vec::from(p),
             Self::ValLookupResponse(p) => Vec::from(p),
+            Self::ValTrace(p) => Vec::from(p),
+            Self::ValTraceHop(p) => Vec::from(p),
+            Self::ValHopLimit(p) => Vec::from(p),
+            Self::ValVersion(p) => Vec::from(p),
+            Self::ValCondInsert(p) => Vec::from(p),
+            Self::ValCondRemove(p) => Vec::from(p),
+            Self::ValCondResponse(p) => Vec::from(p),
+            Self::ValTtl(p) => Vec::from(p),
+            Self::ValScan(p) => Vec::from(p),
+            Self::ValScanResponse(p) => Vec::from(p),
+            Self::ValBatch(p) => Vec::from(p),
+            Self::ValSubscribe(p) => Vec::from(p),
+            Self::ValNotify(p) => Vec::from(p),
+            Self::ValQuery(p) => Vec::from(p),
+            Self::ValQueryResponse(p) => Vec::from(p),
+            Self::ValKvPut(p) => Vec::from(p),
+            Self::ValKvDelete(p) => Vec::from(p),
+            Self::ValKvGet(p) => Vec::from(p),
+            Self::ValKvGetResponse(p) => Vec::from(p),
             Self::StunLookup(p) => Vec::from(p),
             Self::StunResponse(p) => Vec::from(p),
+            Self::Error(p) => Vec::from(p),
         }
     }
 }
 
+/// Reads an ssn written by `write_ssn`, where all zeroes means no ssn.
+fn read_optional_ssn(input: &mut &[u8]) -> Option<String> {
+    let (ssn, rest) = input.split_at(SSN_LENGTH);
+    *input = rest;
+    if ssn.iter().all(|&x| x == 0) {
+        None
+    } else {
+        Some(ssn.iter().map(|&x| x as char).collect())
+    }
+}
+
 fn read_be_u8(input: &mut &[u8]) -> u8 {
     let (int_bytes, rest) = input.split_at(std::mem::size_of::<u8>());
     *input = rest;
@@ -114,14 +487,130 @@ fn read_be_u32(input: &mut &[u8]) -> u32 {
     u32::from_be_bytes(int_bytes.try_into().unwrap())
 }
 
+fn read_be_u64(input: &mut &[u8]) -> u64 {
+    let (int_bytes, rest) = input.split_at(std::mem::size_of::<u64>());
+    *input = rest;
+    u64::from_be_bytes(int_bytes.try_into().unwrap())
+}
+
 fn read_be_u16(input: &mut &[u8]) -> u16 {
     let (int_bytes, rest) = input.split_at(std::mem::size_of::<u16>());
     *input = rest;
     u16::from_be_bytes(int_bytes.try_into().unwrap())
 }
 
+/// Writes exactly `SSN_LENGTH` bytes, padding short SSNs with zeroes so the
+/// PDU keeps its size and the receiver can reject the SSN.
+fn write_ssn(v: &mut Vec<u8>, ssn: &str) {
+    let start = v.len();
+    v.extend(ssn.chars().take(SSN_LENGTH).map(|x| x as u8));
+    v.resize(start + SSN_LENGTH, 0);
+}
+
+/// Writes the length of `text` in bytes, in one byte or in two bytes in network
+/// byte order if `wide`, followed by the UTF-8 encoding of `text`. Panics if the
+/// length does not fit, rather than writing a PDU that can't be parsed.
+fn write_text(v: &mut Vec<u8>, text: &str, wide: bool) {
+    if wide {
+        v.extend_from_slice(&text_length(text).to_be_bytes());
+    } else {
+        let length: u8 = text
+            .len()
+            .try_into()
+            .expect("Text too long for a one byte length");
+        v.push(length);
+    }
+    v.extend_from_slice(text.as_bytes());
+}
+
+/// The length of a name or email in bytes, which must fit in two bytes. Nodes
+/// and the client keep them within `MAX_TEXT_LENGTH`.
+fn text_length(text: &str) -> u16 {
+    text.len()
+        .try_into()
+        .expect("Text longer than u16::MAX bytes")
+}
+
+/// Checks that a PDU with a one byte count can hold `count` entries.
+fn assert_entry_count(count: usize) {
+    assert!(
+        count <= u8::MAX as usize,
+        "{} entries do not fit in one PDU",
+        count
+    );
+}
+
+/// Reads text written by `write_text`. Fails if the buffer ends first or the
+/// text is not valid UTF-8.
+fn read_text(input: &mut &[u8], wide: bool) -> Result<String, ParseError> {
+    let length = match wide {
+        true if input.len() >= 2 => read_be_u16(input) as usize,
+        false if !input.is_empty() => read_be_u8(input) as usize,
+        _ => return Err(ParseError::Incomplete),
+    };
+    if input.len() < length {
+        return Err(ParseError::Incomplete);
+    }
+
+    let (text, rest) = input.split_at(length);
+    *input = rest;
+    String::from_utf8(text.to_vec()).map_err(|_| ParseError::InvalidText)
+}
+
+/// Writes a position on the ring, in one byte or in eight bytes in network
+/// byte order if `wide`.
+fn write_hash(v: &mut Vec<u8>, hash: u64, wide: bool) {
+    if wide {
+        v.extend_from_slice(&hash.to_be_bytes());
+    } else {
+        v.push(hash as u8);
+    }
+}
+
+fn read_hash(input: &mut &[u8], wide: bool) -> u64 {
+    match wide {
+        true => read_be_u64(input),
+        false => read_be_u8(input).into(),
+    }
+}
+
+/// Whether a position is too large for the one byte ranges of the original
+/// join, join response and new range.
+fn needs_wide_hash(hashes: &[u64]) -> bool {
+    hashes.iter().any(|&h| h > u8::MAX.into())
+}
+
+/// Whether a name or email is too long for the one byte length of the
+/// original insert and lookup response.
+fn needs_wide(name: &str, email: &str) -> bool {
+    name.len() > u8::MAX as usize || email.len() > u8::MAX as usize
+}
+
+/// Why a PDU could not be parsed from the start of a buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The buffer ends before the PDU does, more bytes may complete it.
+    Incomplete,
+    /// The first byte is not the type of any PDU.
+    UnknownType,
+    /// A name, email or other text is not valid UTF-8.
+    InvalidText,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "the PDU is incomplete"),
+            ParseError::UnknownType => write!(f, "unknown PDU type"),
+            ParseError::InvalidText => write!(f, "text is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 pub trait ParsePdu: Sized {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)>;
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError>;
 }
 
 pub struct NetGetNodeResponsePdu {
@@ -156,18 +645,18 @@ impl From<NetGetNodeResponsePdu> for Vec<u8> {
 }
 
 impl ParsePdu for NetGetNodeResponsePdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let size = NET_GET_NODE_RESPONSE_SIZE;
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
         let pdu = NetGetNodeResponsePdu {
             pdu_type: read_be_u8(&mut buffer),
             address: read_be_u32(&mut buffer),
             port: read_be_u16(&mut buffer),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -189,27 +678,31 @@ impl NetAlivePdu {
     }
 }
 
+impl Default for NetAlivePdu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl From<NetAlivePdu> for Vec<u8> {
     fn from(pdu: NetAlivePdu) -> Self {
-        let mut v = Vec::new();
-        v.push(pdu.pdu_type);
-        v
+        vec![pdu.pdu_type]
     }
 }
 
 impl ParsePdu for NetAlivePdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let size = NET_ALIVE_SIZE;
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
 
         let pdu = NetAlivePdu {
             pdu_type: read_be_u8(&mut buffer),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -231,26 +724,30 @@ impl NetGetNodePdu {
     }
 }
 
+impl Default for NetGetNodePdu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl From<NetGetNodePdu> for Vec<u8> {
     fn from(pdu: NetGetNodePdu) -> Self {
-        let mut v = Vec::new();
-        v.push(pdu.pdu_type);
-        v
+        vec![pdu.pdu_type]
     }
 }
 
 impl ParsePdu for NetGetNodePdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let size = NET_GET_NODE_SIZE;
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
         let pdu = NetGetNodePdu {
             pdu_type: read_be_u8(&mut buffer),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -272,26 +769,30 @@ impl NetCloseConnectionPdu {
     }
 }
 
+impl Default for NetCloseConnectionPdu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl From<NetCloseConnectionPdu> for Vec<u8> {
     fn from(pdu: NetCloseConnectionPdu) -> Self {
-        let mut v = Vec::new();
-        v.push(pdu.pdu_type);
-        v
+        vec![pdu.pdu_type]
     }
 }
 
 impl ParsePdu for NetCloseConnectionPdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let size = NET_CLOSE_CONNECTION_SIZE;
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
         let pdu = NetCloseConnectionPdu {
             pdu_type: read_be_u8(&mut buffer),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -305,7 +806,7 @@ pub struct NetJoinPdu {
     pub pdu_type: u8,
     pub src_address: u32,
     pub src_port: u16,
-    pub max_span: u8,
+    pub max_span: u64,
     pub max_address: u32,
     pub max_port: u16,
 }
@@ -314,12 +815,15 @@ impl NetJoinPdu {
     pub fn new(
         src_address: u32,
         src_port: u16,
-        max_span: u8,
+        max_span: u64,
         max_address: u32,
         max_port: u16,
     ) -> Self {
         NetJoinPdu {
-            pdu_type: NET_JOIN_ID,
+            pdu_type: match needs_wide_hash(&[max_span]) {
+                true => NET_JOIN_WIDE_ID,
+                false => NET_JOIN_ID,
+            },
             src_address,
             src_port,
             max_span,
@@ -345,7 +849,7 @@ impl From<NetJoinPdu> for Vec<u8> {
         v.push(pdu.pdu_type);
         v.extend_from_slice(&pdu.src_address.to_be_bytes());
         v.extend_from_slice(&pdu.src_port.to_be_bytes());
-        v.push(pdu.max_span);
+        write_hash(&mut v, pdu.max_span, pdu.pdu_type == NET_JOIN_WIDE_ID);
         v.extend_from_slice(&pdu.max_address.to_be_bytes());
         v.extend_from_slice(&pdu.max_port.to_be_bytes());
         v
@@ -353,23 +857,27 @@ impl From<NetJoinPdu> for Vec<u8> {
 }
 
 impl ParsePdu for NetJoinPdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
-        let size = NET_JOIN_SIZE;
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let wide = buffer.first() == Some(&NET_JOIN_WIDE_ID);
+        let size = match wide {
+            true => NET_JOIN_WIDE_SIZE,
+            false => NET_JOIN_SIZE,
+        };
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
 
         let pdu = NetJoinPdu {
             pdu_type: read_be_u8(&mut buffer),
             src_address: read_be_u32(&mut buffer),
             src_port: read_be_u16(&mut buffer),
-            max_span: read_be_u8(&mut buffer),
+            max_span: read_hash(&mut buffer, wide),
             max_address: read_be_u32(&mut buffer),
             max_port: read_be_u16(&mut buffer),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -383,14 +891,17 @@ pub struct NetJoinResponsePdu {
     pub pdu_type: u8,
     pub next_address: u32,
     pub next_port: u16,
-    pub range_start: u8,
-    pub range_end: u8,
+    pub range_start: u64,
+    pub range_end: u64,
 }
 
 impl NetJoinResponsePdu {
-    pub fn new(next_address: u32, next_port: u16, range_start: u8, range_end: u8) -> Self {
+    pub fn new(next_address: u32, next_port: u16, range_start: u64, range_end: u64) -> Self {
         NetJoinResponsePdu {
-            pdu_type: NET_JOIN_RESPONSE_ID,
+            pdu_type: match needs_wide_hash(&[range_start, range_end]) {
+                true => NET_JOIN_RESPONSE_WIDE_ID,
+                false => NET_JOIN_RESPONSE_ID,
+            },
             next_address,
             next_port,
             range_start,
@@ -410,29 +921,34 @@ impl From<NetJoinResponsePdu> for Vec<u8> {
         v.push(pdu.pdu_type);
         v.extend_from_slice(&pdu.next_address.to_be_bytes());
         v.extend_from_slice(&pdu.next_port.to_be_bytes());
-        v.push(pdu.range_start);
-        v.push(pdu.range_end);
+        let wide = pdu.pdu_type == NET_JOIN_RESPONSE_WIDE_ID;
+        write_hash(&mut v, pdu.range_start, wide);
+        write_hash(&mut v, pdu.range_end, wide);
         v
     }
 }
 
 impl ParsePdu for NetJoinResponsePdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
-        let size = NET_JOIN_RESPONSE_SIZE;
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let wide = buffer.first() == Some(&NET_JOIN_RESPONSE_WIDE_ID);
+        let size = match wide {
+            true => NET_JOIN_RESPONSE_WIDE_SIZE,
+            false => NET_JOIN_RESPONSE_SIZE,
+        };
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
 
         let pdu = NetJoinResponsePdu {
             pdu_type: read_be_u8(&mut buffer),
             next_address: read_be_u32(&mut buffer),
             next_port: read_be_u16(&mut buffer),
-            range_start: read_be_u8(&mut buffer),
-            range_end: read_be_u8(&mut buffer),
+            range_start: read_hash(&mut buffer, wide),
+            range_end: read_hash(&mut buffer, wide),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -454,27 +970,31 @@ impl StunLookupPdu {
     }
 }
 
+impl Default for StunLookupPdu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl From<StunLookupPdu> for Vec<u8> {
     fn from(pdu: StunLookupPdu) -> Self {
-        let mut v = Vec::new();
-        v.push(pdu.pdu_type);
-        v
+        vec![pdu.pdu_type]
     }
 }
 
 impl ParsePdu for StunLookupPdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let size = STUN_LOOKUP_SIZE;
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
 
         let pdu = StunLookupPdu {
             pdu_type: read_be_u8(&mut buffer),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -486,14 +1006,17 @@ impl From<StunLookupPdu> for PDU {
 
 pub struct NetNewRangePdu {
     pub pdu_type: u8,
-    pub range_start: u8,
-    pub range_end: u8,
+    pub range_start: u64,
+    pub range_end: u64,
 }
 
 impl NetNewRangePdu {
-    pub fn new(range_start: u8, range_end: u8) -> Self {
+    pub fn new(range_start: u64, range_end: u64) -> Self {
         NetNewRangePdu {
-            pdu_type: NET_NEW_RANGE_ID,
+            pdu_type: match needs_wide_hash(&[range_start, range_end]) {
+                true => NET_NEW_RANGE_WIDE_ID,
+                false => NET_NEW_RANGE_ID,
+            },
             range_start,
             range_end,
         }
@@ -502,29 +1025,33 @@ impl NetNewRangePdu {
 
 impl From<NetNewRangePdu> for Vec<u8> {
     fn from(pdu: NetNewRangePdu) -> Self {
-        let mut v = Vec::new();
-        v.push(pdu.pdu_type);
-        v.push(pdu.range_start);
-        v.push(pdu.range_end);
+        let wide = pdu.pdu_type == NET_NEW_RANGE_WIDE_ID;
+        let mut v = vec![pdu.pdu_type];
+        write_hash(&mut v, pdu.range_start, wide);
+        write_hash(&mut v, pdu.range_end, wide);
         v
     }
 }
 
 impl ParsePdu for NetNewRangePdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
-        let size = NET_NEW_RANGE_SIZE;
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let wide = buffer.first() == Some(&NET_NEW_RANGE_WIDE_ID);
+        let size = match wide {
+            true => NET_NEW_RANGE_WIDE_SIZE,
+            false => NET_NEW_RANGE_SIZE,
+        };
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
 
         let pdu = NetNewRangePdu {
             pdu_type: read_be_u8(&mut buffer),
-            range_start: read_be_u8(&mut buffer),
-            range_end: read_be_u8(&mut buffer),
+            range_start: read_hash(&mut buffer, wide),
+            range_end: read_hash(&mut buffer, wide),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -546,27 +1073,31 @@ impl NetNewRangeResponsePdu {
     }
 }
 
+impl Default for NetNewRangeResponsePdu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl From<NetNewRangeResponsePdu> for Vec<u8> {
     fn from(pdu: NetNewRangeResponsePdu) -> Self {
-        let mut v = Vec::new();
-        v.push(pdu.pdu_type);
-        v
+        vec![pdu.pdu_type]
     }
 }
 
 impl ParsePdu for NetNewRangeResponsePdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let size = NET_NEW_RANGE_RESPONSE_SIZE;
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
 
         let pdu = NetNewRangeResponsePdu {
             pdu_type: read_be_u8(&mut buffer),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -608,20 +1139,20 @@ impl From<NetLeavingPdu> for Vec<u8> {
 }
 
 impl ParsePdu for NetLeavingPdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let size = NET_LEAVING_SIZE;
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
 
         let pdu = NetLeavingPdu {
             pdu_type: read_be_u8(&mut buffer),
             new_address: read_be_u32(&mut buffer),
             new_port: read_be_u16(&mut buffer),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -631,6 +1162,196 @@ impl From<NetLeavingPdu> for PDU {
     }
 }
 
+/// Hands a neighbour one more range to be responsible for, in addition to its
+/// own. Nodes with several virtual ranges send one for each range after the
+/// first when a node joins, and before `NetNewRangePdu` when they leave.
+pub struct NetAddRangePdu {
+    pub pdu_type: u8,
+    pub range_start: u64,
+    pub range_end: u64,
+}
+
+impl NetAddRangePdu {
+    pub fn new(range_start: u64, range_end: u64) -> Self {
+        NetAddRangePdu {
+            pdu_type: NET_ADD_RANGE_ID,
+            range_start,
+            range_end,
+        }
+    }
+}
+
+impl From<NetAddRangePdu> for Vec<u8> {
+    fn from(pdu: NetAddRangePdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        write_hash(&mut v, pdu.range_start, true);
+        write_hash(&mut v, pdu.range_end, true);
+        v
+    }
+}
+
+impl ParsePdu for NetAddRangePdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let size = NET_ADD_RANGE_SIZE;
+        if buffer.len() < size {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+
+        let pdu = NetAddRangePdu {
+            pdu_type: read_be_u8(&mut buffer),
+            range_start: read_be_u64(&mut buffer),
+            range_end: read_be_u64(&mut buffer),
+        };
+        Ok((pdu, size))
+    }
+}
+
+impl From<NetAddRangePdu> for PDU {
+    fn from(pdu: NetAddRangePdu) -> Self {
+        Self::NetAddRange(pdu)
+    }
+}
+
+/// Offers a neighbour to take over part of its load. The neighbour moves part
+/// of its range here with a `NetAddRangePdu` if it carries more than `load`,
+/// or answers with its own load if it carries less. Sent to a node over UDP it
+/// makes the node offer to both its neighbours, and `load` is ignored.
+pub struct NetRebalancePdu {
+    pub pdu_type: u8,
+    pub load: u64,
+}
+
+impl NetRebalancePdu {
+    pub fn new(load: u64) -> Self {
+        NetRebalancePdu {
+            pdu_type: NET_REBALANCE_ID,
+            load,
+        }
+    }
+}
+
+impl From<NetRebalancePdu> for Vec<u8> {
+    fn from(pdu: NetRebalancePdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        v.extend_from_slice(&pdu.load.to_be_bytes());
+        v
+    }
+}
+
+impl ParsePdu for NetRebalancePdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let size = NET_REBALANCE_SIZE;
+        if buffer.len() < size {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+
+        let pdu = NetRebalancePdu {
+            pdu_type: read_be_u8(&mut buffer),
+            load: read_be_u64(&mut buffer),
+        };
+        Ok((pdu, size))
+    }
+}
+
+impl From<NetRebalancePdu> for PDU {
+    fn from(pdu: NetRebalancePdu) -> Self {
+        Self::NetRebalance(pdu)
+    }
+}
+
+/// Makes a node leave or hand its ranges to a neighbour, see `CONTROL_LEAVE`.
+/// `mac` is the HMAC-SHA256 of the fields before it under the control key of
+/// the node. The target is the UDP address of the node the PDU is for, so it
+/// can't be replayed to another node with the same key, and `timestamp` is in
+/// milliseconds since the Unix epoch, which keeps a PDU from being sent again
+/// later.
+pub struct NetControlPdu {
+    pub pdu_type: u8,
+    pub command: u8,
+    pub target_address: u32,
+    pub target_port: u16,
+    pub timestamp: u64,
+    pub mac: [u8; 32],
+}
+
+impl NetControlPdu {
+    pub fn new(
+        command: u8,
+        target_address: u32,
+        target_port: u16,
+        timestamp: u64,
+        key: &[u8],
+    ) -> Self {
+        let mut pdu = NetControlPdu {
+            pdu_type: NET_CONTROL_ID,
+            command,
+            target_address,
+            target_port,
+            timestamp,
+            mac: [0; 32],
+        };
+        pdu.mac = hmac_sha256::HMAC::mac(pdu.signed_bytes(), key);
+        pdu
+    }
+
+    /// Whether the PDU was signed with `key`.
+    pub fn verify(&self, key: &[u8]) -> bool {
+        hmac_sha256::HMAC::verify(self.signed_bytes(), key, &self.mac)
+    }
+
+    pub fn get_target_addr(&self) -> SocketAddr {
+        let ip: Ipv4Addr = self.target_address.into();
+        (ip, self.target_port).into()
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut v = vec![self.pdu_type, self.command];
+        v.extend_from_slice(&self.target_address.to_be_bytes());
+        v.extend_from_slice(&self.target_port.to_be_bytes());
+        v.extend_from_slice(&self.timestamp.to_be_bytes());
+        v
+    }
+}
+
+impl From<NetControlPdu> for Vec<u8> {
+    fn from(pdu: NetControlPdu) -> Self {
+        let mut v = pdu.signed_bytes();
+        v.extend_from_slice(&pdu.mac);
+        v
+    }
+}
+
+impl ParsePdu for NetControlPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let size = NET_CONTROL_SIZE;
+        if buffer.len() < size {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+
+        let pdu = NetControlPdu {
+            pdu_type: read_be_u8(&mut buffer),
+            command: read_be_u8(&mut buffer),
+            target_address: read_be_u32(&mut buffer),
+            target_port: read_be_u16(&mut buffer),
+            timestamp: read_be_u64(&mut buffer),
+            mac: buffer[..32].try_into().unwrap(),
+        };
+        Ok((pdu, size))
+    }
+}
+
+impl From<NetControlPdu> for PDU {
+    fn from(pdu: NetControlPdu) -> Self {
+        Self::NetControl(pdu)
+    }
+}
+
 pub struct StunResponsePdu {
     pub pdu_type: u8,
     pub address: u32,
@@ -655,19 +1376,19 @@ impl From<StunResponsePdu> for Vec<u8> {
 }
 
 impl ParsePdu for StunResponsePdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let size = STUN_RESPONSE_SIZE;
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
 
         let pdu = StunResponsePdu {
             pdu_type: read_be_u8(&mut buffer),
             address: read_be_u32(&mut buffer),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -677,84 +1398,80 @@ impl From<StunResponsePdu> for PDU {
     }
 }
 
+/// Inserts or replaces an entry. Names and emails longer than 255 bytes are
+/// sent as `VAL_INSERT_WIDE_ID`, which has two byte lengths.
 pub struct ValInsertPdu {
     pub pdu_type: u8,
     pub ssn: String,
-    pub name_length: u8,
+    pub name_length: u16,
     pub name: String,
-    pub email_length: u8,
+    pub email_length: u16,
     pub email: String,
 }
 
 impl ValInsertPdu {
+    /// Panics if `name` or `email` is longer than `u16::MAX` bytes.
     pub fn new(ssn: String, name: String, email: String) -> Self {
         ValInsertPdu {
-            pdu_type: VAL_INSERT_ID,
-            name_length: name.len() as u8,
-            email_length: email.len() as u8,
+            pdu_type: match needs_wide(&name, &email) {
+                true => VAL_INSERT_WIDE_ID,
+                false => VAL_INSERT_ID,
+            },
+            name_length: text_length(&name),
+            email_length: text_length(&email),
             ssn,
             name,
             email,
         }
     }
+
+    /// Bytes the PDU takes when sent.
+    pub fn size(&self) -> usize {
+        let width = match self.pdu_type {
+            VAL_INSERT_WIDE_ID => 2,
+            _ => 1,
+        };
+        1 + SSN_LENGTH + width + self.name.len() + width + self.email.len()
+    }
 }
 
 impl From<ValInsertPdu> for Vec<u8> {
     fn from(pdu: ValInsertPdu) -> Self {
+        let wide = pdu.pdu_type == VAL_INSERT_WIDE_ID;
         let mut v = Vec::new();
         v.push(pdu.pdu_type);
-        v.extend(pdu.ssn.chars().take(SSN_LENGTH).map(|x| x as u8));
-        v.push(pdu.name_length);
-        v.extend(pdu.name.chars().map(|x| x as u8));
-        v.push(pdu.email_length);
-        v.extend(pdu.email.chars().map(|x| x as u8));
+        write_ssn(&mut v, &pdu.ssn);
+        write_text(&mut v, &pdu.name, wide);
+        write_text(&mut v, &pdu.email, wide);
         v
     }
 }
 
 impl ParsePdu for ValInsertPdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
-        if buffer.len() < 1 + SSN_LENGTH + 1 {
-            return None;
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < 1 + SSN_LENGTH {
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
-        let pdu_type = read_be_u8(&mut buffer);
+        let mut rest = buffer;
+        let pdu_type = read_be_u8(&mut rest);
+        // The lookup response starts like an insert
+        let wide = pdu_type == VAL_INSERT_WIDE_ID || pdu_type == VAL_LOOKUP_RESPONSE_WIDE_ID;
 
-        let (ssn, mut buffer) = buffer.split_at(SSN_LENGTH);
+        let (ssn, mut rest) = rest.split_at(SSN_LENGTH);
         let ssn = ssn.iter().map(|&x| x as char).collect();
-        let name_length = read_be_u8(&mut buffer);
-
-        if buffer.len() < name_length as usize {
-            return None;
-        }
-
-        let (name, mut buffer) = buffer.split_at(name_length as usize);
-        let name: String = name.iter().map(|&x| x as char).collect();
-
-        let email_length = read_be_u8(&mut buffer);
-
-        if buffer.len() < email_length as usize {
-            return None;
-        }
-
-        let email = buffer
-            .iter()
-            .take(email_length as usize)
-            .map(|&x| x as char)
-            .collect();
+        let name = read_text(&mut rest, wide)?;
+        let email = read_text(&mut rest, wide)?;
 
         let pdu = ValInsertPdu {
             pdu_type,
             ssn,
-            name_length,
+            name_length: text_length(&name),
             name,
-            email_length,
+            email_length: text_length(&email),
             email,
         };
-        //       Type   SSN    Name length  Name           Email length   Email
-        let size = 1 + SSN_LENGTH + 1 + name_length as usize + 1 + email_length as usize;
-        Some((pdu, size))
+        Ok((pdu, buffer.len() - rest.len()))
     }
 }
 
@@ -780,13 +1497,27 @@ impl ValLookupPdu {
             ssn,
         }
     }
+
+    /// A lookup that is answered with the version of the entry, in a
+    /// `VAL_LOOKUP_RESPONSE_VERSIONED_ID` response.
+    pub fn new_versioned(ssn: String, sender_address: u32, sender_port: u16) -> Self {
+        ValLookupPdu {
+            pdu_type: VAL_LOOKUP_VERSIONED_ID,
+            ..ValLookupPdu::new(ssn, sender_address, sender_port)
+        }
+    }
+
+    pub fn get_sender_addr(&self) -> SocketAddr {
+        let ip: Ipv4Addr = self.sender_address.into();
+        (ip, self.sender_port).into()
+    }
 }
 
 impl From<ValLookupPdu> for Vec<u8> {
     fn from(pdu: ValLookupPdu) -> Self {
         let mut v = Vec::new();
         v.push(pdu.pdu_type);
-        v.extend(pdu.ssn.chars().take(SSN_LENGTH).map(|x| x as u8));
+        write_ssn(&mut v, &pdu.ssn);
         v.extend_from_slice(&pdu.sender_address.to_be_bytes());
         v.extend_from_slice(&pdu.sender_port.to_be_bytes());
         v
@@ -794,13 +1525,13 @@ impl From<ValLookupPdu> for Vec<u8> {
 }
 
 impl ParsePdu for ValLookupPdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let size = VAL_LOOKUP_SIZE;
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
         let pdu_type = read_be_u8(&mut buffer);
         let (ssn, mut buffer) = buffer.split_at(SSN_LENGTH);
         let pdu = ValLookupPdu {
@@ -809,7 +1540,7 @@ impl ParsePdu for ValLookupPdu {
             sender_address: read_be_u32(&mut buffer),
             sender_port: read_be_u16(&mut buffer),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -819,53 +1550,80 @@ impl From<ValLookupPdu> for PDU {
     }
 }
 
+/// Response to a lookup. The `VAL_LOOKUP_RESPONSE_ID` of the specification
+/// has no version, `version` is only sent in the answer to a versioned lookup,
+/// `VAL_LOOKUP_RESPONSE_VERSIONED_ID`, and in the wide variant for long names
+/// and emails, `VAL_LOOKUP_RESPONSE_WIDE_ID`. It is the version of the entry,
+/// or 0 if the entry does not exist or the response has no version.
 pub struct ValLookupResponsePdu {
     pub pdu_type: u8,
     pub ssn: String,
-    pub name_length: u8,
+    pub name_length: u16,
     pub name: String,
-    pub email_length: u8,
+    pub email_length: u16,
     pub email: String,
+    pub version: u64,
 }
 
 impl ValLookupResponsePdu {
-    pub fn new(ssn: String, name: String, email: String) -> Self {
+    /// The response to `lookup_type`, a plain or a versioned lookup.
+    pub fn new(lookup_type: u8, ssn: String, name: String, email: String, version: u64) -> Self {
         ValLookupResponsePdu {
-            pdu_type: VAL_LOOKUP_RESPONSE_ID,
-            name_length: name.len() as u8,
-            email_length: email.len() as u8,
+            pdu_type: match (needs_wide(&name, &email), lookup_type) {
+                (true, _) => VAL_LOOKUP_RESPONSE_WIDE_ID,
+                (false, VAL_LOOKUP_VERSIONED_ID) => VAL_LOOKUP_RESPONSE_VERSIONED_ID,
+                (false, _) => VAL_LOOKUP_RESPONSE_ID,
+            },
+            name_length: text_length(&name),
+            email_length: text_length(&email),
             ssn,
             name,
             email,
+            version,
         }
     }
 }
 
 impl From<ValLookupResponsePdu> for Vec<u8> {
     fn from(pdu: ValLookupResponsePdu) -> Self {
+        let wide = pdu.pdu_type == VAL_LOOKUP_RESPONSE_WIDE_ID;
         let mut v = Vec::new();
         v.push(pdu.pdu_type);
-        v.extend(pdu.ssn.chars().take(SSN_LENGTH).map(|x| x as u8));
-        v.push(pdu.name_length);
-        v.extend(pdu.name.chars().map(|x| x as u8));
-        v.push(pdu.email_length);
-        v.extend(pdu.email.chars().map(|x| x as u8));
+        write_ssn(&mut v, &pdu.ssn);
+        write_text(&mut v, &pdu.name, wide);
+        write_text(&mut v, &pdu.email, wide);
+        if pdu.pdu_type != VAL_LOOKUP_RESPONSE_ID {
+            v.extend_from_slice(&pdu.version.to_be_bytes());
+        }
         v
     }
 }
 
 impl ParsePdu for ValLookupResponsePdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let (pdu, s) = ValInsertPdu::try_parse(buffer)?;
+        let size = match pdu.pdu_type {
+            VAL_LOOKUP_RESPONSE_ID => s,
+            _ => s + 8,
+        };
+        if buffer.len() < size {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = &buffer[s..];
         let pdu = ValLookupResponsePdu {
-            pdu_type: VAL_LOOKUP_ID,
+            pdu_type: pdu.pdu_type,
             ssn: pdu.ssn,
             name_length: pdu.name_length,
             name: pdu.name,
             email_length: pdu.email_length,
             email: pdu.email,
+            version: match size > s {
+                true => read_be_u64(&mut buffer),
+                false => 0,
+            },
         };
-        Some((ValLookupResponsePdu { ..pdu }, s))
+        Ok((pdu, size))
     }
 }
 
@@ -893,25 +1651,25 @@ impl From<ValRemovePdu> for Vec<u8> {
     fn from(pdu: ValRemovePdu) -> Self {
         let mut v = Vec::new();
         v.push(pdu.pdu_type);
-        v.extend(pdu.ssn.chars().take(SSN_LENGTH).map(|x| x as u8));
+        write_ssn(&mut v, &pdu.ssn);
         v
     }
 }
 
 impl ParsePdu for ValRemovePdu {
-    fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
         let size = VAL_REMOVE_SIZE;
         if buffer.len() < size {
-            return None;
+            return Err(ParseError::Incomplete);
         }
 
-        let mut buffer = &buffer[..];
+        let mut buffer = buffer;
 
         let pdu = ValRemovePdu {
             pdu_type: read_be_u8(&mut buffer),
             ssn: buffer[..SSN_LENGTH].iter().map(|&x| x as char).collect(),
         };
-        Some((pdu, size))
+        Ok((pdu, size))
     }
 }
 
@@ -921,25 +1679,1518 @@ impl From<ValRemovePdu> for PDU {
     }
 }
 
-#[cfg(test)]
-mod serialization_test {
-    use crate::pdu::PDU::*;
-    use crate::pdu::*;
-    #[test]
-    fn test_net_alive() {
-        let a = NetAlivePdu::new();
-        let b: Vec<u8> = a.into();
-        assert_eq!(b.len(), NET_ALIVE_SIZE);
-        let (a, b) = NetAlivePdu::try_parse(&b).unwrap();
-        assert_eq!(b, NET_ALIVE_SIZE);
-    }
+/// Wraps a value PDU with a trace context.
+///
+/// Every node that receives the PDU increments `hop_count` and reports itself
+/// to `report_address`/`report_port` with a `ValTraceHopPdu` before handling
+/// the inner PDU. Forwarded PDUs keep their trace context.
+pub struct ValTracePdu {
+    pub pdu_type: u8,
+    pub trace_id: u64,
+    pub hop_count: u8,
+    pub report_address: u32,
+    pub report_port: u16,
+    pub inner: Box<PDU>,
+}
+
+impl ValTracePdu {
+    pub fn new(
+        trace_id: u64,
+        hop_count: u8,
+        report_address: u32,
+        report_port: u16,
+        inner: PDU,
+    ) -> Self {
+        ValTracePdu {
+            pdu_type: VAL_TRACE_ID,
+            trace_id,
+            hop_count,
+            report_address,
+            report_port,
+            inner: Box::new(inner),
+        }
+    }
+
+    pub fn get_report_addr(&self) -> SocketAddr {
+        let ip: Ipv4Addr = self.report_address.into();
+        (ip, self.report_port).into()
+    }
+}
+
+impl From<ValTracePdu> for Vec<u8> {
+    fn from(pdu: ValTracePdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        v.extend_from_slice(&pdu.trace_id.to_be_bytes());
+        v.push(pdu.hop_count);
+        v.extend_from_slice(&pdu.report_address.to_be_bytes());
+        v.extend_from_slice(&pdu.report_port.to_be_bytes());
+        v.extend(pdu.inner.to_bytes());
+        v
+    }
+}
+
+impl ParsePdu for ValTracePdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_TRACE_HEADER_SIZE + 1 {
+            return Err(ParseError::Incomplete);
+        }
+
+        let (header, rest) = buffer.split_at(VAL_TRACE_HEADER_SIZE);
+        let (inner, inner_size) = crate::socket_wrapper::parse_pdu(rest)?;
+        let mut buffer = header;
+
+        let pdu = ValTracePdu {
+            pdu_type: read_be_u8(&mut buffer),
+            trace_id: read_be_u64(&mut buffer),
+            hop_count: read_be_u8(&mut buffer),
+            report_address: read_be_u32(&mut buffer),
+            report_port: read_be_u16(&mut buffer),
+            inner: Box::new(inner),
+        };
+        Ok((pdu, VAL_TRACE_HEADER_SIZE + inner_size))
+    }
+}
+
+impl From<ValTracePdu> for PDU {
+    fn from(pdu: ValTracePdu) -> Self {
+        Self::ValTrace(pdu)
+    }
+}
+
+/// Sent by every node a traced PDU passes through.
+pub struct ValTraceHopPdu {
+    pub pdu_type: u8,
+    pub trace_id: u64,
+    pub hop: u8,
+    pub node_address: u32,
+    pub node_port: u16,
+}
+
+impl ValTraceHopPdu {
+    pub fn new(trace_id: u64, hop: u8, node_address: u32, node_port: u16) -> Self {
+        ValTraceHopPdu {
+            pdu_type: VAL_TRACE_HOP_ID,
+            trace_id,
+            hop,
+            node_address,
+            node_port,
+        }
+    }
+
+    pub fn get_node_addr(&self) -> SocketAddr {
+        let ip: Ipv4Addr = self.node_address.into();
+        (ip, self.node_port).into()
+    }
+}
+
+impl From<ValTraceHopPdu> for Vec<u8> {
+    fn from(pdu: ValTraceHopPdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        v.extend_from_slice(&pdu.trace_id.to_be_bytes());
+        v.push(pdu.hop);
+        v.extend_from_slice(&pdu.node_address.to_be_bytes());
+        v.extend_from_slice(&pdu.node_port.to_be_bytes());
+        v
+    }
+}
+
+impl ParsePdu for ValTraceHopPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let size = VAL_TRACE_HOP_SIZE;
+        if buffer.len() < size {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+
+        let pdu = ValTraceHopPdu {
+            pdu_type: read_be_u8(&mut buffer),
+            trace_id: read_be_u64(&mut buffer),
+            hop: read_be_u8(&mut buffer),
+            node_address: read_be_u32(&mut buffer),
+            node_port: read_be_u16(&mut buffer),
+        };
+        Ok((pdu, size))
+    }
+}
+
+impl From<ValTraceHopPdu> for PDU {
+    fn from(pdu: ValTraceHopPdu) -> Self {
+        Self::ValTraceHop(pdu)
+    }
+}
+
+/// Wraps a forwarded value PDU with the number of forwards it has left.
+///
+/// A node that cannot handle the inner PDU forwards it with `hop_limit`
+/// decremented, or drops it and sends an `ErrorPdu` to the origin if the
+/// limit is already zero. An origin of `0:0` means that it is unknown.
+pub struct ValHopLimitPdu {
+    pub pdu_type: u8,
+    pub hop_limit: u8,
+    pub origin_address: u32,
+    pub origin_port: u16,
+    pub inner: Box<PDU>,
+}
+
+impl ValHopLimitPdu {
+    pub fn new(hop_limit: u8, origin_address: u32, origin_port: u16, inner: PDU) -> Self {
+        ValHopLimitPdu {
+            pdu_type: VAL_HOP_LIMIT_ID,
+            hop_limit,
+            origin_address,
+            origin_port,
+            inner: Box::new(inner),
+        }
+    }
+
+    pub fn get_origin_addr(&self) -> Option<SocketAddr> {
+        if self.origin_address == 0 && self.origin_port == 0 {
+            return None;
+        }
+        let ip: Ipv4Addr = self.origin_address.into();
+        Some((ip, self.origin_port).into())
+    }
+}
+
+impl From<ValHopLimitPdu> for Vec<u8> {
+    fn from(pdu: ValHopLimitPdu) -> Self {
+        let mut v = vec![pdu.pdu_type, pdu.hop_limit];
+        v.extend_from_slice(&pdu.origin_address.to_be_bytes());
+        v.extend_from_slice(&pdu.origin_port.to_be_bytes());
+        v.extend(pdu.inner.to_bytes());
+        v
+    }
+}
+
+impl ParsePdu for ValHopLimitPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_HOP_LIMIT_HEADER_SIZE + 1 {
+            return Err(ParseError::Incomplete);
+        }
+
+        let (header, rest) = buffer.split_at(VAL_HOP_LIMIT_HEADER_SIZE);
+        let (inner, inner_size) = crate::socket_wrapper::parse_pdu(rest)?;
+        let mut buffer = header;
+
+        let pdu = ValHopLimitPdu {
+            pdu_type: read_be_u8(&mut buffer),
+            hop_limit: read_be_u8(&mut buffer),
+            origin_address: read_be_u32(&mut buffer),
+            origin_port: read_be_u16(&mut buffer),
+            inner: Box::new(inner),
+        };
+        Ok((pdu, VAL_HOP_LIMIT_HEADER_SIZE + inner_size))
+    }
+}
+
+impl From<ValHopLimitPdu> for PDU {
+    fn from(pdu: ValHopLimitPdu) -> Self {
+        Self::ValHopLimit(pdu)
+    }
+}
+
+/// Wraps a value PDU with the version of the entry it carries.
+///
+/// Used when entries are moved between nodes, so that the receiving node keeps
+/// the version instead of assigning a new one.
+pub struct ValVersionPdu {
+    pub pdu_type: u8,
+    pub version: u64,
+    pub inner: Box<PDU>,
+}
+
+impl ValVersionPdu {
+    pub fn new(version: u64, inner: PDU) -> Self {
+        ValVersionPdu {
+            pdu_type: VAL_VERSION_ID,
+            version,
+            inner: Box::new(inner),
+        }
+    }
+}
+
+impl From<ValVersionPdu> for Vec<u8> {
+    fn from(pdu: ValVersionPdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        v.extend_from_slice(&pdu.version.to_be_bytes());
+        v.extend(pdu.inner.to_bytes());
+        v
+    }
+}
+
+impl ParsePdu for ValVersionPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_VERSION_HEADER_SIZE + 1 {
+            return Err(ParseError::Incomplete);
+        }
+
+        let (header, rest) = buffer.split_at(VAL_VERSION_HEADER_SIZE);
+        let (inner, inner_size) = crate::socket_wrapper::parse_pdu(rest)?;
+        let mut buffer = header;
+
+        let pdu = ValVersionPdu {
+            pdu_type: read_be_u8(&mut buffer),
+            version: read_be_u64(&mut buffer),
+            inner: Box::new(inner),
+        };
+        Ok((pdu, VAL_VERSION_HEADER_SIZE + inner_size))
+    }
+}
+
+impl From<ValVersionPdu> for PDU {
+    fn from(pdu: ValVersionPdu) -> Self {
+        Self::ValVersion(pdu)
+    }
+}
+
+/// Inserts an entry only if the current version of the ssn is
+/// `expected_version`, where 0 means that the entry must not exist.
+///
+/// The responsible node answers with a `ValCondResponsePdu` to the sender.
+pub struct ValCondInsertPdu {
+    pub pdu_type: u8,
+    pub ssn: String,
+    pub expected_version: u64,
+    pub sender_address: u32,
+    pub sender_port: u16,
+    pub name_length: u16,
+    pub name: String,
+    pub email_length: u16,
+    pub email: String,
+}
+
+impl ValCondInsertPdu {
+    pub fn new(
+        ssn: String,
+        name: String,
+        email: String,
+        expected_version: u64,
+        sender_address: u32,
+        sender_port: u16,
+    ) -> Self {
+        ValCondInsertPdu {
+            pdu_type: VAL_COND_INSERT_ID,
+            ssn,
+            expected_version,
+            sender_address,
+            sender_port,
+            name_length: text_length(&name),
+            name,
+            email_length: text_length(&email),
+            email,
+        }
+    }
+
+    pub fn get_sender_addr(&self) -> SocketAddr {
+        let ip: Ipv4Addr = self.sender_address.into();
+        (ip, self.sender_port).into()
+    }
+}
+
+impl From<ValCondInsertPdu> for Vec<u8> {
+    fn from(pdu: ValCondInsertPdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        write_ssn(&mut v, &pdu.ssn);
+        v.extend_from_slice(&pdu.expected_version.to_be_bytes());
+        v.extend_from_slice(&pdu.sender_address.to_be_bytes());
+        v.extend_from_slice(&pdu.sender_port.to_be_bytes());
+        write_text(&mut v, &pdu.name, true);
+        write_text(&mut v, &pdu.email, true);
+        v
+    }
+}
+
+impl ParsePdu for ValCondInsertPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        // Same fixed part as a conditional remove, followed by name and email
+        if buffer.len() < VAL_COND_REMOVE_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut rest = buffer;
+        let pdu_type = read_be_u8(&mut rest);
+        let (ssn, mut rest) = rest.split_at(SSN_LENGTH);
+        let expected_version = read_be_u64(&mut rest);
+        let sender_address = read_be_u32(&mut rest);
+        let sender_port = read_be_u16(&mut rest);
+        let name = read_text(&mut rest, true)?;
+        let email = read_text(&mut rest, true)?;
+
+        let pdu = ValCondInsertPdu {
+            pdu_type,
+            ssn: ssn.iter().map(|&x| x as char).collect(),
+            expected_version,
+            sender_address,
+            sender_port,
+            name_length: text_length(&name),
+            name,
+            email_length: text_length(&email),
+            email,
+        };
+        Ok((pdu, buffer.len() - rest.len()))
+    }
+}
+
+impl From<ValCondInsertPdu> for PDU {
+    fn from(pdu: ValCondInsertPdu) -> Self {
+        Self::ValCondInsert(pdu)
+    }
+}
+
+/// Removes an entry only if its current version is `expected_version`.
+///
+/// The responsible node answers with a `ValCondResponsePdu` to the sender.
+pub struct ValCondRemovePdu {
+    pub pdu_type: u8,
+    pub ssn: String,
+    pub expected_version: u64,
+    pub sender_address: u32,
+    pub sender_port: u16,
+}
+
+impl ValCondRemovePdu {
+    pub fn new(ssn: String, expected_version: u64, sender_address: u32, sender_port: u16) -> Self {
+        ValCondRemovePdu {
+            pdu_type: VAL_COND_REMOVE_ID,
+            ssn,
+            expected_version,
+            sender_address,
+            sender_port,
+        }
+    }
+
+    pub fn get_sender_addr(&self) -> SocketAddr {
+        let ip: Ipv4Addr = self.sender_address.into();
+        (ip, self.sender_port).into()
+    }
+}
+
+impl From<ValCondRemovePdu> for Vec<u8> {
+    fn from(pdu: ValCondRemovePdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        write_ssn(&mut v, &pdu.ssn);
+        v.extend_from_slice(&pdu.expected_version.to_be_bytes());
+        v.extend_from_slice(&pdu.sender_address.to_be_bytes());
+        v.extend_from_slice(&pdu.sender_port.to_be_bytes());
+        v
+    }
+}
+
+impl ParsePdu for ValCondRemovePdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let size = VAL_COND_REMOVE_SIZE;
+        if buffer.len() < size {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu_type = read_be_u8(&mut buffer);
+        let (ssn, mut buffer) = buffer.split_at(SSN_LENGTH);
+        let pdu = ValCondRemovePdu {
+            pdu_type,
+            ssn: ssn.iter().map(|&x| x as char).collect(),
+            expected_version: read_be_u64(&mut buffer),
+            sender_address: read_be_u32(&mut buffer),
+            sender_port: read_be_u16(&mut buffer),
+        };
+        Ok((pdu, size))
+    }
+}
+
+impl From<ValCondRemovePdu> for PDU {
+    fn from(pdu: ValCondRemovePdu) -> Self {
+        Self::ValCondRemove(pdu)
+    }
+}
+
+/// Result of a conditional insert or remove. `version` is the version of the
+/// entry after the request, or 0 if it does not exist.
+pub struct ValCondResponsePdu {
+    pub pdu_type: u8,
+    pub request_type: u8,
+    pub success: bool,
+    pub ssn: String,
+    pub version: u64,
+}
+
+impl ValCondResponsePdu {
+    pub fn new(request_type: u8, success: bool, ssn: String, version: u64) -> Self {
+        ValCondResponsePdu {
+            pdu_type: VAL_COND_RESPONSE_ID,
+            request_type,
+            success,
+            ssn,
+            version,
+        }
+    }
+}
+
+impl From<ValCondResponsePdu> for Vec<u8> {
+    fn from(pdu: ValCondResponsePdu) -> Self {
+        let mut v = vec![pdu.pdu_type, pdu.request_type, pdu.success as u8];
+        write_ssn(&mut v, &pdu.ssn);
+        v.extend_from_slice(&pdu.version.to_be_bytes());
+        v
+    }
+}
+
+impl ParsePdu for ValCondResponsePdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let size = VAL_COND_RESPONSE_SIZE;
+        if buffer.len() < size {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu_type = read_be_u8(&mut buffer);
+        let request_type = read_be_u8(&mut buffer);
+        let success = read_be_u8(&mut buffer) != 0;
+        let (ssn, mut buffer) = buffer.split_at(SSN_LENGTH);
+        let pdu = ValCondResponsePdu {
+            pdu_type,
+            request_type,
+            success,
+            ssn: ssn.iter().map(|&x| x as char).collect(),
+            version: read_be_u64(&mut buffer),
+        };
+        Ok((pdu, size))
+    }
+}
+
+impl From<ValCondResponsePdu> for PDU {
+    fn from(pdu: ValCondResponsePdu) -> Self {
+        Self::ValCondResponse(pdu)
+    }
+}
+
+/// Wraps an insert with the number of seconds the entry lives before it
+/// expires and is removed by the responsible node.
+pub struct ValTtlPdu {
+    pub pdu_type: u8,
+    pub ttl: u32,
+    pub inner: Box<PDU>,
+}
+
+impl ValTtlPdu {
+    pub fn new(ttl: u32, inner: PDU) -> Self {
+        ValTtlPdu {
+            pdu_type: VAL_TTL_ID,
+            ttl,
+            inner: Box::new(inner),
+        }
+    }
+}
+
+impl From<ValTtlPdu> for Vec<u8> {
+    fn from(pdu: ValTtlPdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        v.extend_from_slice(&pdu.ttl.to_be_bytes());
+        v.extend(pdu.inner.to_bytes());
+        v
+    }
+}
+
+impl ParsePdu for ValTtlPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_TTL_HEADER_SIZE + 1 {
+            return Err(ParseError::Incomplete);
+        }
+
+        let (header, rest) = buffer.split_at(VAL_TTL_HEADER_SIZE);
+        let (inner, inner_size) = crate::socket_wrapper::parse_pdu(rest)?;
+        let mut buffer = header;
+
+        let pdu = ValTtlPdu {
+            pdu_type: read_be_u8(&mut buffer),
+            ttl: read_be_u32(&mut buffer),
+            inner: Box::new(inner),
+        };
+        Ok((pdu, VAL_TTL_HEADER_SIZE + inner_size))
+    }
+}
+
+impl From<ValTtlPdu> for PDU {
+    fn from(pdu: ValTtlPdu) -> Self {
+        Self::ValTtl(pdu)
+    }
+}
+
+/// Requests the entries with a hash in `range_start..=range_end`.
+///
+/// The request is routed to the node responsible for `range_start`, which
+/// answers with at most `limit` entries from its part of the range, in hash and
+/// ssn order, starting after the entry with the ssn `cursor`.
+pub struct ValScanPdu {
+    pub pdu_type: u8,
+    pub range_start: u64,
+    pub range_end: u64,
+    pub limit: u8,
+    pub cursor: Option<String>,
+    pub sender_address: u32,
+    pub sender_port: u16,
+}
+
+impl ValScanPdu {
+    pub fn new(
+        range_start: u64,
+        range_end: u64,
+        limit: u8,
+        cursor: Option<String>,
+        sender_address: u32,
+        sender_port: u16,
+    ) -> Self {
+        ValScanPdu {
+            pdu_type: VAL_SCAN_ID,
+            range_start,
+            range_end,
+            limit,
+            cursor,
+            sender_address,
+            sender_port,
+        }
+    }
+
+    pub fn get_sender_addr(&self) -> SocketAddr {
+        let ip: Ipv4Addr = self.sender_address.into();
+        (ip, self.sender_port).into()
+    }
+}
+
+impl From<ValScanPdu> for Vec<u8> {
+    fn from(pdu: ValScanPdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        write_hash(&mut v, pdu.range_start, true);
+        write_hash(&mut v, pdu.range_end, true);
+        v.push(pdu.limit);
+        write_ssn(&mut v, pdu.cursor.as_deref().unwrap_or(""));
+        v.extend_from_slice(&pdu.sender_address.to_be_bytes());
+        v.extend_from_slice(&pdu.sender_port.to_be_bytes());
+        v
+    }
+}
+
+impl ParsePdu for ValScanPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let size = VAL_SCAN_SIZE;
+        if buffer.len() < size {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu = ValScanPdu {
+            pdu_type: read_be_u8(&mut buffer),
+            range_start: read_be_u64(&mut buffer),
+            range_end: read_be_u64(&mut buffer),
+            limit: read_be_u8(&mut buffer),
+            cursor: read_optional_ssn(&mut buffer),
+            sender_address: read_be_u32(&mut buffer),
+            sender_port: read_be_u16(&mut buffer),
+        };
+        Ok((pdu, size))
+    }
+}
+
+impl From<ValScanPdu> for PDU {
+    fn from(pdu: ValScanPdu) -> Self {
+        Self::ValScan(pdu)
+    }
+}
+
+pub struct ScanEntry {
+    pub ssn: String,
+    pub name: String,
+    pub email: String,
+}
+
+impl ScanEntry {
+    /// Bytes the entry takes in a PDU.
+    pub fn size(&self) -> usize {
+        SSN_LENGTH + 2 + self.name.len() + 2 + self.email.len()
+    }
+
+    /// How many of `entries`, from the start, fit within `MAX_ENTRIES_SIZE`.
+    /// At least one, since a single entry always fits.
+    pub fn fitting(entries: &[ScanEntry]) -> usize {
+        let mut size = 0;
+        let count = entries
+            .iter()
+            .take_while(|e| {
+                size += e.size();
+                size <= MAX_ENTRIES_SIZE
+            })
+            .count();
+        count.max(1).min(entries.len())
+    }
+
+    fn write(self, v: &mut Vec<u8>) {
+        write_ssn(v, &self.ssn);
+        write_text(v, &self.name, true);
+        write_text(v, &self.email, true);
+    }
+
+    /// Reads `count` entries, returns them and the number of bytes they used.
+    fn try_read_all(buffer: &[u8], count: u8) -> Result<(Vec<Self>, usize), ParseError> {
+        let mut rest = buffer;
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            if rest.len() < SSN_LENGTH {
+                return Err(ParseError::Incomplete);
+            }
+            let (ssn, mut after) = rest.split_at(SSN_LENGTH);
+            let name = read_text(&mut after, true)?;
+            let email = read_text(&mut after, true)?;
+
+            entries.push(ScanEntry {
+                ssn: ssn.iter().map(|&x| x as char).collect(),
+                name,
+                email,
+            });
+            rest = after;
+        }
+        Ok((entries, buffer.len() - rest.len()))
+    }
+}
+
+/// One page of a scan, covering `range_start..=range_end`. If `more` is set
+/// there are entries left in the range, and the next page is requested with
+/// `cursor`. Otherwise the scan continues at `range_end + 1`.
+pub struct ValScanResponsePdu {
+    pub pdu_type: u8,
+    pub range_start: u64,
+    pub range_end: u64,
+    pub more: bool,
+    pub cursor: Option<String>,
+    pub entries: Vec<ScanEntry>,
+}
+
+impl ValScanResponsePdu {
+    pub fn new(
+        range_start: u64,
+        range_end: u64,
+        cursor: Option<String>,
+        entries: Vec<ScanEntry>,
+    ) -> Self {
+        assert_entry_count(entries.len());
+        ValScanResponsePdu {
+            pdu_type: VAL_SCAN_RESPONSE_ID,
+            range_start,
+            range_end,
+            more: cursor.is_some(),
+            cursor,
+            entries,
+        }
+    }
+}
+
+impl From<ValScanResponsePdu> for Vec<u8> {
+    fn from(pdu: ValScanResponsePdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        write_hash(&mut v, pdu.range_start, true);
+        write_hash(&mut v, pdu.range_end, true);
+        v.push(pdu.more as u8);
+        write_ssn(&mut v, pdu.cursor.as_deref().unwrap_or(""));
+        v.push(pdu.entries.len() as u8);
+        for e in pdu.entries {
+            e.write(&mut v);
+        }
+        v
+    }
+}
+
+impl ParsePdu for ValScanResponsePdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_SCAN_RESPONSE_HEADER_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu_type = read_be_u8(&mut buffer);
+        let range_start = read_be_u64(&mut buffer);
+        let range_end = read_be_u64(&mut buffer);
+        let more = read_be_u8(&mut buffer) != 0;
+        let cursor = read_optional_ssn(&mut buffer);
+        let count = read_be_u8(&mut buffer);
+        let (entries, entries_size) = ScanEntry::try_read_all(buffer, count)?;
+
+        let pdu = ValScanResponsePdu {
+            pdu_type,
+            range_start,
+            range_end,
+            more,
+            cursor,
+            entries,
+        };
+        Ok((pdu, VAL_SCAN_RESPONSE_HEADER_SIZE + entries_size))
+    }
+}
+
+impl From<ValScanResponsePdu> for PDU {
+    fn from(pdu: ValScanResponsePdu) -> Self {
+        Self::ValScanResponse(pdu)
+    }
+}
+
+/// Carries several inserts and removes in one message, for bulk loads.
+///
+/// Each entry is a `ValInsertPdu` or `ValRemovePdu`, an insert may be wrapped
+/// in a `ValTtlPdu`. Nodes apply the entries they are responsible for and
+/// forward the rest as one batch.
+pub struct ValBatchPdu {
+    pub pdu_type: u8,
+    pub entries: Vec<PDU>,
+}
+
+impl ValBatchPdu {
+    pub fn new(entries: Vec<PDU>) -> Self {
+        assert_entry_count(entries.len());
+        ValBatchPdu {
+            pdu_type: VAL_BATCH_ID,
+            entries,
+        }
+    }
+
+    /// Bytes a batch of `entries` takes when sent.
+    pub fn size_of(entries: &[PDU]) -> usize {
+        VAL_BATCH_HEADER_SIZE + entries.iter().map(Self::entry_size).sum::<usize>()
+    }
+
+    /// Bytes `entry` takes in a batch. Panics if a batch can't hold it.
+    pub fn entry_size(entry: &PDU) -> usize {
+        match entry {
+            PDU::ValInsert(p) => p.size(),
+            PDU::ValRemove(_) => VAL_REMOVE_SIZE,
+            PDU::ValTtl(p) => VAL_TTL_HEADER_SIZE + Self::entry_size(&p.inner),
+            x => panic!("A batch can't hold {:?}", x),
+        }
+    }
+}
+
+impl From<ValBatchPdu> for Vec<u8> {
+    fn from(pdu: ValBatchPdu) -> Self {
+        let mut v = vec![pdu.pdu_type, pdu.entries.len() as u8];
+        for e in pdu.entries {
+            v.extend(e.to_bytes());
+        }
+        v
+    }
+}
+
+impl ParsePdu for ValBatchPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_BATCH_HEADER_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu_type = read_be_u8(&mut buffer);
+        let count = read_be_u8(&mut buffer);
+
+        let mut size = VAL_BATCH_HEADER_SIZE;
+        let mut entries = Vec::with_capacity(count.into());
+        for _ in 0..count {
+            let (entry, entry_size) = crate::socket_wrapper::parse_pdu(buffer)?;
+            buffer = &buffer[entry_size..];
+            size += entry_size;
+            entries.push(entry);
+        }
+
+        let pdu = ValBatchPdu { pdu_type, entries };
+        Ok((pdu, size))
+    }
+}
+
+impl From<ValBatchPdu> for PDU {
+    fn from(pdu: ValBatchPdu) -> Self {
+        Self::ValBatch(pdu)
+    }
+}
+
+/// Asks to be sent a `ValNotifyPdu` whenever an entry with the ssn, or with a
+/// hash in `range_start..=range_end` if there is no ssn, changes.
+///
+/// The subscription lasts `lease` seconds and is renewed by sending the same
+/// request again, a lease of 0 ends it.
+pub struct ValSubscribePdu {
+    pub pdu_type: u8,
+    pub range_start: u64,
+    pub range_end: u64,
+    pub ssn: Option<String>,
+    pub lease: u32,
+    pub sender_address: u32,
+    pub sender_port: u16,
+}
+
+impl ValSubscribePdu {
+    pub fn new(
+        range_start: u64,
+        range_end: u64,
+        ssn: Option<String>,
+        lease: u32,
+        sender_address: u32,
+        sender_port: u16,
+    ) -> Self {
+        ValSubscribePdu {
+            pdu_type: VAL_SUBSCRIBE_ID,
+            range_start,
+            range_end,
+            ssn,
+            lease,
+            sender_address,
+            sender_port,
+        }
+    }
+
+    pub fn get_sender_addr(&self) -> SocketAddr {
+        let ip: Ipv4Addr = self.sender_address.into();
+        (ip, self.sender_port).into()
+    }
+}
+
+impl From<ValSubscribePdu> for Vec<u8> {
+    fn from(pdu: ValSubscribePdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        write_hash(&mut v, pdu.range_start, true);
+        write_hash(&mut v, pdu.range_end, true);
+        write_ssn(&mut v, pdu.ssn.as_deref().unwrap_or(""));
+        v.extend_from_slice(&pdu.lease.to_be_bytes());
+        v.extend_from_slice(&pdu.sender_address.to_be_bytes());
+        v.extend_from_slice(&pdu.sender_port.to_be_bytes());
+        v
+    }
+}
+
+impl ParsePdu for ValSubscribePdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        let size = VAL_SUBSCRIBE_SIZE;
+        if buffer.len() < size {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu = ValSubscribePdu {
+            pdu_type: read_be_u8(&mut buffer),
+            range_start: read_be_u64(&mut buffer),
+            range_end: read_be_u64(&mut buffer),
+            ssn: read_optional_ssn(&mut buffer),
+            lease: read_be_u32(&mut buffer),
+            sender_address: read_be_u32(&mut buffer),
+            sender_port: read_be_u16(&mut buffer),
+        };
+        Ok((pdu, size))
+    }
+}
+
+impl From<ValSubscribePdu> for PDU {
+    fn from(pdu: ValSubscribePdu) -> Self {
+        Self::ValSubscribe(pdu)
+    }
+}
+
+/// Sent to subscribers when an entry is inserted, replaced or removed. For a
+/// removal the name and email are the last values of the entry.
+pub struct ValNotifyPdu {
+    pub pdu_type: u8,
+    pub removed: bool,
+    pub ssn: String,
+    pub version: u64,
+    pub name: String,
+    pub email: String,
+}
+
+impl ValNotifyPdu {
+    pub fn new(removed: bool, ssn: String, version: u64, name: String, email: String) -> Self {
+        ValNotifyPdu {
+            pdu_type: VAL_NOTIFY_ID,
+            removed,
+            ssn,
+            version,
+            name,
+            email,
+        }
+    }
+}
+
+impl From<ValNotifyPdu> for Vec<u8> {
+    fn from(pdu: ValNotifyPdu) -> Self {
+        let mut v = vec![pdu.pdu_type, pdu.removed as u8];
+        write_ssn(&mut v, &pdu.ssn);
+        v.extend_from_slice(&pdu.version.to_be_bytes());
+        write_text(&mut v, &pdu.name, true);
+        write_text(&mut v, &pdu.email, true);
+        v
+    }
+}
+
+impl ParsePdu for ValNotifyPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_NOTIFY_HEADER_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut rest = buffer;
+        let pdu_type = read_be_u8(&mut rest);
+        let removed = read_be_u8(&mut rest) != 0;
+        let (ssn, mut rest) = rest.split_at(SSN_LENGTH);
+        let version = read_be_u64(&mut rest);
+        let name = read_text(&mut rest, true)?;
+        let email = read_text(&mut rest, true)?;
+
+        let pdu = ValNotifyPdu {
+            pdu_type,
+            removed,
+            ssn: ssn.iter().map(|&x| x as char).collect(),
+            version,
+            name,
+            email,
+        };
+        Ok((pdu, buffer.len() - rest.len()))
+    }
+}
+
+impl From<ValNotifyPdu> for PDU {
+    fn from(pdu: ValNotifyPdu) -> Self {
+        Self::ValNotify(pdu)
+    }
+}
+
+/// Searches for the entries whose `field` equals `value`, ignoring ASCII case.
+///
+/// The query is routed to the node responsible for `range_start`, which sends
+/// its matches to the sender and passes the query on with `range_start` set
+/// past its own range, until the whole ring has answered.
+pub struct ValQueryPdu {
+    pub pdu_type: u8,
+    pub field: u8,
+    pub range_start: u64,
+    pub sender_address: u32,
+    pub sender_port: u16,
+    pub value: String,
+}
+
+impl ValQueryPdu {
+    pub fn new(
+        field: u8,
+        value: String,
+        range_start: u64,
+        sender_address: u32,
+        sender_port: u16,
+    ) -> Self {
+        ValQueryPdu {
+            pdu_type: VAL_QUERY_ID,
+            field,
+            range_start,
+            sender_address,
+            sender_port,
+            value,
+        }
+    }
+
+    pub fn get_sender_addr(&self) -> SocketAddr {
+        let ip: Ipv4Addr = self.sender_address.into();
+        (ip, self.sender_port).into()
+    }
+}
+
+impl From<ValQueryPdu> for Vec<u8> {
+    fn from(pdu: ValQueryPdu) -> Self {
+        let mut v = vec![pdu.pdu_type, pdu.field];
+        write_hash(&mut v, pdu.range_start, true);
+        v.extend_from_slice(&pdu.sender_address.to_be_bytes());
+        v.extend_from_slice(&pdu.sender_port.to_be_bytes());
+        write_text(&mut v, &pdu.value, true);
+        v
+    }
+}
+
+impl ParsePdu for ValQueryPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_QUERY_HEADER_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut rest = buffer;
+        let pdu_type = read_be_u8(&mut rest);
+        let field = read_be_u8(&mut rest);
+        let range_start = read_be_u64(&mut rest);
+        let sender_address = read_be_u32(&mut rest);
+        let sender_port = read_be_u16(&mut rest);
+        let value = read_text(&mut rest, true)?;
+
+        let pdu = ValQueryPdu {
+            pdu_type,
+            field,
+            range_start,
+            sender_address,
+            sender_port,
+            value,
+        };
+        Ok((pdu, buffer.len() - rest.len()))
+    }
+}
+
+impl From<ValQueryPdu> for PDU {
+    fn from(pdu: ValQueryPdu) -> Self {
+        Self::ValQuery(pdu)
+    }
+}
+
+/// The matches in `range_start..=range_end` for a query. A node with more
+/// matches than fit in one response sends `parts` responses, numbered by `part`.
+pub struct ValQueryResponsePdu {
+    pub pdu_type: u8,
+    pub range_start: u64,
+    pub range_end: u64,
+    pub part: u8,
+    pub parts: u8,
+    pub entries: Vec<ScanEntry>,
+}
+
+impl ValQueryResponsePdu {
+    pub fn new(
+        range_start: u64,
+        range_end: u64,
+        part: u8,
+        parts: u8,
+        entries: Vec<ScanEntry>,
+    ) -> Self {
+        assert_entry_count(entries.len());
+        ValQueryResponsePdu {
+            pdu_type: VAL_QUERY_RESPONSE_ID,
+            range_start,
+            range_end,
+            part,
+            parts,
+            entries,
+        }
+    }
+}
+
+impl From<ValQueryResponsePdu> for Vec<u8> {
+    fn from(pdu: ValQueryResponsePdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        write_hash(&mut v, pdu.range_start, true);
+        write_hash(&mut v, pdu.range_end, true);
+        v.extend_from_slice(&[pdu.part, pdu.parts, pdu.entries.len() as u8]);
+        for e in pdu.entries {
+            e.write(&mut v);
+        }
+        v
+    }
+}
+
+impl ParsePdu for ValQueryResponsePdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_QUERY_RESPONSE_HEADER_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu_type = read_be_u8(&mut buffer);
+        let range_start = read_be_u64(&mut buffer);
+        let range_end = read_be_u64(&mut buffer);
+        let part = read_be_u8(&mut buffer);
+        let parts = read_be_u8(&mut buffer);
+        let count = read_be_u8(&mut buffer);
+        let (entries, entries_size) = ScanEntry::try_read_all(buffer, count)?;
+
+        let pdu = ValQueryResponsePdu {
+            pdu_type,
+            range_start,
+            range_end,
+            part,
+            parts,
+            entries,
+        };
+        Ok((pdu, VAL_QUERY_RESPONSE_HEADER_SIZE + entries_size))
+    }
+}
+
+impl From<ValQueryResponsePdu> for PDU {
+    fn from(pdu: ValQueryResponsePdu) -> Self {
+        Self::ValQueryResponse(pdu)
+    }
+}
+
+/// Stores `value` under `key`, replacing any earlier value. Both are arbitrary
+/// bytes, and the key is hashed like an ssn.
+pub struct ValKvPutPdu {
+    pub pdu_type: u8,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl ValKvPutPdu {
+    pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
+        ValKvPutPdu {
+            pdu_type: VAL_KV_PUT_ID,
+            key,
+            value,
+        }
+    }
+}
+
+impl From<ValKvPutPdu> for Vec<u8> {
+    fn from(pdu: ValKvPutPdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        v.extend_from_slice(&(pdu.key.len() as u16).to_be_bytes());
+        v.extend_from_slice(&(pdu.value.len() as u32).to_be_bytes());
+        v.extend(pdu.key);
+        v.extend(pdu.value);
+        v
+    }
+}
+
+impl ParsePdu for ValKvPutPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_KV_PUT_HEADER_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu_type = read_be_u8(&mut buffer);
+        let key_length = read_be_u16(&mut buffer) as usize;
+        let value_length = read_be_u32(&mut buffer) as usize;
+        if buffer.len() < key_length + value_length {
+            return Err(ParseError::Incomplete);
+        }
+        let (key, rest) = buffer.split_at(key_length);
+
+        let pdu = ValKvPutPdu {
+            pdu_type,
+            key: key.to_vec(),
+            value: rest[..value_length].to_vec(),
+        };
+        Ok((pdu, VAL_KV_PUT_HEADER_SIZE + key_length + value_length))
+    }
+}
+
+impl From<ValKvPutPdu> for PDU {
+    fn from(pdu: ValKvPutPdu) -> Self {
+        Self::ValKvPut(pdu)
+    }
+}
+
+pub struct ValKvDeletePdu {
+    pub pdu_type: u8,
+    pub key: Vec<u8>,
+}
+
+impl ValKvDeletePdu {
+    pub fn new(key: Vec<u8>) -> Self {
+        ValKvDeletePdu {
+            pdu_type: VAL_KV_DELETE_ID,
+            key,
+        }
+    }
+}
+
+impl From<ValKvDeletePdu> for Vec<u8> {
+    fn from(pdu: ValKvDeletePdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        v.extend_from_slice(&(pdu.key.len() as u16).to_be_bytes());
+        v.extend(pdu.key);
+        v
+    }
+}
+
+impl ParsePdu for ValKvDeletePdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_KV_DELETE_HEADER_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu_type = read_be_u8(&mut buffer);
+        let key_length = read_be_u16(&mut buffer) as usize;
+        if buffer.len() < key_length {
+            return Err(ParseError::Incomplete);
+        }
+
+        let pdu = ValKvDeletePdu {
+            pdu_type,
+            key: buffer[..key_length].to_vec(),
+        };
+        Ok((pdu, VAL_KV_DELETE_HEADER_SIZE + key_length))
+    }
+}
+
+impl From<ValKvDeletePdu> for PDU {
+    fn from(pdu: ValKvDeletePdu) -> Self {
+        Self::ValKvDelete(pdu)
+    }
+}
+
+/// Asks for the value of `key`, which is sent in a `ValKvGetResponsePdu`.
+pub struct ValKvGetPdu {
+    pub pdu_type: u8,
+    pub sender_address: u32,
+    pub sender_port: u16,
+    pub key: Vec<u8>,
+}
+
+impl ValKvGetPdu {
+    pub fn new(key: Vec<u8>, sender_address: u32, sender_port: u16) -> Self {
+        ValKvGetPdu {
+            pdu_type: VAL_KV_GET_ID,
+            sender_address,
+            sender_port,
+            key,
+        }
+    }
+
+    pub fn get_sender_addr(&self) -> SocketAddr {
+        let ip: Ipv4Addr = self.sender_address.into();
+        (ip, self.sender_port).into()
+    }
+}
+
+impl From<ValKvGetPdu> for Vec<u8> {
+    fn from(pdu: ValKvGetPdu) -> Self {
+        let mut v = vec![pdu.pdu_type];
+        v.extend_from_slice(&pdu.sender_address.to_be_bytes());
+        v.extend_from_slice(&pdu.sender_port.to_be_bytes());
+        v.extend_from_slice(&(pdu.key.len() as u16).to_be_bytes());
+        v.extend(pdu.key);
+        v
+    }
+}
+
+impl ParsePdu for ValKvGetPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_KV_GET_HEADER_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu_type = read_be_u8(&mut buffer);
+        let sender_address = read_be_u32(&mut buffer);
+        let sender_port = read_be_u16(&mut buffer);
+        let key_length = read_be_u16(&mut buffer) as usize;
+        if buffer.len() < key_length {
+            return Err(ParseError::Incomplete);
+        }
+
+        let pdu = ValKvGetPdu {
+            pdu_type,
+            sender_address,
+            sender_port,
+            key: buffer[..key_length].to_vec(),
+        };
+        Ok((pdu, VAL_KV_GET_HEADER_SIZE + key_length))
+    }
+}
+
+impl From<ValKvGetPdu> for PDU {
+    fn from(pdu: ValKvGetPdu) -> Self {
+        Self::ValKvGet(pdu)
+    }
+}
+
+/// Answer to a `ValKvGetPdu`. If the key is not stored `found` is false and
+/// the value is empty.
+pub struct ValKvGetResponsePdu {
+    pub pdu_type: u8,
+    pub found: bool,
+    pub version: u64,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl ValKvGetResponsePdu {
+    pub fn new(key: Vec<u8>, value: Option<(Vec<u8>, u64)>) -> Self {
+        let (found, value, version) = match value {
+            Some((value, version)) => (true, value, version),
+            None => (false, Vec::new(), 0),
+        };
+        ValKvGetResponsePdu {
+            pdu_type: VAL_KV_GET_RESPONSE_ID,
+            found,
+            version,
+            key,
+            value,
+        }
+    }
+}
+
+impl From<ValKvGetResponsePdu> for Vec<u8> {
+    fn from(pdu: ValKvGetResponsePdu) -> Self {
+        let mut v = vec![pdu.pdu_type, pdu.found as u8];
+        v.extend_from_slice(&pdu.version.to_be_bytes());
+        v.extend_from_slice(&(pdu.key.len() as u16).to_be_bytes());
+        v.extend_from_slice(&(pdu.value.len() as u32).to_be_bytes());
+        v.extend(pdu.key);
+        v.extend(pdu.value);
+        v
+    }
+}
+
+impl ParsePdu for ValKvGetResponsePdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < VAL_KV_GET_RESPONSE_HEADER_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu_type = read_be_u8(&mut buffer);
+        let found = read_be_u8(&mut buffer) != 0;
+        let version = read_be_u64(&mut buffer);
+        let key_length = read_be_u16(&mut buffer) as usize;
+        let value_length = read_be_u32(&mut buffer) as usize;
+        if buffer.len() < key_length + value_length {
+            return Err(ParseError::Incomplete);
+        }
+        let (key, rest) = buffer.split_at(key_length);
+
+        let pdu = ValKvGetResponsePdu {
+            pdu_type,
+            found,
+            version,
+            key: key.to_vec(),
+            value: rest[..value_length].to_vec(),
+        };
+        let size = VAL_KV_GET_RESPONSE_HEADER_SIZE + key_length + value_length;
+        Ok((pdu, size))
+    }
+}
+
+impl From<ValKvGetResponsePdu> for PDU {
+    fn from(pdu: ValKvGetResponsePdu) -> Self {
+        Self::ValKvGetResponse(pdu)
+    }
+}
+
+/// Sent to the originator of a request that a node rejected or could not route.
+///
+/// `request_type` is the type of the rejected PDU and `code` is one of the
+/// `ERROR_*` constants. The message is UTF-8 and at most 255 bytes long.
+pub struct ErrorPdu {
+    pub pdu_type: u8,
+    pub code: u8,
+    pub request_type: u8,
+    pub message_length: u8,
+    pub message: String,
+}
+
+impl ErrorPdu {
+    pub fn new(code: u8, request_type: u8, message: String) -> Self {
+        let mut message = message;
+        let mut end = message.len().min(u8::MAX as usize);
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+
+        ErrorPdu {
+            pdu_type: ERROR_ID,
+            code,
+            request_type,
+            message_length: message.len() as u8,
+            message,
+        }
+    }
+
+    /// Short description of the error code.
+    pub fn code_name(&self) -> &'static str {
+        match self.code {
+            ERROR_HOP_LIMIT_EXCEEDED => "hop limit exceeded",
+            ERROR_NO_SUCCESSOR => "no successor",
+            ERROR_MALFORMED_SSN => "malformed ssn",
+            ERROR_STORAGE_FULL => "storage full",
+            ERROR_INVALID_STATE => "invalid state",
+            ERROR_TOO_LARGE => "too large",
+            ERROR_UNAUTHORIZED => "unauthorized",
+            ERROR_INVALID_PDU => "invalid pdu",
+            _ => "unknown error",
+        }
+    }
+}
+
+impl From<ErrorPdu> for Vec<u8> {
+    fn from(pdu: ErrorPdu) -> Self {
+        let mut v = vec![pdu.pdu_type, pdu.code, pdu.request_type, pdu.message_length];
+        v.extend_from_slice(pdu.message.as_bytes());
+        v
+    }
+}
+
+impl ParsePdu for ErrorPdu {
+    fn try_parse(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+        if buffer.len() < ERROR_HEADER_SIZE {
+            return Err(ParseError::Incomplete);
+        }
+
+        let mut buffer = buffer;
+        let pdu_type = read_be_u8(&mut buffer);
+        let code = read_be_u8(&mut buffer);
+        let request_type = read_be_u8(&mut buffer);
+        let message_length = read_be_u8(&mut buffer);
+
+        if buffer.len() < message_length as usize {
+            return Err(ParseError::Incomplete);
+        }
+
+        let message = String::from_utf8_lossy(&buffer[..message_length as usize]).into_owned();
+
+        let pdu = ErrorPdu {
+            pdu_type,
+            code,
+            request_type,
+            message_length,
+            message,
+        };
+        Ok((pdu, ERROR_HEADER_SIZE + message_length as usize))
+    }
+}
+
+impl From<ErrorPdu> for PDU {
+    fn from(pdu: ErrorPdu) -> Self {
+        Self::Error(pdu)
+    }
+}
+
+#[cfg(test)]
+mod serialization_test {
+    use crate::pdu::*;
+
+    #[test]
+    fn test_ssn() {
+        let ssn = Ssn::parse("197223058909").unwrap();
+        assert_eq!(ssn.as_str(), "197223058909");
+        assert_eq!(Ssn::parse(" 19722305-8909\n"), Ok(ssn.clone()));
+        assert_eq!(Ssn::parse("aaaaabbbbbcc"), Err(SsnError::NotDigits));
+        assert_eq!(Ssn::parse("1972230-58909"), Err(SsnError::NotDigits));
+        assert_eq!(Ssn::parse("12ab\0\0\0\0\0\0\0\0"), Err(SsnError::NotDigits));
+        assert_eq!(Ssn::parse("19722305890"), Err(SsnError::Length(11)));
+        assert_eq!(Ssn::parse("19722305-89091"), Err(SsnError::Length(13)));
+        assert_eq!(ssn.to_string().parse(), Ok(ssn));
+    }
+
+    #[test]
+    fn test_ssn_luhn() {
+        let valid = Ssn::parse("19811218-9876").unwrap();
+        assert!(valid.luhn_valid());
+        assert!(!Ssn::parse("198112189875").unwrap().luhn_valid());
+        assert!(Ssn::parse_checked("198112189876", true).is_ok());
+        let invalid = Ssn::parse_checked("198112189875", true);
+        assert_eq!(invalid, Err(SsnError::Checksum));
+        assert!(Ssn::parse_checked("198112189875", false).is_ok());
+    }
+    #[test]
+    fn test_net_alive() {
+        let a = NetAlivePdu::new();
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), NET_ALIVE_SIZE);
+        let (_a, b) = NetAlivePdu::try_parse(&b).unwrap();
+        assert_eq!(b, NET_ALIVE_SIZE);
+    }
 
     #[test]
     fn test_net_get_node() {
         let a = NetGetNodePdu::new();
         let b: Vec<u8> = a.into();
         assert_eq!(b.len(), NET_GET_NODE_SIZE);
-        let (a, b) = NetGetNodePdu::try_parse(&b).unwrap();
+        let (_a, b) = NetGetNodePdu::try_parse(&b).unwrap();
         assert_eq!(b, NET_GET_NODE_SIZE);
     }
 
@@ -968,6 +3219,24 @@ mod serialization_test {
         assert_eq!(1123, a.max_port);
     }
 
+    #[test]
+    fn test_net_join_wide() {
+        let span = u64::from(u32::MAX);
+        let a = NetJoinPdu::new(1010, 1011, span, 1122, 1123);
+        assert_eq!(a.pdu_type, NET_JOIN_WIDE_ID);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), NET_JOIN_WIDE_SIZE);
+        assert_eq!(
+            NetJoinPdu::try_parse(&b[..NET_JOIN_SIZE]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = NetJoinPdu::try_parse(&b).unwrap();
+        assert_eq!(b, NET_JOIN_WIDE_SIZE);
+        assert_eq!(span, a.max_span);
+        assert_eq!(1122, a.max_address);
+        assert_eq!(1123, a.max_port);
+    }
+
     #[test]
     fn test_net_join_response() {
         let a = NetJoinResponsePdu::new(123456, 1234, 10, 20);
@@ -981,12 +3250,29 @@ mod serialization_test {
         assert_eq!(20, a.range_end);
     }
 
+    #[test]
+    fn test_net_join_response_wide() {
+        let a = NetJoinResponsePdu::new(123456, 1234, 256, u64::MAX);
+        assert_eq!(a.pdu_type, NET_JOIN_RESPONSE_WIDE_ID);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), NET_JOIN_RESPONSE_WIDE_SIZE);
+        assert_eq!(
+            NetJoinResponsePdu::try_parse(&b[..b.len() - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = NetJoinResponsePdu::try_parse(&b).unwrap();
+        assert_eq!(b, NET_JOIN_RESPONSE_WIDE_SIZE);
+        assert_eq!(1234, a.next_port);
+        assert_eq!(256, a.range_start);
+        assert_eq!(u64::MAX, a.range_end);
+    }
+
     #[test]
     fn test_net_close_connection() {
         let a = NetCloseConnectionPdu::new();
         let b: Vec<u8> = a.into();
         assert_eq!(b.len(), NET_CLOSE_CONNECTION_SIZE);
-        let (a, b) = NetCloseConnectionPdu::try_parse(&b).unwrap();
+        let (_a, b) = NetCloseConnectionPdu::try_parse(&b).unwrap();
         assert_eq!(b, NET_CLOSE_CONNECTION_SIZE);
     }
 
@@ -1001,6 +3287,18 @@ mod serialization_test {
         assert_eq!(a.range_end, 255);
     }
 
+    #[test]
+    fn test_net_new_range_wide() {
+        let a = NetNewRangePdu::new(0, 1 << 40);
+        assert_eq!(a.pdu_type, NET_NEW_RANGE_WIDE_ID);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), NET_NEW_RANGE_WIDE_SIZE);
+        let (a, b) = NetNewRangePdu::try_parse(&b).unwrap();
+        assert_eq!(b, NET_NEW_RANGE_WIDE_SIZE);
+        assert_eq!(a.range_start, 0);
+        assert_eq!(a.range_end, 1 << 40);
+    }
+
     #[test]
     fn test_net_new_range_response() {
         let a = NetNewRangeResponsePdu::new();
@@ -1022,6 +3320,71 @@ mod serialization_test {
         assert_eq!(a.new_port, 255);
     }
 
+    #[test]
+    fn test_net_add_range() {
+        let a = NetAddRangePdu::new(128, u64::MAX);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), NET_ADD_RANGE_SIZE);
+        assert_eq!(
+            NetAddRangePdu::try_parse(&b[..NET_ADD_RANGE_SIZE - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = NetAddRangePdu::try_parse(&b).unwrap();
+        assert_eq!(b, NET_ADD_RANGE_SIZE);
+        assert_eq!(a.range_start, 128);
+        assert_eq!(a.range_end, u64::MAX);
+    }
+
+    #[test]
+    fn test_net_rebalance() {
+        let a = NetRebalancePdu::new(1 << 33);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), NET_REBALANCE_SIZE);
+        assert_eq!(
+            NetRebalancePdu::try_parse(&b[..NET_REBALANCE_SIZE - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = NetRebalancePdu::try_parse(&b).unwrap();
+        assert_eq!(b, NET_REBALANCE_SIZE);
+        assert_eq!(a.load, 1 << 33);
+    }
+
+    #[test]
+    fn test_net_control() {
+        let a = NetControlPdu::new(
+            CONTROL_HANDOFF_PREDECESSOR,
+            0x7f000001,
+            4000,
+            1 << 40,
+            b"secret",
+        );
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), NET_CONTROL_SIZE);
+        assert_eq!(
+            NetControlPdu::try_parse(&b[..NET_CONTROL_SIZE - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, size) = NetControlPdu::try_parse(&b).unwrap();
+        assert_eq!(size, NET_CONTROL_SIZE);
+        assert_eq!(a.command, CONTROL_HANDOFF_PREDECESSOR);
+        assert_eq!(a.get_target_addr(), "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(a.timestamp, 1 << 40);
+        assert!(a.verify(b"secret"));
+        assert!(!a.verify(b"other"));
+
+        let mut b = b;
+        b[1] = CONTROL_LEAVE;
+        let (a, _) = NetControlPdu::try_parse(&b).unwrap();
+        assert!(!a.verify(b"secret"));
+
+        let mut b: Vec<u8> =
+            NetControlPdu::new(CONTROL_LEAVE, 0x7f000001, 4000, 1, b"secret").into();
+        b[7] ^= 1;
+        let (a, _) = NetControlPdu::try_parse(&b).unwrap();
+        assert_eq!(a.target_port, 4001);
+        assert!(!a.verify(b"secret"));
+    }
+
     #[test]
     fn test_val_insert() {
         let ssn = "111111111111".to_owned();
@@ -1034,12 +3397,74 @@ mod serialization_test {
         let (a, b) = ValInsertPdu::try_parse(&b).unwrap();
         assert_eq!(b, len);
         assert_eq!(a.ssn, ssn);
-        assert_eq!(a.name_length, name.len() as u8);
+        assert_eq!(a.name_length, name.len() as u16);
         assert_eq!(a.name, name);
-        assert_eq!(a.email_length, email.len() as u8);
+        assert_eq!(a.email_length, email.len() as u16);
         assert_eq!(a.email, email);
     }
 
+    #[test]
+    fn test_val_insert_utf8() {
+        let name = "Åsa Öberg-Lindgärde".to_owned();
+        let a = ValInsertPdu::new("111111111111".to_owned(), name.clone(), "å@ä.se".to_owned());
+        assert_eq!(a.pdu_type, VAL_INSERT_ID);
+        let b: Vec<u8> = a.into();
+        let len = 1 + SSN_LENGTH + 1 + name.len() + 1 + "å@ä.se".len();
+        assert_eq!(b.len(), len);
+        let (a, b) = ValInsertPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.name, name);
+        assert_eq!(a.email, "å@ä.se");
+    }
+
+    #[test]
+    fn test_val_insert_wide() {
+        let name = "ö".repeat(200);
+        let a = ValInsertPdu::new("111111111111".to_owned(), name.clone(), "Emai".to_owned());
+        assert_eq!(a.pdu_type, VAL_INSERT_WIDE_ID);
+        let len = 1 + SSN_LENGTH + 2 + 400 + 2 + 4;
+        assert_eq!(a.size(), len);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValInsertPdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValInsertPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.pdu_type, VAL_INSERT_WIDE_ID);
+        assert_eq!(a.name_length, 400);
+        assert_eq!(a.name, name);
+        assert_eq!(a.email, "Emai");
+    }
+
+    #[test]
+    #[should_panic(expected = "Text longer than u16::MAX bytes")]
+    fn test_val_insert_too_long() {
+        let name = "a".repeat(u16::MAX as usize + 1);
+        ValInsertPdu::new("111111111111".to_owned(), name, String::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "Text too long for a one byte length")]
+    fn test_val_insert_narrow_too_long() {
+        let mut a = ValInsertPdu::new("111111111111".to_owned(), "a".repeat(256), String::new());
+        a.pdu_type = VAL_INSERT_ID;
+        let _: Vec<u8> = a.into();
+    }
+
+    #[test]
+    fn test_val_insert_invalid_utf8() {
+        let ssn = "111111111111".to_owned();
+        let a = ValInsertPdu::new(ssn, "Test".to_owned(), "Emai".to_owned());
+        let mut b: Vec<u8> = a.into();
+        b[1 + SSN_LENGTH + 1] = 0xff;
+        let error = ValInsertPdu::try_parse(&b).err();
+        assert_eq!(error, Some(ParseError::InvalidText));
+        let error = crate::socket_wrapper::parse_pdu(&b).err();
+        assert_eq!(error, Some(ParseError::InvalidText));
+    }
+
     #[test]
     fn test_val_remove() {
         let ssn = "111111111111".to_owned();
@@ -1051,6 +3476,15 @@ mod serialization_test {
         assert_eq!(a.ssn, ssn);
     }
 
+    #[test]
+    fn test_val_remove_short_ssn() {
+        let a = ValRemovePdu::new("12ab".to_owned());
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), VAL_REMOVE_SIZE);
+        let (a, _) = ValRemovePdu::try_parse(&b).unwrap();
+        assert_eq!(a.ssn, "12ab\0\0\0\0\0\0\0\0");
+    }
+
     #[test]
     fn test_val_lookup() {
         let ssn = "111111111111".to_owned();
@@ -1069,25 +3503,519 @@ mod serialization_test {
         let ssn = "111111111111".to_owned();
         let name = "Test".to_owned();
         let email = "Emai".to_owned();
-        let a = ValLookupResponsePdu::new(ssn.clone(), name.clone(), email.clone());
+        let lookup = VAL_LOOKUP_ID;
+        let a = ValLookupResponsePdu::new(lookup, ssn.clone(), name.clone(), email.clone(), 42);
         let b: Vec<u8> = a.into();
+        // The response of the specification, without the version
         let len = 1 + SSN_LENGTH + 1 + name.len() + 1 + email.len();
         assert_eq!(b.len(), len);
+        assert_eq!(
+            ValLookupResponsePdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValLookupResponsePdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.pdu_type, VAL_LOOKUP_RESPONSE_ID);
+        assert_eq!(a.version, 0);
+        assert_eq!(a.ssn, ssn);
+        assert_eq!(a.name_length, name.len() as u16);
+        assert_eq!(a.name, name);
+        assert_eq!(a.email_length, email.len() as u16);
+        assert_eq!(a.email, email);
+    }
+
+    #[test]
+    fn test_val_lookup_response_versioned() {
+        let ssn = "111111111111".to_owned();
+        let lookup = ValLookupPdu::new_versioned(ssn.clone(), 12345, 1234);
+        let b: Vec<u8> = lookup.into();
+        let (lookup, _) = ValLookupPdu::try_parse(&b).unwrap();
+        assert_eq!(lookup.pdu_type, VAL_LOOKUP_VERSIONED_ID);
+
+        let name = "Test".to_owned();
+        let a = ValLookupResponsePdu::new(lookup.pdu_type, ssn, name, "Emai".into(), 42);
+        let b: Vec<u8> = a.into();
+        let len = 1 + SSN_LENGTH + 1 + 4 + 1 + 4 + 8;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValLookupResponsePdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValLookupResponsePdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.pdu_type, VAL_LOOKUP_RESPONSE_VERSIONED_ID);
+        assert_eq!(a.email, "Emai");
+        assert_eq!(a.version, 42);
+    }
+
+    #[test]
+    fn test_val_lookup_response_wide() {
+        let email = "e".repeat(300);
+        let ssn = "111111111111".to_owned();
+        let name = "Test".to_owned();
+        let a = ValLookupResponsePdu::new(VAL_LOOKUP_ID, ssn, name, email.clone(), 42);
+        let b: Vec<u8> = a.into();
+        let len = 1 + SSN_LENGTH + 2 + 4 + 2 + 300 + 8;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValLookupResponsePdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
         let (a, b) = ValLookupResponsePdu::try_parse(&b).unwrap();
         assert_eq!(b, len);
+        assert_eq!(a.pdu_type, VAL_LOOKUP_RESPONSE_WIDE_ID);
+        assert_eq!(a.email, email);
+        assert_eq!(a.version, 42);
+    }
+
+    #[test]
+    fn test_scan_entry_fitting() {
+        let entry = |name: String| ScanEntry {
+            ssn: "111111111111".to_owned(),
+            name,
+            email: String::new(),
+        };
+        let entries: Vec<ScanEntry> = (0..5).map(|_| entry("a".repeat(MAX_TEXT_LENGTH))).collect();
+        assert_eq!(ScanEntry::fitting(&entries), 3);
+        assert_eq!(ScanEntry::fitting(&entries[..1]), 1);
+        assert_eq!(ScanEntry::fitting(&[]), 0);
+        let small: Vec<ScanEntry> = (0..5).map(|_| entry("Test".to_owned())).collect();
+        assert_eq!(ScanEntry::fitting(&small), 5);
+    }
+
+    #[test]
+    fn test_val_trace() {
+        let ssn = "111111111111".to_owned();
+        let lookup = ValLookupPdu::new(ssn.clone(), 12345, 1234);
+        let a = ValTracePdu::new(0xdead_beef, 3, 54321, 4321, lookup.into());
+        let b: Vec<u8> = a.into();
+        let len = VAL_TRACE_HEADER_SIZE + VAL_LOOKUP_SIZE;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValTracePdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValTracePdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.trace_id, 0xdead_beef);
+        assert_eq!(a.hop_count, 3);
+        assert_eq!(a.report_address, 54321);
+        assert_eq!(a.report_port, 4321);
+        match *a.inner {
+            PDU::ValLookup(inner) => assert_eq!(inner.ssn, ssn),
+            x => panic!("Expected ValLookup, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_val_trace_hop() {
+        let a = ValTraceHopPdu::new(0xdead_beef, 2, 12345, 1234);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), VAL_TRACE_HOP_SIZE);
+        let (a, b) = ValTraceHopPdu::try_parse(&b).unwrap();
+        assert_eq!(b, VAL_TRACE_HOP_SIZE);
+        assert_eq!(a.trace_id, 0xdead_beef);
+        assert_eq!(a.hop, 2);
+        assert_eq!(a.node_address, 12345);
+        assert_eq!(a.node_port, 1234);
+    }
+
+    #[test]
+    fn test_val_hop_limit() {
+        let ssn = "111111111111".to_owned();
+        let remove = ValRemovePdu::new(ssn.clone());
+        let a = ValHopLimitPdu::new(7, 12345, 1234, remove.into());
+        let b: Vec<u8> = a.into();
+        let len = VAL_HOP_LIMIT_HEADER_SIZE + VAL_REMOVE_SIZE;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValHopLimitPdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValHopLimitPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.hop_limit, 7);
+        assert_eq!(a.get_origin_addr(), Some(([0, 0, 48, 57], 1234).into()));
+        match *a.inner {
+            PDU::ValRemove(inner) => assert_eq!(inner.ssn, ssn),
+            x => panic!("Expected ValRemove, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_val_cond_insert() {
+        let ssn = "111111111111".to_owned();
+        let name = "Test".to_owned();
+        let email = "Emai".to_owned();
+        let a = ValCondInsertPdu::new(ssn.clone(), name.clone(), email.clone(), 42, 12345, 1234);
+        let b: Vec<u8> = a.into();
+        let len = VAL_COND_REMOVE_SIZE + 2 + name.len() + 2 + email.len();
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValCondInsertPdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValCondInsertPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
         assert_eq!(a.ssn, ssn);
-        assert_eq!(a.name_length, name.len() as u8);
+        assert_eq!(a.expected_version, 42);
+        assert_eq!(a.get_sender_addr(), ([0, 0, 48, 57], 1234).into());
         assert_eq!(a.name, name);
-        assert_eq!(a.email_length, email.len() as u8);
         assert_eq!(a.email, email);
     }
 
+    #[test]
+    fn test_val_cond_remove() {
+        let ssn = "111111111111".to_owned();
+        let a = ValCondRemovePdu::new(ssn.clone(), 42, 12345, 1234);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), VAL_COND_REMOVE_SIZE);
+        let (a, b) = ValCondRemovePdu::try_parse(&b).unwrap();
+        assert_eq!(b, VAL_COND_REMOVE_SIZE);
+        assert_eq!(a.ssn, ssn);
+        assert_eq!(a.expected_version, 42);
+        assert_eq!(a.sender_port, 1234);
+    }
+
+    #[test]
+    fn test_val_cond_response() {
+        let ssn = "111111111111".to_owned();
+        let a = ValCondResponsePdu::new(VAL_COND_REMOVE_ID, true, ssn.clone(), 42);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), VAL_COND_RESPONSE_SIZE);
+        let (a, b) = ValCondResponsePdu::try_parse(&b).unwrap();
+        assert_eq!(b, VAL_COND_RESPONSE_SIZE);
+        assert_eq!(a.request_type, VAL_COND_REMOVE_ID);
+        assert!(a.success);
+        assert_eq!(a.ssn, ssn);
+        assert_eq!(a.version, 42);
+    }
+
+    #[test]
+    fn test_val_ttl() {
+        let ssn = "111111111111".to_owned();
+        let insert = ValInsertPdu::new(ssn.clone(), "Test".to_owned(), "Emai".to_owned());
+        let a = ValTtlPdu::new(3600, ValVersionPdu::new(42, insert.into()).into());
+        let b: Vec<u8> = a.into();
+        let len = VAL_TTL_HEADER_SIZE + VAL_VERSION_HEADER_SIZE + 1 + SSN_LENGTH + 1 + 4 + 1 + 4;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValTtlPdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValTtlPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.ttl, 3600);
+        match *a.inner {
+            PDU::ValVersion(inner) => assert_eq!(inner.version, 42),
+            x => panic!("Expected ValVersion, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_val_scan() {
+        let cursor = "111111111111".to_owned();
+        let a = ValScanPdu::new(10, 20, 5, Some(cursor.clone()), 12345, 1234);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), VAL_SCAN_SIZE);
+        let (a, b) = ValScanPdu::try_parse(&b).unwrap();
+        assert_eq!(b, VAL_SCAN_SIZE);
+        assert_eq!((a.range_start, a.range_end, a.limit), (10, 20, 5));
+        assert_eq!(a.cursor, Some(cursor));
+        assert_eq!(a.get_sender_addr(), ([0, 0, 48, 57], 1234).into());
+
+        let a = ValScanPdu::new(0, u64::MAX, 5, None, 12345, 1234);
+        let b: Vec<u8> = a.into();
+        let (a, _) = ValScanPdu::try_parse(&b).unwrap();
+        assert_eq!(a.range_end, u64::MAX);
+        assert_eq!(a.cursor, None);
+    }
+
+    #[test]
+    fn test_val_scan_response() {
+        let entries = vec![
+            ScanEntry {
+                ssn: "111111111111".to_owned(),
+                name: "Test".to_owned(),
+                email: "Emai".to_owned(),
+            },
+            ScanEntry {
+                ssn: "222222222222".to_owned(),
+                name: String::new(),
+                email: "E".to_owned(),
+            },
+        ];
+        let a = ValScanResponsePdu::new(10, 20, Some("222222222222".to_owned()), entries);
+        let b: Vec<u8> = a.into();
+        let len =
+            VAL_SCAN_RESPONSE_HEADER_SIZE + (SSN_LENGTH + 2 + 4 + 2 + 4) + (SSN_LENGTH + 4 + 1);
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValScanResponsePdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValScanResponsePdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!((a.range_start, a.range_end), (10, 20));
+        assert!(a.more);
+        assert_eq!(a.cursor.as_deref(), Some("222222222222"));
+        assert_eq!(a.entries.len(), 2);
+        assert_eq!(a.entries[0].name, "Test");
+        assert_eq!(a.entries[1].ssn, "222222222222");
+        assert_eq!(a.entries[1].email, "E");
+    }
+
+    #[test]
+    fn test_val_batch() {
+        let ssn = "111111111111".to_owned();
+        let insert = ValInsertPdu::new(ssn.clone(), "Test".to_owned(), "Emai".to_owned());
+        let empty = ValInsertPdu::new(ssn, String::new(), String::new());
+        let entries = vec![
+            insert.into(),
+            ValRemovePdu::new("222222222222".to_owned()).into(),
+            ValTtlPdu::new(60, empty.into()).into(),
+        ];
+        assert_eq!(
+            ValBatchPdu::size_of(&entries[2..]),
+            VAL_BATCH_HEADER_SIZE + VAL_TTL_HEADER_SIZE + 1 + SSN_LENGTH + 1 + 1
+        );
+        let size = ValBatchPdu::size_of(&entries);
+        let a = ValBatchPdu::new(entries);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), size);
+        let len = VAL_BATCH_HEADER_SIZE
+            + (1 + SSN_LENGTH + 1 + 4 + 1 + 4)
+            + VAL_REMOVE_SIZE
+            + (VAL_TTL_HEADER_SIZE + 1 + SSN_LENGTH + 1 + 1);
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValBatchPdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValBatchPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.entries.len(), 3);
+        assert_eq!(a.entries[0].value_ssn(), Some("111111111111"));
+        assert_eq!(a.entries[1].value_ssn(), Some("222222222222"));
+        match &a.entries[2] {
+            PDU::ValTtl(p) => assert_eq!(p.ttl, 60),
+            x => panic!("Expected ValTtl, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_parse_unknown_type() {
+        use crate::socket_wrapper::parse_pdu;
+        assert_eq!(parse_pdu(&[99, 0, 0]).err(), Some(ParseError::UnknownType));
+        assert_eq!(parse_pdu(&[]).err(), Some(ParseError::Incomplete));
+
+        let a = ValBatchPdu::new(vec![ValRemovePdu::new("222222222222".to_owned()).into()]);
+        let mut b: Vec<u8> = a.into();
+        b[VAL_BATCH_HEADER_SIZE] = 99;
+        assert_eq!(
+            ValBatchPdu::try_parse(&b).err(),
+            Some(ParseError::UnknownType)
+        );
+    }
+
+    #[test]
+    fn test_val_subscribe() {
+        let ssn = "111111111111".to_owned();
+        let a = ValSubscribePdu::new(0, 0, Some(ssn.clone()), 60, 12345, 1234);
+        let b: Vec<u8> = a.into();
+        assert_eq!(b.len(), VAL_SUBSCRIBE_SIZE);
+        let (a, b) = ValSubscribePdu::try_parse(&b).unwrap();
+        assert_eq!(b, VAL_SUBSCRIBE_SIZE);
+        assert_eq!(a.ssn, Some(ssn));
+        assert_eq!(a.lease, 60);
+        assert_eq!(a.get_sender_addr(), ([0, 0, 48, 57], 1234).into());
+
+        let a = ValSubscribePdu::new(10, 20, None, 0, 12345, 1234);
+        let b: Vec<u8> = a.into();
+        let (a, _) = ValSubscribePdu::try_parse(&b).unwrap();
+        assert_eq!((a.range_start, a.range_end, a.ssn), (10, 20, None));
+    }
+
+    #[test]
+    fn test_val_notify() {
+        let ssn = "111111111111".to_owned();
+        let a = ValNotifyPdu::new(true, ssn.clone(), 42, "Test".to_owned(), String::new());
+        let b: Vec<u8> = a.into();
+        let len = VAL_NOTIFY_HEADER_SIZE + 2 + 4 + 2;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValNotifyPdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValNotifyPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert!(a.removed);
+        assert_eq!(a.ssn, ssn);
+        assert_eq!(a.version, 42);
+        assert_eq!(a.name, "Test");
+        assert_eq!(a.email, "");
+    }
+
+    #[test]
+    fn test_val_query() {
+        let a = ValQueryPdu::new(QUERY_FIELD_EMAIL, "a@b.se".to_owned(), 10, 12345, 1234);
+        let b: Vec<u8> = a.into();
+        let len = VAL_QUERY_HEADER_SIZE + 6;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValQueryPdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValQueryPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!((a.field, a.range_start), (QUERY_FIELD_EMAIL, 10));
+        assert_eq!(a.value, "a@b.se");
+        assert_eq!(a.get_sender_addr(), ([0, 0, 48, 57], 1234).into());
+    }
+
+    #[test]
+    fn test_val_query_response() {
+        let entries = vec![ScanEntry {
+            ssn: "111111111111".to_owned(),
+            name: "Test".to_owned(),
+            email: "Emai".to_owned(),
+        }];
+        let a = ValQueryResponsePdu::new(10, 20, 1, 2, entries);
+        let b: Vec<u8> = a.into();
+        let len = VAL_QUERY_RESPONSE_HEADER_SIZE + SSN_LENGTH + 2 + 4 + 2 + 4;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValQueryResponsePdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValQueryResponsePdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!((a.range_start, a.range_end), (10, 20));
+        assert_eq!((a.part, a.parts), (1, 2));
+        assert_eq!(a.entries.len(), 1);
+        assert_eq!(a.entries[0].email, "Emai");
+    }
+
+    #[test]
+    fn test_val_kv_put() {
+        let value: Vec<u8> = (0..=255).collect();
+        let a = ValKvPutPdu::new(b"key\0".to_vec(), value.clone());
+        let b: Vec<u8> = a.into();
+        let len = VAL_KV_PUT_HEADER_SIZE + 4 + 256;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValKvPutPdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValKvPutPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.key, b"key\0");
+        assert_eq!(a.value, value);
+    }
+
+    #[test]
+    fn test_val_kv_delete() {
+        let a = ValKvDeletePdu::new(vec![0xff, 0]);
+        let b: Vec<u8> = a.into();
+        let len = VAL_KV_DELETE_HEADER_SIZE + 2;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValKvDeletePdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValKvDeletePdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.key, [0xff, 0]);
+    }
+
+    #[test]
+    fn test_val_kv_get() {
+        let a = ValKvGetPdu::new(b"key".to_vec(), 12345, 1234);
+        let b: Vec<u8> = a.into();
+        let len = VAL_KV_GET_HEADER_SIZE + 3;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValKvGetPdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValKvGetPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.key, b"key");
+        assert_eq!(a.get_sender_addr(), ([0, 0, 48, 57], 1234).into());
+    }
+
+    #[test]
+    fn test_val_kv_get_response() {
+        let a = ValKvGetResponsePdu::new(b"key".to_vec(), Some((vec![1, 2], 42)));
+        let b: Vec<u8> = a.into();
+        let len = VAL_KV_GET_RESPONSE_HEADER_SIZE + 3 + 2;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValKvGetResponsePdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValKvGetResponsePdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert!(a.found);
+        assert_eq!(a.key, b"key");
+        assert_eq!(a.value, [1, 2]);
+        assert_eq!(a.version, 42);
+
+        let a = ValKvGetResponsePdu::new(b"key".to_vec(), None);
+        let b: Vec<u8> = a.into();
+        let (a, _) = ValKvGetResponsePdu::try_parse(&b).unwrap();
+        assert!(!a.found);
+        assert!(a.value.is_empty());
+    }
+
+    #[test]
+    fn test_val_version() {
+        let ssn = "111111111111".to_owned();
+        let insert = ValInsertPdu::new(ssn.clone(), "Test".to_owned(), "Emai".to_owned());
+        let a = ValVersionPdu::new(1 << 40, insert.into());
+        let b: Vec<u8> = a.into();
+        let len = VAL_VERSION_HEADER_SIZE + 1 + SSN_LENGTH + 1 + 4 + 1 + 4;
+        assert_eq!(b.len(), len);
+        assert_eq!(
+            ValVersionPdu::try_parse(&b[..len - 1]).err(),
+            Some(ParseError::Incomplete)
+        );
+        let (a, b) = ValVersionPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.version, 1 << 40);
+        match *a.inner {
+            PDU::ValInsert(inner) => assert_eq!(inner.ssn, ssn),
+            x => panic!("Expected ValInsert, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_error() {
+        let message = "Hop limit exceeded".to_owned();
+        let a = ErrorPdu::new(ERROR_HOP_LIMIT_EXCEEDED, VAL_INSERT_ID, message.clone());
+        let b: Vec<u8> = a.into();
+        let len = ERROR_HEADER_SIZE + message.len();
+        assert_eq!(b.len(), len);
+        let (a, b) = ErrorPdu::try_parse(&b).unwrap();
+        assert_eq!(b, len);
+        assert_eq!(a.code, ERROR_HOP_LIMIT_EXCEEDED);
+        assert_eq!(a.request_type, VAL_INSERT_ID);
+        assert_eq!(a.message, message);
+    }
+
+    #[test]
+    fn test_error_message_truncated() {
+        let a = ErrorPdu::new(ERROR_HOP_LIMIT_EXCEEDED, VAL_INSERT_ID, "å".repeat(200));
+        assert_eq!(a.message_length, 254);
+        assert_eq!(a.message.chars().count(), 127);
+    }
+
     #[test]
     fn test_stun_lookup() {
         let a = StunLookupPdu::new();
         let b: Vec<u8> = a.into();
         assert_eq!(b.len(), STUN_LOOKUP_SIZE);
-        let (a, b) = StunLookupPdu::try_parse(&b).unwrap();
+        let (_a, b) = StunLookupPdu::try_parse(&b).unwrap();
         assert_eq!(b, STUN_LOOKUP_SIZE);
     }
 
@@ -1,3 +1,4 @@
+use crate::metrics;
 use crate::pdu;
 use crate::pdu::PDU;
 use pdu::*;
@@ -9,19 +10,37 @@ use std::net::SocketAddr;
 use std::io::prelude::*;
 use std::io::ErrorKind;
 
+use tracing::{debug, trace, warn};
+
 const BUFFER_SIZE: usize = 25600;
 
 pub type Message = (PDU, SocketAddr);
 
+/// The type of a PDU that could not be parsed, why, and its sender.
+pub type Rejected = (u8, ParseError, SocketAddr);
+
 pub struct TcpWrapper {
+    name: &'static str,
     incoming_queue: VecDeque<Message>,
     buffer: [u8; BUFFER_SIZE],
     buffer_fill: usize,
 }
 
+impl Default for TcpWrapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TcpWrapper {
     pub fn new() -> Self {
+        Self::with_name("tcp")
+    }
+
+    /// Creates a wrapper whose traffic is reported under `name` in the metrics.
+    pub fn with_name(name: &'static str) -> Self {
         TcpWrapper {
+            name,
             incoming_queue: VecDeque::new(),
             buffer: [0; BUFFER_SIZE],
             buffer_fill: 0,
@@ -29,7 +48,10 @@ impl TcpWrapper {
     }
 
     pub fn send(&self, socket: &mut TcpStream, pdu: PDU) {
+        trace!("Sending {:?} to {:?}", pdu, socket.peer_addr());
+        metrics::PDUS_SENT.inc(&[self.name, pdu.name()]);
         let bytes = pdu.to_bytes();
+        metrics::BYTES_SENT.add(&[self.name], bytes.len() as u64);
         let mut sent = 0;
         while sent != bytes.len() {
             match socket.write(&bytes[sent..]) {
@@ -51,17 +73,33 @@ impl TcpWrapper {
         self.incoming_queue.pop_front()
     }
 
+    /// Removes and returns the first queued PDU that `wanted` accepts,
+    /// leaving the others queued in order.
+    pub fn take_pdu(&mut self, wanted: impl Fn(&PDU) -> bool) -> Option<Message> {
+        let i = self
+            .incoming_queue
+            .iter()
+            .position(|(pdu, _)| wanted(pdu))?;
+        self.incoming_queue.remove(i)
+    }
+
+    /// Reads until the socket would block, which edge-triggered polling
+    /// needs, and queues the PDUs read. Returns whether the remote closed the
+    /// connection.
     pub fn try_read(&mut self, socket: &mut TcpStream) -> bool {
         let mut closed = false;
         loop {
             match socket.read(&mut self.buffer[self.buffer_fill..]) {
                 Ok(amt) => {
                     if amt == 0 {
-                        println!("    Remote closed the connection {:?}", socket.peer_addr());
+                        debug!("Remote closed the connection {:?}", socket.peer_addr());
                         closed = true;
                         break;
                     }
                     self.buffer_fill += amt;
+                    metrics::BYTES_RECEIVED.add(&[self.name], amt as u64);
+                    // Parse as we go, a full buffer would read 0 bytes
+                    self.parse(socket);
                 }
                 Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
                     //No data to read at the moment
@@ -73,26 +111,53 @@ impl TcpWrapper {
             }
         }
 
-        while let Some((pdu, used)) = parse_pdu(&self.buffer[..self.buffer_fill]) {
-            self.incoming_queue
-                .push_back((pdu, socket.peer_addr().unwrap()));
-            self.buffer.copy_within(used..self.buffer_fill, 0);
-            self.buffer_fill -= used;
-        }
-
         closed
     }
+
+    fn parse(&mut self, socket: &TcpStream) {
+        let sender = socket.peer_addr().unwrap();
+        loop {
+            match parse_pdu(&self.buffer[..self.buffer_fill]) {
+                Ok((pdu, used)) => {
+                    trace!("Received {:?} from {}", pdu, sender);
+                    metrics::PDUS_RECEIVED.inc(&[self.name, pdu.name()]);
+                    self.incoming_queue.push_back((pdu, sender));
+                    self.buffer.copy_within(used..self.buffer_fill, 0);
+                    self.buffer_fill -= used;
+                }
+                Err(ParseError::Incomplete) => break,
+                Err(e) => {
+                    // There is no telling where the next PDU starts
+                    warn!("Dropping {} bytes from {}: {}", self.buffer_fill, sender, e);
+                    self.buffer_fill = 0;
+                }
+            }
+        }
+    }
 }
 
 pub struct UdpWrapper {
+    name: &'static str,
     incoming_queue: VecDeque<Message>,
     buffer: [u8; BUFFER_SIZE],
     buffer_fill: usize,
 }
 
+impl Default for UdpWrapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UdpWrapper {
     pub fn new() -> Self {
+        Self::with_name("udp")
+    }
+
+    /// Creates a wrapper whose traffic is reported under `name` in the metrics.
+    pub fn with_name(name: &'static str) -> Self {
         UdpWrapper {
+            name,
             incoming_queue: VecDeque::new(),
             buffer: [0; BUFFER_SIZE],
             buffer_fill: 0,
@@ -100,7 +165,10 @@ impl UdpWrapper {
     }
 
     pub fn send(&self, socket: &mut UdpSocket, pdu: PDU, rec: SocketAddr) {
+        trace!("Sending {:?} to {}", pdu, rec);
+        metrics::PDUS_SENT.inc(&[self.name, pdu.name()]);
         let bytes = pdu.to_bytes();
+        metrics::BYTES_SENT.add(&[self.name], bytes.len() as u64);
         loop {
             match socket.send_to(&bytes, rec) {
                 Ok(amt) => {
@@ -129,17 +197,27 @@ impl UdpWrapper {
         self.incoming_queue.pop_front()
     }
 
-    pub fn try_read(&mut self, socket: &mut UdpSocket) {
+    /// Removes and returns the first queued PDU that `wanted` accepts,
+    /// leaving the others queued in order.
+    pub fn take_pdu(&mut self, wanted: impl Fn(&PDU) -> bool) -> Option<Message> {
+        let i = self
+            .incoming_queue
+            .iter()
+            .position(|(pdu, _)| wanted(pdu))?;
+        self.incoming_queue.remove(i)
+    }
+
+    /// Reads until the socket would block and queues the PDUs read. Returns
+    /// the datagrams that could not be parsed, which are dropped, so the
+    /// caller can tell their senders.
+    pub fn try_read(&mut self, socket: &mut UdpSocket) -> Vec<Rejected> {
+        let mut rejected = Vec::new();
         loop {
             match socket.recv_from(&mut self.buffer[self.buffer_fill..]) {
                 Ok((amt, src)) => {
                     self.buffer_fill += amt;
-
-                    while let Some((pdu, used)) = parse_pdu(&self.buffer[..self.buffer_fill]) {
-                        self.incoming_queue.push_back((pdu, src));
-                        self.buffer.copy_within(used..self.buffer_fill, 0);
-                        self.buffer_fill -= used;
-                    }
+                    metrics::BYTES_RECEIVED.add(&[self.name], amt as u64);
+                    self.parse(src, &mut rejected);
                 }
                 Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
                     //No data to read at the moment
@@ -150,104 +228,212 @@ impl UdpWrapper {
                 }
             }
         }
+        rejected
+    }
+
+    /// Parses the datagram in the buffer, which holds whole PDUs. Bytes left
+    /// that are not a whole PDU are rejected, as the rest never arrives.
+    fn parse(&mut self, src: SocketAddr, rejected: &mut Vec<Rejected>) {
+        while self.buffer_fill > 0 {
+            match parse_pdu(&self.buffer[..self.buffer_fill]) {
+                Ok((pdu, used)) => {
+                    trace!("Received {:?} from {}", pdu, src);
+                    metrics::PDUS_RECEIVED.inc(&[self.name, pdu.name()]);
+                    self.incoming_queue.push_back((pdu, src));
+                    self.buffer.copy_within(used..self.buffer_fill, 0);
+                    self.buffer_fill -= used;
+                }
+                Err(e) => {
+                    debug!("Dropping {} bytes from {}: {}", self.buffer_fill, src, e);
+                    rejected.push((self.buffer[0], e, src));
+                    self.buffer_fill = 0;
+                }
+            }
+        }
     }
 }
 
-fn parse_pdu(buffer: &[u8]) -> Option<(PDU, usize)> {
-    if buffer.len() == 0 {
-        return None;
+pub(crate) fn parse_pdu(buffer: &[u8]) -> Result<(PDU, usize), ParseError> {
+    if buffer.is_empty() {
+        return Err(ParseError::Incomplete);
     }
 
     match buffer[0] {
-        0..=8 => parse_net_pdu(buffer),
-        100..=103 => parse_val_pdu(buffer),
+        0..=14 => parse_net_pdu(buffer),
+        100..=126 => parse_val_pdu(buffer),
         200..=201 => parse_stun_pdu(buffer),
-        _ => {
-            panic!("Got invalid PDU: \n {:#?}", buffer);
+        pdu::ERROR_ID => {
+            let (p, s) = ErrorPdu::try_parse(buffer)?;
+            Ok((PDU::Error(p), s))
         }
+        _ => Err(ParseError::UnknownType),
     }
 }
 
-fn parse_net_pdu(buffer: &[u8]) -> Option<(PDU, usize)> {
+fn parse_net_pdu(buffer: &[u8]) -> Result<(PDU, usize), ParseError> {
     match buffer[0] {
         pdu::NET_ALIVE_ID => {
             let (p, s) = NetAlivePdu::try_parse(buffer)?;
-            Some((p.into(), s))
+            Ok((p.into(), s))
         }
         pdu::NET_GET_NODE_ID => {
             let (p, s) = NetGetNodePdu::try_parse(buffer)?;
-            Some((p.into(), s))
+            Ok((p.into(), s))
         }
         pdu::NET_GET_NODE_RESPONSE_ID => {
             let (p, s) = NetGetNodeResponsePdu::try_parse(buffer)?;
-            Some((p.into(), s))
+            Ok((p.into(), s))
         }
-        pdu::NET_JOIN_ID => {
+        pdu::NET_JOIN_ID | pdu::NET_JOIN_WIDE_ID => {
             let (p, s) = NetJoinPdu::try_parse(buffer)?;
-            Some((p.into(), s))
+            Ok((p.into(), s))
         }
-        pdu::NET_JOIN_RESPONSE_ID => {
+        pdu::NET_JOIN_RESPONSE_ID | pdu::NET_JOIN_RESPONSE_WIDE_ID => {
             let (p, s) = NetJoinResponsePdu::try_parse(buffer)?;
-            Some((p.into(), s))
+            Ok((p.into(), s))
         }
         pdu::NET_CLOSE_CONNECTION_ID => {
             let (p, s) = NetCloseConnectionPdu::try_parse(buffer)?;
-            Some((p.into(), s))
+            Ok((p.into(), s))
         }
-        pdu::NET_NEW_RANGE_ID => {
+        pdu::NET_NEW_RANGE_ID | pdu::NET_NEW_RANGE_WIDE_ID => {
             let (p, s) = NetNewRangePdu::try_parse(buffer)?;
-            Some((p.into(), s))
+            Ok((p.into(), s))
         }
         pdu::NET_NEW_RANGE_RESPONSE_ID => {
             let (p, s) = NetNewRangeResponsePdu::try_parse(buffer)?;
-            Some((p.into(), s))
+            Ok((p.into(), s))
         }
         pdu::NET_LEAVING_ID => {
             let (p, s) = NetLeavingPdu::try_parse(buffer)?;
-            Some((p.into(), s))
+            Ok((p.into(), s))
         }
-        x => {
-            panic!("Invalid lookup id, this should not happen ({}) ", x);
+        pdu::NET_ADD_RANGE_ID => {
+            let (p, s) = NetAddRangePdu::try_parse(buffer)?;
+            Ok((p.into(), s))
         }
+        pdu::NET_REBALANCE_ID => {
+            let (p, s) = NetRebalancePdu::try_parse(buffer)?;
+            Ok((p.into(), s))
+        }
+        pdu::NET_CONTROL_ID => {
+            let (p, s) = NetControlPdu::try_parse(buffer)?;
+            Ok((p.into(), s))
+        }
+        _ => Err(ParseError::UnknownType),
     }
 }
 
-fn parse_val_pdu(buffer: &[u8]) -> Option<(PDU, usize)> {
+fn parse_val_pdu(buffer: &[u8]) -> Result<(PDU, usize), ParseError> {
     match buffer[0] {
-        pdu::VAL_INSERT_ID => {
+        pdu::VAL_INSERT_ID | pdu::VAL_INSERT_WIDE_ID => {
             let (p, s) = ValInsertPdu::try_parse(buffer)?;
-            Some((PDU::ValInsert(p), s))
+            Ok((PDU::ValInsert(p), s))
         }
         pdu::VAL_REMOVE_ID => {
             let (p, s) = ValRemovePdu::try_parse(buffer)?;
-            Some((PDU::ValRemove(p), s))
+            Ok((PDU::ValRemove(p), s))
         }
-        pdu::VAL_LOOKUP_ID => {
+        pdu::VAL_LOOKUP_ID | pdu::VAL_LOOKUP_VERSIONED_ID => {
             let (p, s) = ValLookupPdu::try_parse(buffer)?;
-            Some((PDU::ValLookup(p), s))
+            Ok((PDU::ValLookup(p), s))
         }
-        pdu::VAL_LOOKUP_RESPONSE_ID => {
+        pdu::VAL_LOOKUP_RESPONSE_ID
+        | pdu::VAL_LOOKUP_RESPONSE_WIDE_ID
+        | pdu::VAL_LOOKUP_RESPONSE_VERSIONED_ID => {
             let (p, s) = ValLookupResponsePdu::try_parse(buffer)?;
-            Some((PDU::ValLookupResponse(p), s))
+            Ok((PDU::ValLookupResponse(p), s))
+        }
+        pdu::VAL_TRACE_ID => {
+            let (p, s) = ValTracePdu::try_parse(buffer)?;
+            Ok((PDU::ValTrace(p), s))
+        }
+        pdu::VAL_TRACE_HOP_ID => {
+            let (p, s) = ValTraceHopPdu::try_parse(buffer)?;
+            Ok((PDU::ValTraceHop(p), s))
+        }
+        pdu::VAL_HOP_LIMIT_ID => {
+            let (p, s) = ValHopLimitPdu::try_parse(buffer)?;
+            Ok((PDU::ValHopLimit(p), s))
+        }
+        pdu::VAL_VERSION_ID => {
+            let (p, s) = ValVersionPdu::try_parse(buffer)?;
+            Ok((PDU::ValVersion(p), s))
+        }
+        pdu::VAL_COND_INSERT_ID => {
+            let (p, s) = ValCondInsertPdu::try_parse(buffer)?;
+            Ok((PDU::ValCondInsert(p), s))
         }
-        x => {
-            panic!("Invalid lookup id, this should not happen ({}) ", x);
+        pdu::VAL_COND_REMOVE_ID => {
+            let (p, s) = ValCondRemovePdu::try_parse(buffer)?;
+            Ok((PDU::ValCondRemove(p), s))
         }
+        pdu::VAL_COND_RESPONSE_ID => {
+            let (p, s) = ValCondResponsePdu::try_parse(buffer)?;
+            Ok((PDU::ValCondResponse(p), s))
+        }
+        pdu::VAL_TTL_ID => {
+            let (p, s) = ValTtlPdu::try_parse(buffer)?;
+            Ok((PDU::ValTtl(p), s))
+        }
+        pdu::VAL_SCAN_ID => {
+            let (p, s) = ValScanPdu::try_parse(buffer)?;
+            Ok((PDU::ValScan(p), s))
+        }
+        pdu::VAL_SCAN_RESPONSE_ID => {
+            let (p, s) = ValScanResponsePdu::try_parse(buffer)?;
+            Ok((PDU::ValScanResponse(p), s))
+        }
+        pdu::VAL_BATCH_ID => {
+            let (p, s) = ValBatchPdu::try_parse(buffer)?;
+            Ok((PDU::ValBatch(p), s))
+        }
+        pdu::VAL_SUBSCRIBE_ID => {
+            let (p, s) = ValSubscribePdu::try_parse(buffer)?;
+            Ok((PDU::ValSubscribe(p), s))
+        }
+        pdu::VAL_NOTIFY_ID => {
+            let (p, s) = ValNotifyPdu::try_parse(buffer)?;
+            Ok((PDU::ValNotify(p), s))
+        }
+        pdu::VAL_QUERY_ID => {
+            let (p, s) = ValQueryPdu::try_parse(buffer)?;
+            Ok((PDU::ValQuery(p), s))
+        }
+        pdu::VAL_QUERY_RESPONSE_ID => {
+            let (p, s) = ValQueryResponsePdu::try_parse(buffer)?;
+            Ok((PDU::ValQueryResponse(p), s))
+        }
+        pdu::VAL_KV_PUT_ID => {
+            let (p, s) = ValKvPutPdu::try_parse(buffer)?;
+            Ok((PDU::ValKvPut(p), s))
+        }
+        pdu::VAL_KV_DELETE_ID => {
+            let (p, s) = ValKvDeletePdu::try_parse(buffer)?;
+            Ok((PDU::ValKvDelete(p), s))
+        }
+        pdu::VAL_KV_GET_ID => {
+            let (p, s) = ValKvGetPdu::try_parse(buffer)?;
+            Ok((PDU::ValKvGet(p), s))
+        }
+        pdu::VAL_KV_GET_RESPONSE_ID => {
+            let (p, s) = ValKvGetResponsePdu::try_parse(buffer)?;
+            Ok((PDU::ValKvGetResponse(p), s))
+        }
+        _ => Err(ParseError::UnknownType),
     }
 }
 
-fn parse_stun_pdu(buffer: &[u8]) -> Option<(PDU, usize)> {
+fn parse_stun_pdu(buffer: &[u8]) -> Result<(PDU, usize), ParseError> {
     match buffer[0] {
         pdu::STUN_LOOKUP_ID => {
             let (p, s) = StunLookupPdu::try_parse(buffer)?;
-            Some((PDU::StunLookup(p), s))
+            Ok((PDU::StunLookup(p), s))
         }
         pdu::STUN_RESPONSE_ID => {
             let (p, s) = StunResponsePdu::try_parse(buffer)?;
-            Some((PDU::StunResponse(p), s))
-        }
-        x => {
-            panic!("Invalid stun id, this should not happen ({}) ", x);
+            Ok((PDU::StunResponse(p), s))
         }
+        _ => Err(ParseError::UnknownType),
     }
 }
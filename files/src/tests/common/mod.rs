@@ -0,0 +1,319 @@
+//! Helpers for the tests that run a tracker and nodes. Each test crate uses a
+//! part of them.
+#![allow(dead_code)]
+
+use mio::net::UdpSocket;
+use mio::{Events, Interest, Poll, Token};
+use ou2::hash::{self, KeyHasher};
+use ou2::pdu::*;
+use ou2::socket_wrapper::*;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// Kills the processes when the test ends, passed or not.
+pub struct Processes(pub Vec<Child>);
+
+impl Drop for Processes {
+    fn drop(&mut self) {
+        for child in &mut self.0 {
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
+}
+
+pub fn spawn(binary: &str, args: &[&str]) -> Child {
+    Command::new(binary)
+        .args(args)
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start binary")
+}
+
+pub struct Client {
+    wrapper: UdpWrapper,
+    socket: UdpSocket,
+    poll: Poll,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        let mut socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let poll = Poll::new().unwrap();
+        poll.registry()
+            .register(&mut socket, Token(0), Interest::READABLE)
+            .unwrap();
+        Client {
+            wrapper: UdpWrapper::new(),
+            socket,
+            poll,
+        }
+    }
+
+    /// The port responses to the client are sent to.
+    pub fn port(&self) -> u16 {
+        self.socket.local_addr().unwrap().port()
+    }
+
+    pub fn send(&mut self, pdu: PDU, to: SocketAddr) {
+        self.wrapper.send(&mut self.socket, pdu, to);
+    }
+
+    /// Sends `pdu` to `to` and returns the first PDU that arrives within a
+    /// second, if any.
+    pub fn request(&mut self, pdu: PDU, to: SocketAddr) -> Option<PDU> {
+        self.request_from(pdu, to).map(|(pdu, _)| pdu)
+    }
+
+    /// Like `request`, but also returns the address the answer came from.
+    pub fn request_from(&mut self, pdu: PDU, to: SocketAddr) -> Option<(PDU, SocketAddr)> {
+        self.send(pdu, to);
+        self.receive()
+    }
+
+    /// Like `request`, but sends `bytes` as they are, for PDUs that can't be
+    /// built.
+    pub fn request_bytes(&mut self, bytes: &[u8], to: SocketAddr) -> Option<PDU> {
+        self.socket.send_to(bytes, to).unwrap();
+        self.receive().map(|(pdu, _)| pdu)
+    }
+
+    /// The next PDU that arrives within a second and its sender, if any.
+    pub fn receive(&mut self) -> Option<(PDU, SocketAddr)> {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut events = Events::with_capacity(1);
+        loop {
+            self.wrapper.try_read(&mut self.socket);
+            if let Some(answer) = self.wrapper.next_pdu() {
+                return Some(answer);
+            }
+            let left = deadline.checked_duration_since(Instant::now())?;
+            self.poll.poll(&mut events, Some(left)).unwrap();
+        }
+    }
+
+    pub fn get_node(&mut self, tracker: SocketAddr) -> Option<SocketAddr> {
+        match self.request(NetGetNodePdu::new().into(), tracker)? {
+            PDU::NetGetNodeResponse(p) if p.port != 0 => Some(p.get_addr()),
+            _ => None,
+        }
+    }
+
+    /// The range of the node that holds `start`, found by a scan sent to `node`.
+    pub fn range(&mut self, node: SocketAddr, start: u64, max_hash: u64) -> Option<(u64, u64)> {
+        let address = Ipv4Addr::LOCALHOST.into();
+        let scan = ValScanPdu::new(start, max_hash, 1, None, address, self.port());
+        match self.request(scan.into(), node)? {
+            PDU::ValScanResponse(p) if p.range_start == start => Some((start, p.range_end)),
+            _ => None,
+        }
+    }
+
+    /// Scans the empty ring from hash 0 and returns the range of every node it
+    /// passes, or None if a node does not answer in time. While nodes are
+    /// joining, late answers and errors are expected and also give None.
+    pub fn ranges(&mut self, node: SocketAddr, max_hash: u64) -> Option<Vec<(u64, u64)>> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        loop {
+            let (_, end) = self.range(node, start, max_hash)?;
+            ranges.push((start, end));
+            if end >= max_hash {
+                return Some(ranges);
+            }
+            start = end + 1;
+        }
+    }
+
+    /// Scans through `node` until the ring holds `count` ranges, and returns
+    /// them. Fails the test if that takes longer than `timeout`.
+    pub fn wait_for_ranges(
+        &mut self,
+        node: SocketAddr,
+        max_hash: u64,
+        count: usize,
+        timeout: Duration,
+    ) -> Vec<(u64, u64)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.ranges(node, max_hash) {
+                Some(ranges) if ranges.len() == count => return ranges,
+                ranges => assert!(
+                    Instant::now() < deadline,
+                    "The ring never held {} ranges, last scan found {:?}",
+                    count,
+                    ranges
+                ),
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Inserts an entry named after its ssn for each of `ssns` through `node`.
+    pub fn insert_all(&mut self, node: SocketAddr, ssns: &[String]) {
+        for ssn in ssns {
+            let insert = ValInsertPdu::new(ssn.clone(), name(ssn), "a@b.c".into());
+            self.send(insert.into(), node);
+        }
+    }
+
+    /// Looks up each of `ssns` through `node` and checks that it holds the
+    /// entry `insert_all` stored.
+    pub fn assert_stored(&mut self, node: SocketAddr, ssns: &[String]) {
+        let port = self.port();
+        for ssn in ssns {
+            let lookup = ValLookupPdu::new(ssn.clone(), Ipv4Addr::LOCALHOST.into(), port);
+            match self.request(lookup.into(), node) {
+                Some(PDU::ValLookupResponse(p)) => {
+                    assert_eq!(p.ssn, *ssn);
+                    assert_eq!(p.name, name(ssn));
+                }
+                x => panic!("Expected the entry of {}, got {:?}", ssn, x),
+            }
+        }
+    }
+}
+
+/// `count` distinct valid ssns, in order.
+pub fn ssns(count: usize) -> Vec<String> {
+    assert!(count <= 100);
+    (0..count).map(|i| format!("1980010100{:02}", i)).collect()
+}
+
+/// The name `insert_all` gives the entry of `ssn`.
+pub fn name(ssn: &str) -> String {
+    format!("Name {}", ssn)
+}
+
+/// Starts a tracker on a free port and returns its address.
+pub fn start_tracker(processes: &mut Processes) -> SocketAddr {
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let tracker_port = port.to_string();
+    processes
+        .0
+        .push(spawn(env!("CARGO_BIN_EXE_tracker"), &[&tracker_port]));
+    std::thread::sleep(Duration::from_millis(200));
+    (Ipv4Addr::LOCALHOST, port).into()
+}
+
+/// How long a test waits for nodes to register and join.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A tracker and the nodes started through it, with a client to talk to
+/// them. `processes` holds the tracker first, then the nodes in the order
+/// they were started.
+pub struct Cluster {
+    pub processes: Processes,
+    pub tracker: SocketAddr,
+    pub client: Client,
+    /// The first node, which every other node joins through.
+    pub node: SocketAddr,
+    /// The hasher the nodes use by default.
+    pub hasher: Box<dyn KeyHasher>,
+    pub max_hash: u64,
+}
+
+/// Starts a tracker and `n` nodes with the extra `args`, and waits until the
+/// ring holds a range for each node.
+pub fn cluster(n: usize, args: &[&str]) -> Cluster {
+    assert!(n > 0);
+    let mut processes = Processes(Vec::new());
+    let tracker = start_tracker(&mut processes);
+    start_node(&mut processes, tracker, args);
+    let mut client = Client::new();
+    let node = first_node(&mut client, tracker);
+    let hasher = hash::by_name("djb2").unwrap();
+    let max_hash = hasher.max_hash();
+    let mut cluster = Cluster {
+        processes,
+        tracker,
+        client,
+        node,
+        hasher,
+        max_hash,
+    };
+    for _ in 1..n {
+        cluster.start_node(args);
+    }
+    if n > 1 {
+        cluster.wait_for_ranges(n);
+    }
+    cluster
+}
+
+impl Cluster {
+    /// Starts a node with the extra `args` that joins through the tracker,
+    /// without waiting for it.
+    pub fn start_node(&mut self, args: &[&str]) {
+        start_node(&mut self.processes, self.tracker, args);
+    }
+
+    /// Scans through the first node until the ring holds `count` ranges, and
+    /// returns them.
+    pub fn wait_for_ranges(&mut self, count: usize) -> Vec<(u64, u64)> {
+        self.client
+            .wait_for_ranges(self.node, self.max_hash, count, JOIN_TIMEOUT)
+    }
+
+    /// The ranges of the ring, scanned through the first node.
+    pub fn ranges(&mut self) -> Option<Vec<(u64, u64)>> {
+        self.client.ranges(self.node, self.max_hash)
+    }
+
+    /// The range of the node that holds `start`, found through the first node.
+    pub fn range(&mut self, start: u64) -> Option<(u64, u64)> {
+        self.client.range(self.node, start, self.max_hash)
+    }
+}
+
+/// Starts a node with the extra `args` that joins through `tracker`.
+fn start_node(processes: &mut Processes, tracker: SocketAddr, args: &[&str]) {
+    let port = tracker.port().to_string();
+    let mut node_args = vec!["127.0.0.1", port.as_str()];
+    node_args.extend_from_slice(args);
+    processes
+        .0
+        .push(spawn(env!("CARGO_BIN_EXE_node"), &node_args));
+}
+
+/// Asks the tracker for a node until one has registered.
+fn first_node(client: &mut Client, tracker: SocketAddr) -> SocketAddr {
+    let deadline = Instant::now() + JOIN_TIMEOUT;
+    loop {
+        if let Some(node) = client.get_node(tracker) {
+            return node;
+        }
+        assert!(Instant::now() < deadline, "The first node never registered");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Sends SIGINT to `child`, as Ctrl-C would.
+pub fn interrupt(child: &Child) {
+    let status = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .expect("Failed to run kill");
+    assert!(status.success(), "Failed to interrupt {}", child.id());
+}
+
+/// Waits up to `timeout` for `child` to exit and returns how it exited.
+pub fn wait_exit(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return Some(status);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
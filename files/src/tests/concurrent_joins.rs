@@ -0,0 +1,22 @@
+//! Starts a tracker and a first node, then many nodes at once, and checks that
+//! they all join and that their ranges cover the ring exactly once.
+
+mod common;
+
+use common::*;
+
+const JOINING: usize = 8;
+
+#[test]
+fn test_concurrent_joins() {
+    let mut cluster = cluster(1 + JOINING, &[]);
+    let ranges = cluster.ranges().unwrap();
+
+    for child in &mut cluster.processes.0 {
+        assert!(child.try_wait().unwrap().is_none(), "A process exited");
+    }
+    // Every node splits the range it joins at in two
+    for &(start, end) in &ranges {
+        assert!((end - start + 1).is_power_of_two(), "{:?}", ranges);
+    }
+}
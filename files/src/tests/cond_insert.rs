@@ -0,0 +1,54 @@
+//! Sends conditional inserts through the node that does not hold the ssn,
+//! and checks that a matching and a mismatching version are both answered
+//! with a response for the conditional insert.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// Inserts `ssn` through `node` if its version is `expected`, and returns
+/// whether it was and the version after.
+fn cond_insert(client: &mut Client, node: SocketAddr, ssn: &str, expected: u64) -> (bool, u64) {
+    let (address, port) = (Ipv4Addr::LOCALHOST.into(), client.port());
+    let insert = ValCondInsertPdu::new(
+        ssn.into(),
+        name(ssn),
+        "a@b.c".into(),
+        expected,
+        address,
+        port,
+    );
+    match client.request(insert.into(), node) {
+        Some(PDU::ValCondResponse(p)) => {
+            assert_eq!(p.request_type, VAL_COND_INSERT_ID);
+            assert_eq!(p.ssn, ssn);
+            (p.success, p.version)
+        }
+        x => panic!("Expected a conditional response, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_cond_insert() {
+    let mut cluster = cluster(2, &[]);
+    let ranges = cluster.ranges().unwrap();
+
+    // An ssn of the second node, so the inserts are forwarded
+    let ssn = ssns(100)
+        .into_iter()
+        .find(|ssn| cluster.hasher.hash(ssn.as_bytes()) >= ranges[1].0)
+        .expect("No ssn hashes to the second node");
+    let (client, node) = (&mut cluster.client, cluster.node);
+
+    let (success, version) = cond_insert(client, node, &ssn, 0);
+    assert!(success);
+    assert!(version > 0);
+    assert_eq!(cond_insert(client, node, &ssn, 0), (false, version));
+
+    let (success, newer) = cond_insert(client, node, &ssn, version);
+    assert!(success);
+    assert!(newer > version);
+    client.assert_stored(node, &[ssn]);
+}
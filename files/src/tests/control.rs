@@ -0,0 +1,122 @@
+//! Controls a node with signed control PDUs: refuses PDUs with the wrong key,
+//! sent again or meant for another node, hands the ranges of the first of two
+//! nodes to the second, and makes the first node leave with `dhtctl leave`.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const KEY: &[u8] = b"control key";
+const ENTRIES: usize = 32;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// A control PDU for `target` signed with `key`.
+fn control(command: u8, target: SocketAddr, timestamp: u64, key: &[u8]) -> PDU {
+    let ip = match target {
+        SocketAddr::V4(a) => (*a.ip()).into(),
+        SocketAddr::V6(_) => panic!("The nodes run on IPv4"),
+    };
+    NetControlPdu::new(command, ip, target.port(), timestamp, key).into()
+}
+
+fn error_code(response: Option<PDU>) -> Option<u8> {
+    match response? {
+        PDU::Error(p) => Some(p.code),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_control() {
+    let key_file = std::env::temp_dir().join(format!("control-key-{}", std::process::id()));
+    std::fs::write(&key_file, KEY).unwrap();
+    let key_file = key_file.to_str().unwrap().to_owned();
+
+    let mut cluster = cluster(2, &["--control-key-file", &key_file]);
+    let (client, node, max_hash) = (&mut cluster.client, cluster.node, cluster.max_hash);
+    let processes = &mut cluster.processes;
+
+    let ssns = ssns(ENTRIES);
+    client.insert_all(node, &ssns);
+
+    // The second node answers scans of its own range
+    let address = Ipv4Addr::LOCALHOST.into();
+    let other = client
+        .ranges(node, max_hash)
+        .unwrap()
+        .into_iter()
+        .find_map(|(start, _)| {
+            let scan = ValScanPdu::new(start, max_hash, 1, None, address, client.port());
+            let (_, from) = client.request_from(scan.into(), node)?;
+            Some(from).filter(|from| *from != node)
+        });
+    let other = other.expect("No scan was answered by the second node");
+
+    let forged = control(CONTROL_LEAVE, node, now(), b"wrong key");
+    assert_eq!(
+        error_code(client.request(forged, node)),
+        Some(ERROR_UNAUTHORIZED)
+    );
+
+    // Signed with the shared key, but for the first node
+    let replayed = control(CONTROL_LEAVE, node, now(), KEY);
+    assert_eq!(
+        error_code(client.request(replayed, other)),
+        Some(ERROR_UNAUTHORIZED)
+    );
+    assert!(processes.0[2].try_wait().unwrap().is_none());
+
+    let timestamp = now();
+    let handoff = control(CONTROL_HANDOFF_SUCCESSOR, node, timestamp, KEY);
+    assert_eq!(error_code(client.request(handoff, node)), None);
+    let again = control(CONTROL_HANDOFF_SUCCESSOR, node, timestamp, KEY);
+    assert_eq!(
+        error_code(client.request(again, node)),
+        Some(ERROR_UNAUTHORIZED)
+    );
+
+    // The second node now owns the whole ring, and the first forwards to it
+    assert_eq!(client.ranges(node, max_hash), Some(vec![(0, max_hash)]));
+    client.assert_stored(node, &ssns);
+
+    // dhtctl fails when the node refuses it
+    let wrong_key_file = format!("{}-wrong", key_file);
+    std::fs::write(&wrong_key_file, b"wrong key").unwrap();
+    let node_arg = node.to_string();
+    let dhtctl = |key_file: &str| {
+        let args = ["--control-key-file", key_file, "leave", &node_arg];
+        let mut child = spawn(env!("CARGO_BIN_EXE_dhtctl"), &args);
+        wait_exit(&mut child, Duration::from_secs(5))
+    };
+    let status = dhtctl(&wrong_key_file);
+    assert!(
+        matches!(status, Some(s) if !s.success()),
+        "dhtctl: {:?}",
+        status
+    );
+    assert!(processes.0[1].try_wait().unwrap().is_none());
+
+    let status = dhtctl(&key_file);
+    assert!(
+        matches!(status, Some(s) if s.success()),
+        "dhtctl failed: {:?}",
+        status
+    );
+    let status = wait_exit(&mut processes.0[1], Duration::from_secs(10));
+    assert!(
+        matches!(status, Some(s) if s.success()),
+        "The node did not leave cleanly: {:?}",
+        status
+    );
+    std::fs::remove_file(&key_file).ok();
+    std::fs::remove_file(&wrong_key_file).ok();
+}
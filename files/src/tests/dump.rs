@@ -0,0 +1,44 @@
+//! Dumps through a node that never answers, and checks that the client sends
+//! the scan again a few times and then gives up instead of waiting forever.
+
+mod common;
+
+use common::*;
+use std::time::Duration;
+
+#[test]
+fn test_dump_gives_up() {
+    let mut processes = Processes(Vec::new());
+    let tracker = start_tracker(&mut processes);
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    silent
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let out = std::env::temp_dir().join(format!("dump-{}.csv", std::process::id()));
+    let (tracker, node) = (
+        tracker.to_string(),
+        silent.local_addr().unwrap().to_string(),
+    );
+    let args = [
+        "-t",
+        &tracker,
+        "-n",
+        &node,
+        "dump",
+        "--out",
+        out.to_str().unwrap(),
+    ];
+    processes.0.push(spawn(env!("CARGO_BIN_EXE_client"), &args));
+
+    // The scan and the first time it is sent again
+    let mut buffer = [0; 128];
+    for _ in 0..2 {
+        silent
+            .recv_from(&mut buffer)
+            .expect("The scan was not sent");
+    }
+    let client = processes.0.last_mut().unwrap();
+    let status = wait_exit(client, Duration::from_secs(15)).expect("The dump never gave up");
+    assert!(!status.success());
+    std::fs::remove_file(out).ok();
+}
@@ -0,0 +1,67 @@
+//! Sends a node bytes that are not a PDU, an insert whose name is not UTF-8
+//! and a datagram that ends part-way through a PDU, and checks that it answers
+//! each with an invalid PDU error, does not store the insert and keeps
+//! handling requests, also from other senders.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::net::Ipv4Addr;
+
+#[test]
+fn test_invalid_pdu() {
+    let mut cluster = cluster(1, &[]);
+    let (client, node) = (&mut cluster.client, cluster.node);
+
+    match client.request_bytes(&[99, 1, 2, 3], node) {
+        Some(PDU::Error(p)) => {
+            assert_eq!(p.code, ERROR_INVALID_PDU);
+            assert_eq!(p.request_type, 99);
+        }
+        x => panic!("Expected an invalid PDU error, got {:?}", x),
+    }
+
+    let ssn = "198001010000".to_owned();
+    let port = client.port();
+    let insert = ValInsertPdu::new(ssn.clone(), "Name".into(), "a@b.c".into());
+    let mut bytes: Vec<u8> = insert.into();
+    bytes[1 + 12 + 1] = 0xff;
+    match client.request_bytes(&bytes, node) {
+        Some(PDU::Error(p)) => {
+            assert_eq!(p.code, ERROR_INVALID_PDU);
+            assert_eq!(p.request_type, VAL_INSERT_ID);
+        }
+        x => panic!("Expected an invalid PDU error, got {:?}", x),
+    }
+    let lookup = ValLookupPdu::new(ssn.clone(), Ipv4Addr::LOCALHOST.into(), port);
+    match client.request(lookup.into(), node) {
+        Some(PDU::ValLookupResponse(p)) => assert_eq!(p.ssn, "000000000000"),
+        x => panic!("Expected no entry for {}, got {:?}", ssn, x),
+    }
+
+    let insert = ValInsertPdu::new(ssn.clone(), "Name".into(), "a@b.c".into());
+    client.send(insert.into(), node);
+    let lookup = ValLookupPdu::new(ssn.clone(), Ipv4Addr::LOCALHOST.into(), port);
+    match client.request(lookup.into(), node) {
+        Some(PDU::ValLookupResponse(p)) => assert_eq!(p.ssn, ssn),
+        x => panic!("Expected the entry of {}, got {:?}", ssn, x),
+    }
+
+    // The rest of a truncated lookup is not taken from the next datagram
+    let lookup = ValLookupPdu::new(ssn.clone(), Ipv4Addr::LOCALHOST.into(), port);
+    let bytes: Vec<u8> = lookup.into();
+    match client.request_bytes(&bytes[..bytes.len() - 2], node) {
+        Some(PDU::Error(p)) => {
+            assert_eq!(p.code, ERROR_INVALID_PDU);
+            assert_eq!(p.request_type, VAL_LOOKUP_ID);
+        }
+        x => panic!("Expected an invalid PDU error, got {:?}", x),
+    }
+    let mut other = Client::new();
+    let lookup = ValLookupPdu::new(ssn.clone(), Ipv4Addr::LOCALHOST.into(), other.port());
+    match other.request(lookup.into(), node) {
+        Some(PDU::ValLookupResponse(p)) => assert_eq!(p.ssn, ssn),
+        x => panic!("Expected the entry of {}, got {:?}", ssn, x),
+    }
+}
@@ -0,0 +1,55 @@
+//! Interrupts nodes the way Ctrl-C does and checks that they leave cleanly:
+//! one of two nodes hands its entries to the other, and a node waiting for a
+//! joining node to connect back gives the join up and leaves.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::net::{Ipv4Addr, TcpListener};
+use std::time::Duration;
+
+const ENTRIES: usize = 32;
+
+#[test]
+fn test_leave_two_nodes() {
+    let mut cluster = cluster(1, &[]);
+    cluster.start_node(&["--drain-timeout", "1"]);
+    cluster.wait_for_ranges(2);
+
+    let ssns = ssns(ENTRIES);
+    cluster.client.insert_all(cluster.node, &ssns);
+
+    let leaving = &mut cluster.processes.0[2];
+    interrupt(leaving);
+    let status = wait_exit(leaving, Duration::from_secs(10));
+    assert!(
+        matches!(status, Some(s) if s.success()),
+        "The node did not leave cleanly: {:?}",
+        status
+    );
+
+    assert_eq!(cluster.ranges(), Some(vec![(0, cluster.max_hash)]));
+    cluster.client.assert_stored(cluster.node, &ssns);
+}
+
+#[test]
+fn test_leave_during_join() {
+    let mut cluster = cluster(1, &["--accept-timeout", "30"]);
+
+    // The joining node never connects back, so the join stays pending
+    let joiner = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = joiner.local_addr().unwrap().port();
+    let join = NetJoinPdu::new(Ipv4Addr::LOCALHOST.into(), port, 0, 0, 0);
+    cluster.client.send(join.into(), cluster.node);
+    let (_connection, _) = joiner.accept().unwrap();
+
+    let leaving = &mut cluster.processes.0[1];
+    interrupt(leaving);
+    let status = wait_exit(leaving, Duration::from_secs(10));
+    assert!(
+        matches!(status, Some(s) if s.success()),
+        "The node did not leave cleanly: {:?}",
+        status
+    );
+}
@@ -0,0 +1,31 @@
+//! Joins a second node to a node holding entries, with joins weighted by
+//! entries, and checks that the range is split at the median hash of the
+//! entries instead of the midpoint.
+
+mod common;
+
+use common::*;
+
+const ENTRIES: usize = 20;
+
+#[test]
+fn test_median_split() {
+    let args = ["--join-weight", "entries"];
+    let mut cluster = cluster(1, &args);
+    let (node, max_hash) = (cluster.node, cluster.max_hash);
+
+    let ssns = ssns(ENTRIES);
+    cluster.client.insert_all(node, &ssns);
+    cluster.client.assert_stored(node, &ssns);
+
+    let hasher = &cluster.hasher;
+    let mut hashes: Vec<u64> = ssns.iter().map(|s| hasher.hash(s.as_bytes())).collect();
+    hashes.sort_unstable();
+    let median = hashes[(hashes.len() - 1) / 2];
+    assert_ne!(median, (max_hash - 1) / 2, "The median is the midpoint");
+
+    cluster.start_node(&args);
+    let ranges = cluster.wait_for_ranges(2);
+    assert_eq!(ranges, [(0, median), (median + 1, max_hash)]);
+    cluster.client.assert_stored(node, &ssns);
+}
@@ -0,0 +1,28 @@
+//! Joins a node with a NET_JOIN from a node that never connects back, and
+//! checks that the node keeps answering while it waits and takes its range
+//! back when the wait times out.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::net::{Ipv4Addr, TcpListener};
+use std::time::Duration;
+
+#[test]
+fn test_predecessor_timeout() {
+    let mut cluster = cluster(1, &["--accept-timeout", "1"]);
+    let max_hash = cluster.max_hash;
+
+    let joiner = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = joiner.local_addr().unwrap().port();
+    let join = NetJoinPdu::new(Ipv4Addr::LOCALHOST.into(), port, 0, 0, 0);
+    cluster.client.send(join.into(), cluster.node);
+    let (_connection, _) = joiner.accept().unwrap();
+
+    let half = (max_hash - 1) / 2;
+    assert_eq!(cluster.range(0), Some((0, half)));
+
+    std::thread::sleep(Duration::from_millis(1500));
+    assert_eq!(cluster.range(0), Some((0, max_hash)));
+}
@@ -0,0 +1,37 @@
+//! Queries a ring of three nodes with a hop limit of one, and checks that the
+//! query still reaches every range, since each node that answers passes the
+//! rest on with a new hop limit.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::net::Ipv4Addr;
+
+const NODES: usize = 3;
+
+#[test]
+fn test_query_hops() {
+    let mut cluster = cluster(NODES, &[]);
+    let ranges = cluster.ranges().unwrap();
+    let (client, node, max_hash) = (&mut cluster.client, cluster.node, cluster.max_hash);
+
+    // The query starts at the node that owns hash 0, with one hop to spend
+    let address = Ipv4Addr::LOCALHOST.into();
+    let port = client.port();
+    let scan = ValScanPdu::new(0, max_hash, 1, None, address, port);
+    let (_, first) = client.request_from(scan.into(), node).unwrap();
+    let query = ValQueryPdu::new(QUERY_FIELD_EMAIL, "a@b.c".into(), 0, address, port);
+    let limited = ValHopLimitPdu::new(1, address, port, query.into());
+    client.send(limited.into(), first);
+
+    let mut answered = Vec::new();
+    while answered.len() < NODES {
+        match client.receive() {
+            Some((PDU::ValQueryResponse(p), _)) => answered.push((p.range_start, p.range_end)),
+            x => panic!("Expected a query response, got {:?}", x),
+        }
+    }
+    answered.sort_unstable();
+    assert_eq!(answered, ranges);
+}
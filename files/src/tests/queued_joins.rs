@@ -0,0 +1,63 @@
+//! Sends a node a second NET_JOIN while it still waits for the node of the
+//! first to connect, and checks that the second join is only taken once the
+//! wait is over.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::io::Read;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+/// Waits up to `timeout` for a node to connect to `listener`.
+fn accept(listener: &TcpListener, timeout: Duration) -> Option<TcpStream> {
+    let deadline = Instant::now() + timeout;
+    listener.set_nonblocking(true).unwrap();
+    loop {
+        if let Ok((stream, _)) = listener.accept() {
+            stream.set_nonblocking(false).unwrap();
+            return Some(stream);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn test_queued_joins() {
+    let mut cluster = cluster(1, &["--accept-timeout", "2"]);
+    let (client, node, max_hash) = (&mut cluster.client, cluster.node, cluster.max_hash);
+    let half = (max_hash - 1) / 2;
+
+    let first = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = first.local_addr().unwrap().port();
+    let join = NetJoinPdu::new(Ipv4Addr::LOCALHOST.into(), port, 0, 0, 0);
+    client.send(join.into(), node);
+    let mut first_stream = accept(&first, Duration::from_secs(5)).expect("No first join");
+    assert_eq!(client.range(node, 0, max_hash), Some((0, half)));
+
+    let second = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = second.local_addr().unwrap().port();
+    let join = NetJoinPdu::new(Ipv4Addr::LOCALHOST.into(), port, 0, 0, 0);
+    client.send(join.into(), node);
+
+    // The first joining node gets its join response and nothing more while
+    // it is awaited, and the second is not connected to
+    let mut buffer = [0; 64];
+    assert!(first_stream.read(&mut buffer).unwrap() > 0);
+    first_stream
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    assert!(
+        first_stream.read(&mut buffer).is_err(),
+        "The second join reached the first joining node"
+    );
+    assert!(accept(&second, Duration::from_millis(500)).is_none());
+
+    // The first join times out, then the second splits the whole ring again
+    let _second_stream = accept(&second, Duration::from_secs(5)).expect("No second join");
+    assert_eq!(client.range(node, 0, max_hash), Some((0, half)));
+}
@@ -0,0 +1,39 @@
+//! Runs `client ring` on two nodes with virtual ranges, and checks that it
+//! reports the ranges and share of each node.
+
+mod common;
+
+use common::*;
+use std::process::Command;
+
+#[test]
+fn test_ring_view() {
+    let mut cluster = cluster(1, &["--vnodes", "4"]);
+    cluster.start_node(&[]);
+    let ranges = cluster.wait_for_ranges(8);
+    let (tracker, node) = (cluster.tracker, cluster.node);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["-t", &tracker.to_string(), "-n", &node.to_string(), "ring"])
+        .env("RUST_LOG", "warn")
+        .output()
+        .expect("Failed to run the client");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+
+    // Each node owns every other range, half of the ring
+    assert_eq!(lines.len(), 2 * 5, "{}", stdout);
+    assert_eq!(
+        lines[0],
+        format!(
+            "Node {} owns 128 hashes, 50.0% of the ring, in 4 ranges",
+            node
+        )
+    );
+    assert!(lines[5].ends_with(" owns 128 hashes, 50.0% of the ring, in 4 ranges"));
+    for (i, (min, max)) in ranges.iter().enumerate() {
+        let line = 1 + i / 2 + (i % 2) * 5;
+        assert_eq!(lines[line], format!("  ({}, {})", min, max));
+    }
+}
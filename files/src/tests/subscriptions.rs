@@ -0,0 +1,40 @@
+//! Subscribes to an ssn on the only node, lets a second node join and take the
+//! range holding it, and checks that the second node sends the notification.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::net::Ipv4Addr;
+
+#[test]
+fn test_subscription_follows_range() {
+    let mut cluster = cluster(1, &[]);
+    let (node, max_hash) = (cluster.node, cluster.max_hash);
+
+    // An ssn in the upper half of the ring, which the joining node takes
+    let half = (max_hash - 1) / 2;
+    let ssn = ssns(100)
+        .into_iter()
+        .find(|ssn| cluster.hasher.hash(ssn.as_bytes()) > half)
+        .expect("No ssn hashes to the upper half");
+
+    let address = Ipv4Addr::LOCALHOST.into();
+    let port = cluster.client.port();
+    let subscribe = ValSubscribePdu::new(0, 0, Some(ssn.clone()), 60, address, port);
+    cluster.client.send(subscribe.into(), node);
+
+    cluster.start_node(&[]);
+    let ranges = cluster.wait_for_ranges(2);
+    assert_eq!(ranges, [(0, half), (half + 1, max_hash)]);
+
+    let insert = ValInsertPdu::new(ssn.clone(), name(&ssn), "a@b.c".into());
+    match cluster.client.request_from(insert.into(), node) {
+        Some((PDU::ValNotify(p), from)) => {
+            assert_ne!(from, node, "The notification came from the first node");
+            assert!(!p.removed);
+            assert_eq!(p.ssn, ssn);
+        }
+        x => panic!("Expected a notification, got {:?}", x),
+    }
+}
@@ -0,0 +1,31 @@
+//! Sends a node an insert wrapped in a version from a client, and checks that
+//! it is refused with an invalid PDU error and not stored.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::net::Ipv4Addr;
+
+#[test]
+fn test_version_from_client() {
+    let mut cluster = cluster(1, &[]);
+    let (client, node) = (&mut cluster.client, cluster.node);
+
+    let ssn = "198001010000".to_owned();
+    let insert = ValInsertPdu::new(ssn.clone(), name(&ssn), "a@b.c".into());
+    let version = ValVersionPdu::new(u64::MAX / 2, insert.into());
+    match client.request(version.into(), node) {
+        Some(PDU::Error(p)) => {
+            assert_eq!(p.code, ERROR_INVALID_PDU);
+            assert_eq!(p.request_type, VAL_VERSION_ID);
+        }
+        x => panic!("Expected an invalid PDU error, got {:?}", x),
+    }
+
+    let lookup = ValLookupPdu::new(ssn, Ipv4Addr::LOCALHOST.into(), client.port());
+    match client.request(lookup.into(), node) {
+        Some(PDU::ValLookupResponse(p)) => assert_eq!(p.ssn, "000000000000"),
+        x => panic!("Expected an empty lookup response, got {:?}", x),
+    }
+}
@@ -0,0 +1,65 @@
+//! Splits the ring of the first node into virtual ranges, checks that scans
+//! of a node span all of its adjacent ranges, and that a joining node takes
+//! the upper half of every range.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::net::{Ipv4Addr, SocketAddr};
+
+const VNODES: u64 = 4;
+
+/// The node that answers a scan from `start`, sent through `node`.
+fn owner(client: &mut Client, node: SocketAddr, start: u64, max_hash: u64) -> SocketAddr {
+    let scan = ValScanPdu::new(
+        start,
+        max_hash,
+        1,
+        None,
+        Ipv4Addr::LOCALHOST.into(),
+        client.port(),
+    );
+    match client.request_from(scan.into(), node) {
+        Some((PDU::ValScanResponse(_), from)) => from,
+        x => panic!("Expected a scan response from {}, got {:?}", start, x),
+    }
+}
+
+#[test]
+fn test_vnodes() {
+    let mut cluster = cluster(1, &["--vnodes", &VNODES.to_string()]);
+    let (node, max_hash) = (cluster.node, cluster.max_hash);
+    let ring = u128::from(max_hash) + 1;
+    let vnodes: Vec<(u64, u64)> = (0..u128::from(VNODES))
+        .map(|i| {
+            let end = (i + 1) * ring / u128::from(VNODES) - 1;
+            ((i * ring / u128::from(VNODES)) as u64, end as u64)
+        })
+        .collect();
+
+    // Alone, the node owns one run of adjacent ranges, from any of them
+    for &(start, _) in &vnodes {
+        assert_eq!(cluster.range(start), Some((start, max_hash)));
+    }
+
+    cluster.start_node(&[]);
+    let ranges = cluster.wait_for_ranges(2 * vnodes.len());
+    let client = &mut cluster.client;
+    let halves: Vec<(u64, u64)> = vnodes
+        .iter()
+        .flat_map(|&(min, max)| {
+            let mid = (max - min) / 2 + min;
+            vec![(min, mid), (mid + 1, max)]
+        })
+        .collect();
+    assert_eq!(ranges, halves);
+
+    // The nodes take turns around the ring
+    let other = owner(client, node, ranges[1].0, max_hash);
+    assert_ne!(other, node);
+    for (i, &(start, _)) in ranges.iter().enumerate() {
+        let expected = if i % 2 == 0 { node } else { other };
+        assert_eq!(owner(client, node, start, max_hash), expected);
+    }
+}
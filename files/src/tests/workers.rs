@@ -0,0 +1,39 @@
+//! Stores entries in a node that keeps them on storage worker threads, and
+//! checks that every entry can be looked up and that a scan finds them all.
+
+mod common;
+
+use common::*;
+use ou2::pdu::*;
+use std::net::Ipv4Addr;
+
+const ENTRIES: usize = 64;
+
+#[test]
+fn test_workers() {
+    let mut cluster = cluster(1, &["--workers", "4"]);
+    let (client, node, max_hash) = (&mut cluster.client, cluster.node, cluster.max_hash);
+    let ssns = ssns(ENTRIES);
+    client.insert_all(node, &ssns);
+    client.assert_stored(node, &ssns);
+
+    let port = client.port();
+
+    let mut found = Vec::new();
+    let mut cursor = None;
+    loop {
+        let address = Ipv4Addr::LOCALHOST.into();
+        let scan = ValScanPdu::new(0, max_hash, 16, cursor, address, port);
+        let response = match client.request(scan.into(), node) {
+            Some(PDU::ValScanResponse(p)) => p,
+            x => panic!("Expected a scan response, got {:?}", x),
+        };
+        found.extend(response.entries.into_iter().map(|e| e.ssn));
+        cursor = response.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    found.sort();
+    assert_eq!(found, ssns);
+}